@@ -341,9 +341,15 @@ install_module() {
   MODAUTH=`grep_prop author $TMPDIR/module.prop`
   MODPATH=$MODULEROOT/$MODID
 
+  # apd has already extracted the zip into modules_update/<id>
+  local EXTRACTED=false
+  $BOOTMODE && [ "$APD_EXTRACTED" = "1" ] && ! is_legacy_script && EXTRACTED=true
+
   # Create mod paths
-  rm -rf $MODPATH
-  mkdir -p $MODPATH
+  if ! $EXTRACTED; then
+    rm -rf $MODPATH
+    mkdir -p $MODPATH
+  fi
 
   if is_legacy_script; then
     unzip -oj "$ZIPFILE" module.prop install.sh uninstall.sh 'common/*' -d $TMPDIR >&2
@@ -368,11 +374,21 @@ install_module() {
     print_title "$MODNAME" "by $MODAUTH"
     print_title "Powered by APatch"
 
-    unzip -o "$ZIPFILE" customize.sh -d $MODPATH >&2
+    if $EXTRACTED; then
+      rm -rf $MODPATH/META-INF
+      # the module extracts its own files, leave it only customize.sh as usual
+      if grep -q '^SKIPUNZIP=1$' $MODPATH/customize.sh 2>/dev/null; then
+        find $MODPATH -mindepth 1 -maxdepth 1 ! -name customize.sh -exec rm -rf {} +
+      fi
+    else
+      unzip -o "$ZIPFILE" customize.sh -d $MODPATH >&2
+    fi
 
     if ! grep -q '^SKIPUNZIP=1$' $MODPATH/customize.sh 2>/dev/null; then
-      ui_print "- Extracting module files"
-      unzip -o "$ZIPFILE" -x 'META-INF/*' -d $MODPATH >&2
+      if ! $EXTRACTED; then
+        ui_print "- Extracting module files"
+        unzip -o "$ZIPFILE" -x 'META-INF/*' -d $MODPATH >&2
+      fi
 
       # Default permissions
       set_perm_recursive $MODPATH 0 0 0755 0644
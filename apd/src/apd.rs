@@ -3,7 +3,7 @@ use std::os::unix::process::CommandExt;
 use std::{
     env,
     ffi::{CStr, CString},
-    path::PathBuf,
+    path::Path,
     process::Command,
 };
 
@@ -253,11 +253,12 @@ pub fn root_shell() -> Result<()> {
 
     // add /data/adb/ap/bin to PATH
     #[cfg(any(target_os = "linux", target_os = "android"))]
-    add_path_to_env(defs::BINARY_DIR)?;
+    add_path_to_env(&defs::binary_dir())?;
 
     // when AP_RC_PATH exists and ENV is not set, set ENV to AP_RC_PATH
-    if PathBuf::from(defs::AP_RC_PATH).exists() && env::var("ENV").is_err() {
-        command = command.env("ENV", defs::AP_RC_PATH);
+    let ap_rc_path = defs::resolve(defs::AP_RC_PATH);
+    if ap_rc_path.exists() && env::var("ENV").is_err() {
+        command = command.env("ENV", ap_rc_path);
     }
     #[cfg(target_os = "android")]
     if !matches.opt_present("no-pty")
@@ -265,6 +266,7 @@ pub fn root_shell() -> Result<()> {
     {
         log::error!("failed to prepare pty: {:?}", e);
     }
+    let global_namespace_file = defs::resolve(defs::GLOBAL_NAMESPACE_FILE);
     // escape from the current cgroup and become session leader
     // WARNING!!! This cause some root shell hang forever!
     // command = command.process_group(0);
@@ -276,7 +278,7 @@ pub fn root_shell() -> Result<()> {
             // switch to global mount namespace
             #[cfg(any(target_os = "linux", target_os = "android"))]
             let global_namespace_enable =
-                std::fs::read_to_string(defs::GLOBAL_NAMESPACE_FILE).unwrap_or("0".to_string());
+                std::fs::read_to_string(&global_namespace_file).unwrap_or("0".to_string());
             if global_namespace_enable.trim() == "1" || mount_master {
                 let _ = utils::switch_mnt_ns(1);
             }
@@ -291,11 +293,10 @@ pub fn root_shell() -> Result<()> {
     Err(command.exec().into())
 }
 
-fn add_path_to_env(path: &str) -> Result<()> {
+fn add_path_to_env(path: &Path) -> Result<()> {
     let mut paths =
         env::var_os("PATH").map_or(Vec::new(), |val| env::split_paths(&val).collect::<Vec<_>>());
-    let new_path = PathBuf::from(path.trim_end_matches('/'));
-    paths.push(new_path);
+    paths.push(path.components().collect());
     let new_path_env = env::join_paths(paths)?;
     unsafe { env::set_var("PATH", new_path_env) };
     Ok(())
@@ -1,21 +1,30 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 use const_format::concatcp;
 
-use crate::{defs::BINARY_DIR, utils};
+use crate::{
+    defs::{self, BINARY_DIR},
+    utils,
+};
 
 pub const RESETPROP_PATH: &str = concatcp!(BINARY_DIR, "resetprop");
 pub const BUSYBOX_PATH: &str = concatcp!(BINARY_DIR, "busybox");
 pub const MAGISKPOLICY_PATH: &str = concatcp!(BINARY_DIR, "magiskpolicy");
 
+pub fn busybox_path() -> PathBuf {
+    defs::resolve(BUSYBOX_PATH)
+}
+
 pub fn ensure_binaries() -> Result<()> {
-    utils::ensure_binary(BUSYBOX_PATH)?;
-    let resetprop_link = RESETPROP_PATH;
-    let _ = std::fs::remove_file(resetprop_link);
-    std::os::unix::fs::symlink("/data/adb/apd", resetprop_link)?;
+    utils::ensure_binary(busybox_path())?;
+    let resetprop_link = defs::resolve(RESETPROP_PATH);
+    let _ = std::fs::remove_file(&resetprop_link);
+    std::os::unix::fs::symlink(defs::daemon_path(), resetprop_link)?;
 
-    let magiskpolicy_link = MAGISKPOLICY_PATH;
-    let _ = std::fs::remove_file(magiskpolicy_link);
-    std::os::unix::fs::symlink("/data/adb/apd", magiskpolicy_link)?;
+    let magiskpolicy_link = defs::resolve(MAGISKPOLICY_PATH);
+    let _ = std::fs::remove_file(&magiskpolicy_link);
+    std::os::unix::fs::symlink(defs::daemon_path(), magiskpolicy_link)?;
 
     Ok(())
 }
@@ -0,0 +1,446 @@
+//! Full backup and restore of the APatch state
+//!
+//! `apd backup create <file>` writes a zip with a `manifest.json`, every
+//! installed module under `modules/<id>/` (flag files included), their
+//! persistent configs under `module_configs/<id>/persist.config` and the root
+//! state in `package_config` and `su_path`.
+//!
+//! `apd backup restore <file>` validates the manifest and every entry, then
+//! stages the modules in modules_update so the next boot applies them like any
+//! other update. Configs, root grants and the metamodule symlink are restored
+//! right away. The archive carries no signatures, so restored modules count as
+//! unsigned under the signature policy.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use jwalk::{Parallelism::Serial, WalkDir};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    defs, extract, integrity, metamodule,
+    module::{mark_module_state, mark_update, read_module_prop, validate_module_id},
+    module_config::{self, ConfigType},
+    restorecon, signature,
+    utils::ensure_dir_exists,
+};
+
+/// Bumped whenever the archive layout changes incompatibly
+const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const MODULES_PREFIX: &str = "modules";
+const CONFIGS_PREFIX: &str = "module_configs";
+/// Where a restore is extracted before it is moved into place
+const STAGING_DIR: &str = ".backup_restore";
+
+/// Root state files kept next to the modules, with their on-device paths
+const STATE_FILES: &[(&str, &str)] = &[
+    ("package_config", defs::PACKAGE_CONFIG_PATH),
+    ("su_path", defs::SU_PATH_FILE),
+];
+
+/// Per-boot bookkeeping that must not travel to another device
+const SKIPPED_FLAGS: &[&str] = &[defs::UPDATE_FILE_NAME, defs::TIMEOUT_COUNT_FILE_NAME];
+/// Trust records only a verification on this device may write
+const TRUST_FILES: &[&str] = &[defs::SIGNER_FILE_NAME, defs::INTEGRITY_FILE_NAME];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    format: u32,
+    apd_version: String,
+    /// Unix time in seconds
+    created_at: u64,
+    modules: Vec<ModuleEntry>,
+    /// Id of the module the metamodule symlink points to
+    metamodule: Option<String>,
+    /// Root state files included, see [`STATE_FILES`]
+    files: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ModuleEntry {
+    id: String,
+    version: String,
+    enabled: bool,
+    removed: bool,
+    config: bool,
+}
+
+fn file_options(mode: u32) -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(mode & 0o777)
+}
+
+/// Add `dir` to the archive under `prefix`, keeping symlinks and permissions
+fn add_dir(writer: &mut ZipWriter<fs::File>, dir: &Path, prefix: &str) -> Result<()> {
+    for entry in WalkDir::new(dir)
+        .parallelism(Serial)
+        .skip_hidden(false)
+        .sort(true)
+    {
+        let path = entry?.path();
+        let relative = path.strip_prefix(dir)?;
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        if relative
+            .to_str()
+            .is_some_and(|name| SKIPPED_FLAGS.contains(&name) || TRUST_FILES.contains(&name))
+        {
+            continue;
+        }
+        let Some(relative) = relative.to_str() else {
+            warn!("skipping non UTF-8 path {}", path.display());
+            continue;
+        };
+        let name = format!("{prefix}/{relative}");
+
+        let metadata = fs::symlink_metadata(&path)?;
+        let options = file_options(metadata.permissions().mode());
+        if metadata.is_symlink() {
+            let target = fs::read_link(&path)?;
+            writer.add_symlink(name, target.to_string_lossy(), options)?;
+        } else if metadata.is_dir() {
+            writer.add_directory(name, options)?;
+        } else if metadata.is_file() {
+            writer.start_file(name, options)?;
+            io::copy(&mut fs::File::open(&path)?, writer)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn add_file(writer: &mut ZipWriter<fs::File>, path: &Path, name: &str) -> Result<()> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    writer.start_file(name, file_options(mode))?;
+    io::copy(&mut fs::File::open(path)?, writer)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(())
+}
+
+/// `apd backup create <file>`
+pub fn create(file: &Path) -> Result<()> {
+    let out =
+        fs::File::create(file).with_context(|| format!("Failed to create {}", file.display()))?;
+    let mut writer = ZipWriter::new(out);
+    let mut manifest = Manifest {
+        format: FORMAT_VERSION,
+        apd_version: defs::VERSION_CODE.trim().to_string(),
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        modules: Vec::new(),
+        metamodule: metamodule::get_metamodule_id(),
+        files: Vec::new(),
+    };
+
+    let mut modules: Vec<PathBuf> = fs::read_dir(defs::module_dir())
+        .map(|dir| dir.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    modules.retain(|path| path.is_dir());
+    modules.sort();
+    for module in modules {
+        let Some(id) = module.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if let Err(e) = validate_module_id(id) {
+            warn!("skipping {}: {e}", module.display());
+            continue;
+        }
+        add_dir(&mut writer, &module, &format!("{MODULES_PREFIX}/{id}"))
+            .with_context(|| format!("Failed to back up {id}"))?;
+
+        let config = module_config::get_config_path(id, ConfigType::Persist);
+        let has_config = config.exists();
+        if has_config {
+            add_file(
+                &mut writer,
+                &config,
+                &format!("{CONFIGS_PREFIX}/{id}/{}", defs::PERSIST_CONFIG_NAME),
+            )?;
+        }
+
+        let props = read_module_prop(&module).unwrap_or_default();
+        manifest.modules.push(ModuleEntry {
+            id: id.to_string(),
+            version: props.get("version").cloned().unwrap_or_default(),
+            enabled: !module.join(defs::DISABLE_FILE_NAME).exists(),
+            removed: module.join(defs::REMOVE_FILE_NAME).exists(),
+            config: has_config,
+        });
+    }
+
+    for (name, path) in STATE_FILES {
+        let path = defs::resolve(path);
+        if path.exists() {
+            add_file(&mut writer, &path, name)?;
+            manifest.files.push(name.to_string());
+        }
+    }
+
+    writer.start_file(MANIFEST, file_options(0o600))?;
+    serde_json::to_writer_pretty(&mut writer, &manifest)?;
+    writer.finish()?;
+
+    println!(
+        "- Backed up {} modules to {}",
+        manifest.modules.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+fn read_manifest(archive: &mut zip::ZipArchive<fs::File>) -> Result<Manifest> {
+    let entry = archive
+        .by_name(MANIFEST)
+        .with_context(|| format!("not a backup: {MANIFEST} is missing"))?;
+    let manifest: Manifest =
+        serde_json::from_reader(entry).with_context(|| format!("invalid {MANIFEST}"))?;
+
+    ensure!(
+        manifest.format == FORMAT_VERSION,
+        "unsupported backup format {}, this apd reads format {FORMAT_VERSION}",
+        manifest.format
+    );
+    let mut ids = HashSet::new();
+    for module in &manifest.modules {
+        validate_module_id(&module.id)?;
+        ensure!(ids.insert(&module.id), "{} is listed twice", module.id);
+    }
+    if let Some(meta) = &manifest.metamodule {
+        ensure!(
+            ids.contains(meta),
+            "metamodule {meta} is not part of the backup"
+        );
+    }
+    for file in &manifest.files {
+        ensure!(
+            STATE_FILES.iter().any(|(name, _)| name == file),
+            "unknown state file {file}"
+        );
+    }
+    Ok(manifest)
+}
+
+/// Every entry must belong to something the manifest lists
+fn check_entries(archive: &zip::ZipArchive<fs::File>, manifest: &Manifest) -> Result<()> {
+    let ids: HashSet<&str> = manifest.modules.iter().map(|m| m.id.as_str()).collect();
+    let config_name = defs::PERSIST_CONFIG_NAME;
+    for name in archive.file_names() {
+        let allowed = match name.split_once('/') {
+            None => name == MANIFEST || manifest.files.iter().any(|file| file == name),
+            Some((MODULES_PREFIX, rest)) => {
+                let id = rest.split('/').next().unwrap_or_default();
+                ids.contains(id)
+            }
+            Some((CONFIGS_PREFIX, rest)) => match rest.split_once('/') {
+                Some((id, file)) => ids.contains(id) && (file.is_empty() || file == config_name),
+                None => false,
+            },
+            Some(_) => false,
+        };
+        ensure!(allowed, "unexpected entry in backup: {name}");
+    }
+    Ok(())
+}
+
+/// `apd backup restore <file>`
+pub fn restore(file: &Path) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(
+        fs::File::open(file).with_context(|| format!("Failed to open {}", file.display()))?,
+    )?;
+    let manifest = read_manifest(&mut archive)?;
+    check_entries(&archive, &manifest)?;
+
+    for module in &manifest.modules {
+        ensure!(
+            !defs::module_update_dir().join(&module.id).exists(),
+            "{} already has a pending update, reboot first",
+            module.id
+        );
+    }
+    if let Some(current) = metamodule::get_metamodule_id()
+        && manifest.metamodule.as_ref() != Some(&current)
+    {
+        bail!("metamodule {current} is installed, uninstall it and reboot before restoring");
+    }
+    for module in &manifest.modules {
+        signature::check_restore(&module.id)
+            .with_context(|| format!("Refusing to restore {}", module.id))?;
+    }
+
+    let staging = defs::working_dir().join(STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let options = extract::Options {
+        symlinks: extract::SymlinkPolicy::Contained,
+        limits: extract::Limits {
+            // a whole module collection, not a single module
+            max_total_size: 8 << 30,
+            ..extract::Limits::default()
+        },
+    };
+    let result = extract::extract(&mut archive, &staging, &options)
+        .and_then(|()| apply(&staging, &manifest));
+    let _ = fs::remove_dir_all(&staging);
+    result?;
+
+    mark_update()?;
+    println!(
+        "- Restored {} modules from {}, reboot to apply",
+        manifest.modules.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+fn apply(staging: &Path, manifest: &Manifest) -> Result<()> {
+    let modules_dir = defs::module_dir();
+    let update_dir = defs::module_update_dir();
+    ensure_dir_exists(&modules_dir)?;
+    ensure_dir_exists(&update_dir)?;
+
+    for module in &manifest.modules {
+        let id = &module.id;
+        let staged = staging.join(MODULES_PREFIX).join(id);
+        ensure!(
+            staged.is_dir(),
+            "{id} is listed but missing from the backup"
+        );
+        // never trust what the archive claims about itself
+        for name in TRUST_FILES {
+            let path = staged.join(name);
+            if fs::symlink_metadata(&path).is_ok() {
+                fs::remove_file(&path)?;
+            }
+        }
+        let target = update_dir.join(id);
+        fs::rename(&staged, &target).with_context(|| format!("Failed to stage {id}"))?;
+        let system = target.join("system");
+        if system.exists() {
+            restorecon::restore_syscon(&system)?;
+        }
+
+        // like an install: the placeholder in modules carries the flags, and
+        // the update swaps in the staged copy on the next boot
+        let module_dir = modules_dir.join(id);
+        if !module_dir.exists() {
+            fs::create_dir(&module_dir)?;
+            fs::set_permissions(&module_dir, fs::Permissions::from_mode(0o700))?;
+        }
+        // restore() ran the signature check, which has no signer to report
+        for dir in [&target, &module_dir] {
+            signature::record_signer(dir, None)?;
+        }
+        // owners and labels differ on the new device
+        integrity::record(&target)?;
+        mark_module_state(id, defs::UPDATE_FILE_NAME, true)?;
+        mark_module_state(id, defs::DISABLE_FILE_NAME, !module.enabled)?;
+        mark_module_state(id, defs::REMOVE_FILE_NAME, module.removed)?;
+
+        let config_path = module_config::get_config_path(id, ConfigType::Persist);
+        if module.config {
+            let config = staging
+                .join(CONFIGS_PREFIX)
+                .join(id)
+                .join(defs::PERSIST_CONFIG_NAME);
+            if let Some(parent) = config_path.parent() {
+                ensure_dir_exists(parent)?;
+            }
+            fs::copy(&config, &config_path)
+                .with_context(|| format!("Failed to restore config of {id}"))?;
+        } else {
+            let _ = fs::remove_file(&config_path);
+        }
+        info!("{id} restored from backup");
+    }
+
+    for name in &manifest.files {
+        let Some((_, path)) = STATE_FILES.iter().find(|(file, _)| file == name) else {
+            continue;
+        };
+        let path = defs::resolve(path);
+        fs::copy(staging.join(name), &path)
+            .with_context(|| format!("Failed to restore {}", path.display()))?;
+    }
+
+    if let Some(meta) = &manifest.metamodule {
+        metamodule::ensure_symlink(modules_dir.join(meta))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::{FakePlatform, with_fake};
+
+    /// A backup of `mod_a` whose archive claims a signer
+    fn forged_backup() -> PathBuf {
+        let file = defs::working_dir().join("backup.zip");
+        let mut writer = ZipWriter::new(fs::File::create(&file).unwrap());
+        for (name, content) in [
+            ("modules/mod_a/module.prop", "id=mod_a\n"),
+            ("modules/mod_a/.signer", "trusted"),
+        ] {
+            writer.start_file(name, file_options(0o644)).unwrap();
+            io::Write::write_all(&mut writer, content.as_bytes()).unwrap();
+        }
+        let manifest = Manifest {
+            format: FORMAT_VERSION,
+            apd_version: String::new(),
+            created_at: 0,
+            modules: vec![ModuleEntry {
+                id: "mod_a".to_string(),
+                version: String::new(),
+                enabled: true,
+                removed: false,
+                config: false,
+            }],
+            metamodule: None,
+            files: Vec::new(),
+        };
+        writer.start_file(MANIFEST, file_options(0o600)).unwrap();
+        serde_json::to_writer(&mut writer, &manifest).unwrap();
+        writer.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn restore_drops_the_archived_signer() {
+        with_fake(FakePlatform::default(), |_| {
+            let file = forged_backup();
+            restore(&file).unwrap();
+
+            let target = defs::module_update_dir().join("mod_a");
+            assert!(target.join("module.prop").exists());
+            assert_eq!(signature::read_signer(&target), None);
+            assert!(target.join(defs::INTEGRITY_FILE_NAME).exists());
+        });
+    }
+
+    #[test]
+    fn restore_is_refused_under_enforce() {
+        with_fake(FakePlatform::default(), |_| {
+            let file = forged_backup();
+            fs::write(defs::resolve(defs::SIGNATURE_POLICY_FILE), "enforce").unwrap();
+            let err = restore(&file).unwrap_err();
+            assert!(format!("{err:#}").contains("Signature check failed"));
+            assert!(!defs::module_update_dir().join("mod_a").exists());
+            assert!(!defs::module_dir().join("mod_a").exists());
+        });
+    }
+}
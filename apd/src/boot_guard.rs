@@ -0,0 +1,128 @@
+//! Bootloop protection
+//!
+//! Every boot bumps an attempt counter in post-fs-data and records how far it
+//! got; reaching boot-completed resets it. A soft reboot re-runs post-fs-data
+//! but never reaches boot-completed, so it is not counted. Modules installed or updated by
+//! `handle_updated_modules` are remembered until a boot completes. When
+//! `MAX_FAILED_BOOTS` boots in a row never complete, only those modules get
+//! disabled, instead of turning everything off like safe mode does.
+
+use std::{
+    fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{defs, module, utils::lock_file};
+
+/// Consecutive boots without boot-completed before suspect modules are disabled
+const MAX_FAILED_BOOTS: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rescue {
+    /// Unix time in seconds
+    pub at: u64,
+    pub failed_boots: u32,
+    /// Last stage the failed boots reached
+    pub last_stage: String,
+    pub disabled: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BootState {
+    /// Boots started since the last one that completed
+    attempts: u32,
+    /// Furthest stage of the current boot
+    stage: String,
+    /// Modules added or updated since the last good boot
+    pending: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    last_rescue: Option<Rescue>,
+}
+
+fn load_state() -> BootState {
+    fs::read(defs::resolve(defs::BOOT_STATE_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_state(state: &BootState) -> Result<()> {
+    let path = defs::resolve(defs::BOOT_STATE_FILE);
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, serde_json::to_vec_pretty(state)?)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    fs::rename(&temp_path, &path)
+        .with_context(|| format!("Failed to rename {}", temp_path.display()))?;
+    Ok(())
+}
+
+/// Load, change and save the state under a lock, boot stages and module
+/// installs run in separate processes
+fn update_state(f: impl FnOnce(&mut BootState)) -> Result<()> {
+    let _lock = lock_file(defs::resolve(defs::BOOT_STATE_FILE))?;
+    let mut state = load_state();
+    f(&mut state);
+    save_state(&state)
+}
+
+/// Start a boot attempt. Called early in post-fs-data, before updated modules
+/// are moved in, so a rescue only ever touches modules from earlier boots.
+pub fn begin_boot() -> Result<()> {
+    update_state(|state| {
+        if state.attempts >= MAX_FAILED_BOOTS {
+            let rescue = Rescue {
+                at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                failed_boots: state.attempts,
+                last_stage: state.stage.clone(),
+                disabled: std::mem::take(&mut state.pending),
+            };
+            warn!(
+                "{} boots in a row did not complete (last stage: {}), disabling modules changed since the last good boot: {:?}",
+                rescue.failed_boots, rescue.last_stage, rescue.disabled
+            );
+            for id in &rescue.disabled {
+                if let Err(e) = module::disable_module(id, false) {
+                    warn!("Failed to disable module {id}: {e}");
+                }
+            }
+            state.attempts = 0;
+            state.last_rescue = Some(rescue);
+        }
+
+        state.attempts += 1;
+        state.stage = "post-fs-data".to_string();
+        info!("boot attempt {}", state.attempts);
+    })
+}
+
+/// Remember modules that were just added or updated
+pub fn track_updated_modules(ids: &[String]) -> Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    update_state(|state| {
+        for id in ids {
+            if !state.pending.contains(id) {
+                state.pending.push(id.clone());
+            }
+        }
+    })
+}
+
+/// Record how far the current boot got
+pub fn mark_stage(stage: &str) -> Result<()> {
+    update_state(|state| {
+        state.stage = stage.to_string();
+        if stage == "boot-completed" {
+            state.attempts = 0;
+            state.pending.clear();
+        }
+    })
+}
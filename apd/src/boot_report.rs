@@ -0,0 +1,396 @@
+//! Structured boot report
+//!
+//! Every boot stage (post-fs-data, post-mount, service, boot-completed) records
+//! the steps it ran, with timing, exit status and error chain, into
+//! `APATCH_LOG_FOLDER/boot-<n>.json`. Stages run in separate apd processes, so
+//! each one appends itself to the report of the current boot, identified by the
+//! kernel boot id. A stage is written when it starts and again as every step
+//! starts and ends, so a boot that hangs or dies mid-stage still shows where.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs,
+    utils::{ensure_dir_exists, lock_file},
+};
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const REPORT_PREFIX: &str = "boot-";
+const REPORT_SUFFIX: &str = ".json";
+/// Number of boot reports kept in the log folder
+const MAX_BOOT_REPORTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    /// Started and not finished: still running, or the boot never got past it
+    Running,
+    /// Finished successfully
+    #[default]
+    Ok,
+    /// Returned an error or exited non-zero
+    Failed,
+    /// Started in the background, no exit status to wait for
+    Spawned,
+    /// Killed after running past its timeout
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepReport {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    /// Unix time in milliseconds
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub status: StepStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Error chain, outermost context first
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub error: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageReport {
+    pub stage: String,
+    /// Unix time in milliseconds
+    pub started_at: u64,
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub status: StepStatus,
+    pub steps: Vec<StepReport>,
+    /// Error that aborted the stage, if any
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub error: Vec<String>,
+    #[serde(skip)]
+    start: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootReport {
+    pub boot: u64,
+    pub boot_id: String,
+    pub apd_version: String,
+    pub stages: Vec<StageReport>,
+}
+
+/// What a finished step hands back to the report
+pub enum Outcome {
+    Done,
+    Exited(ExitStatus),
+    Spawned,
+    TimedOut(Duration),
+}
+
+impl From<()> for Outcome {
+    fn from((): ()) -> Self {
+        Self::Done
+    }
+}
+
+impl From<Option<ExitStatus>> for Outcome {
+    fn from(status: Option<ExitStatus>) -> Self {
+        status.map_or(Self::Spawned, Self::Exited)
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn error_chain(e: &anyhow::Error) -> Vec<String> {
+    e.chain().map(ToString::to_string).collect()
+}
+
+impl StepReport {
+    /// A step that has just started
+    pub fn started(name: &str, module: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            module: module.map(ToString::to_string),
+            started_at: unix_millis(),
+            duration_ms: 0,
+            status: StepStatus::Running,
+            exit_code: None,
+            error: Vec::new(),
+        }
+    }
+
+    /// Run `f` and describe how it went, without attaching it to a stage
+    pub fn run<T: Into<Outcome>>(
+        name: &str,
+        module: Option<&str>,
+        f: impl FnOnce() -> Result<T>,
+    ) -> (Self, Result<()>) {
+        let started_at = unix_millis();
+        let start = Instant::now();
+        let result = f();
+        let duration_ms = start.elapsed().as_millis() as u64;
+
+        let (status, exit_code, error, result) = match result {
+            Ok(outcome) => match outcome.into() {
+                Outcome::Done => (StepStatus::Ok, None, Vec::new(), Ok(())),
+                Outcome::Spawned => (StepStatus::Spawned, None, Vec::new(), Ok(())),
+                Outcome::TimedOut(timeout) => (
+                    StepStatus::TimedOut,
+                    None,
+                    vec![format!(
+                        "timed out after {}s, process group killed",
+                        timeout.as_secs()
+                    )],
+                    Ok(()),
+                ),
+                Outcome::Exited(status) if status.success() => {
+                    (StepStatus::Ok, status.code(), Vec::new(), Ok(()))
+                }
+                Outcome::Exited(status) => (
+                    StepStatus::Failed,
+                    status.code(),
+                    vec![format!("exited with {status}")],
+                    Ok(()),
+                ),
+            },
+            Err(e) => (StepStatus::Failed, None, error_chain(&e), Err(e)),
+        };
+
+        let step = Self {
+            name: name.to_string(),
+            module: module.map(ToString::to_string),
+            started_at,
+            duration_ms,
+            status,
+            exit_code,
+            error,
+        };
+        (step, result)
+    }
+}
+
+impl StageReport {
+    /// Start a stage, it shows up in the boot report right away
+    pub fn new(stage: &str) -> Self {
+        let report = Self {
+            stage: stage.to_string(),
+            started_at: unix_millis(),
+            duration_ms: 0,
+            status: StepStatus::Running,
+            steps: Vec::new(),
+            error: Vec::new(),
+            start: Some(Instant::now()),
+        };
+        save_stage(&report);
+        report
+    }
+
+    /// Run one step of the stage and record how it went.
+    /// The result is passed through so callers keep their own error handling.
+    pub fn step<T: Into<Outcome>>(
+        &mut self,
+        name: &str,
+        module: Option<&str>,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<()> {
+        let index = self.start_step(name, module);
+        let (step, result) = StepReport::run(name, module, f);
+        self.end_step(index, step);
+        result
+    }
+
+    /// Record a step as running, e.g. one handed to a worker thread.
+    /// Returns the index to pass to [`StageReport::end_step`].
+    pub fn start_step(&mut self, name: &str, module: Option<&str>) -> usize {
+        self.steps.push(StepReport::started(name, module));
+        save_stage(self);
+        self.steps.len() - 1
+    }
+
+    /// Replace the running step at `index` with how it went
+    pub fn end_step(&mut self, index: usize, step: StepReport) {
+        self.steps[index] = step;
+        save_stage(self);
+    }
+
+    /// Close the stage, recording the error that aborted it (if any)
+    pub fn finish<T>(&mut self, result: &Result<T>) {
+        if let Some(start) = self.start.take() {
+            self.duration_ms = start.elapsed().as_millis() as u64;
+        }
+        self.status = match result {
+            Ok(_) => StepStatus::Ok,
+            Err(e) => {
+                self.error = error_chain(e);
+                StepStatus::Failed
+            }
+        };
+        save_stage(self);
+    }
+}
+
+pub fn read_boot_id() -> String {
+    fs::read_to_string(BOOT_ID_PATH)
+        .map(|id| id.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn report_path(dir: &Path, boot: u64) -> PathBuf {
+    dir.join(format!("{REPORT_PREFIX}{boot}{REPORT_SUFFIX}"))
+}
+
+/// Boot numbers of all reports in the log folder, oldest first
+fn list_reports(dir: &Path) -> Vec<u64> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut boots: Vec<u64> = entries
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(REPORT_PREFIX)?
+                .strip_suffix(REPORT_SUFFIX)?
+                .parse()
+                .ok()
+        })
+        .collect();
+    boots.sort_unstable();
+    boots
+}
+
+fn load_report(path: &Path) -> Result<BootReport> {
+    let content = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_slice(&content).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+fn write_report(path: &Path, report: &BootReport) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, serde_json::to_vec_pretty(report)?)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to rename {}", temp_path.display()))?;
+    Ok(())
+}
+
+fn store_stage(stage: &StageReport) -> Result<()> {
+    let dir = defs::log_dir();
+    ensure_dir_exists(&dir)?;
+    // stages of one boot can finish at the same time
+    let _lock = lock_file(dir.join("boot-report"))?;
+
+    let boot_id = read_boot_id();
+    let boots = list_reports(&dir);
+
+    // Reuse the latest report if it belongs to this boot, otherwise start a new one
+    let current = boots.last().and_then(|&boot| {
+        load_report(&report_path(&dir, boot))
+            .ok()
+            .filter(|report| !boot_id.is_empty() && report.boot_id == boot_id)
+    });
+    let mut report = current.unwrap_or_else(|| BootReport {
+        boot: boots.last().map_or(1, |boot| boot + 1),
+        boot_id,
+        apd_version: defs::VERSION_NAME.trim().to_string(),
+        stages: Vec::new(),
+    });
+
+    // Replace what an earlier save of this stage wrote
+    report
+        .stages
+        .retain(|s| s.stage != stage.stage || s.started_at != stage.started_at);
+    report.stages.push(stage.clone());
+    report.stages.sort_by_key(|stage| stage.started_at);
+    write_report(&report_path(&dir, report.boot), &report)?;
+    info!("boot report updated: boot-{}", report.boot);
+
+    // Drop the oldest reports
+    let mut boots = list_reports(&dir);
+    while boots.len() > MAX_BOOT_REPORTS {
+        let boot = boots.remove(0);
+        let _ = fs::remove_file(report_path(&dir, boot));
+    }
+    Ok(())
+}
+
+/// Store a stage as it is now into the current boot's report.
+/// Best effort: the report must never break boot.
+fn save_stage(stage: &StageReport) {
+    if let Err(e) = store_stage(stage) {
+        warn!("Failed to save boot report: {e:#}");
+    }
+}
+
+/// `apd boot-report [--last N]`: print the latest reports, oldest first
+pub fn print_reports(last: usize) -> Result<()> {
+    let dir = defs::log_dir();
+    let boots = list_reports(&dir);
+    let mut reports = Vec::new();
+    for boot in boots.iter().skip(boots.len().saturating_sub(last)) {
+        match load_report(&report_path(&dir, *boot)) {
+            Ok(report) => reports.push(report),
+            Err(e) => warn!("{e:#}"),
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&reports)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::{FakePlatform, with_fake};
+
+    fn stored_stage() -> StageReport {
+        let dir = defs::log_dir();
+        let boot = *list_reports(&dir).last().unwrap();
+        let mut report = load_report(&report_path(&dir, boot)).unwrap();
+        assert_eq!(report.stages.len(), 1);
+        report.stages.remove(0)
+    }
+
+    #[test]
+    fn steps_are_on_disk_while_they_run() {
+        with_fake(FakePlatform::default(), |_| {
+            let mut report = StageReport::new("post-fs-data");
+            assert_eq!(stored_stage().status, StepStatus::Running);
+
+            report
+                .step("first", None, || {
+                    let stage = stored_stage();
+                    assert_eq!(stage.steps.len(), 1);
+                    assert_eq!(stage.steps[0].status, StepStatus::Running);
+                    Ok(())
+                })
+                .unwrap();
+            assert!(
+                report
+                    .step("second", Some("mod_a"), || -> Result<()> {
+                        anyhow::bail!("broken")
+                    })
+                    .is_err()
+            );
+
+            let stage = stored_stage();
+            assert_eq!(stage.status, StepStatus::Running);
+            let statuses: Vec<_> = stage.steps.iter().map(|step| step.status).collect();
+            assert_eq!(statuses, [StepStatus::Ok, StepStatus::Failed]);
+            assert_eq!(stage.steps[1].error, ["broken"]);
+
+            report.finish(&Ok(()));
+            assert_eq!(stored_stage().status, StepStatus::Ok);
+        });
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{defs, event, insmod, late_load, lua, magica, module, module_config, supercall, utils};
+use crate::{
+    backup, boot_report, config_batch, config_events, defs, event, insmod, inspect, integrity,
+    late_load, lua, magica, module, module_config, module_log, module_update, platform, restart,
+    snapshot, supercall, supervisor, trash,
+};
 #[cfg(target_os = "android")]
 use android_logger::Config;
 use anyhow::{Context, Result};
@@ -18,6 +22,13 @@ struct Args {
         help = "Super key for authentication root"
     )]
     superkey: Option<String>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        global = true,
+        help = "Filesystem root to resolve all apd paths against (default: $APD_ROOT or /)"
+    )]
+    root: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -42,6 +53,22 @@ enum Commands {
     /// Start uid listener for synchronizing root list
     UidListener,
 
+    /// Back up or restore modules, their configs and root grants
+    Backup {
+        #[command(subcommand)]
+        command: BackupCmd,
+    },
+
+    /// Run the supervisor of module services (started at the service stage)
+    Supervisor,
+
+    /// Show structured boot reports as JSON
+    BootReport {
+        /// number of most recent boots to show
+        #[arg(long, default_value_t = 1)]
+        last: usize,
+    },
+
     /// Load a kernel module (.ko) without version check (jailbreak mode)
     Insmod {
         /// kernel module path
@@ -85,6 +112,21 @@ enum Commands {
     Sepolicy(crate::sepolicy::Args),
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum BackupCmd {
+    /// Write all modules, module configs and root grants to <FILE>
+    Create {
+        /// backup file path
+        file: PathBuf,
+    },
+
+    /// Restore a backup, modules are applied on next boot
+    Restore {
+        /// backup file path
+        file: PathBuf,
+    },
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum Module {
     /// Install module <ZIP>
@@ -93,10 +135,59 @@ enum Module {
         zip: String,
     },
 
+    /// Show what installing module <ZIP> would do, without running it
+    Inspect {
+        /// module zip file path
+        zip: String,
+        /// print the result as json
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Re-run service.sh, the Lua service hook and system.prop of module <id>
+    Restart {
+        /// module id
+        id: String,
+    },
+
+    /// Show the state of supervised module services
+    Services {
+        /// print the result as json
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Show the stage script and Lua hook output of module <id>
+    Logs {
+        /// module id
+        id: String,
+        /// only this stage, e.g. post-fs-data or service
+        #[arg(long)]
+        stage: Option<String>,
+    },
+
+    /// Check installed files against the manifest recorded at install time
+    Verify {
+        /// module id, all modules if omitted
+        id: Option<String>,
+        /// print the result as json
+        #[arg(long)]
+        json: bool,
+    },
+
     /// Uninstall module <id>
     Uninstall {
         /// module id
         id: String,
+        /// uninstall even if other active modules require it
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Roll module <id> back to the version before its last update, on next boot
+    Rollback {
+        /// module id
+        id: String,
     },
 
     /// UudoUninstall module <id>
@@ -109,12 +200,18 @@ enum Module {
     Enable {
         /// module id
         id: String,
+        /// run the on_enable hooks now instead of waiting for a reboot
+        #[arg(long)]
+        now: bool,
     },
 
     /// disable module <id>
     Disable {
         // module id
         id: String,
+        /// run the on_disable hooks now instead of waiting for a reboot
+        #[arg(long)]
+        now: bool,
     },
 
     /// run action for module <id>
@@ -132,6 +229,25 @@ enum Module {
     /// list all modules
     List,
 
+    /// check modules for updates via their updateJson
+    CheckUpdates {
+        /// print the result as json
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// download and install the latest version of module <id>
+    Update {
+        /// module id
+        id: String,
+    },
+
+    /// manage uninstalled modules kept in the trash
+    Trash {
+        #[command(subcommand)]
+        command: TrashCmd,
+    },
+
     /// manage module configuration
     Config {
         /// target internal module name (resolved as internal.<name>)
@@ -142,6 +258,24 @@ enum Module {
     },
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum TrashCmd {
+    /// List uninstalled modules in the trash
+    List,
+
+    /// Restore the most recently uninstalled copy of module <id>
+    Restore {
+        /// module id
+        id: String,
+    },
+
+    /// Permanently delete trashed modules
+    Purge {
+        /// only purge this module id
+        id: Option<String>,
+    },
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum ModuleConfigCmd {
     /// Get a config value
@@ -159,6 +293,9 @@ enum ModuleConfigCmd {
         /// read value from stdin (default if value not provided)
         #[arg(long)]
         stdin: bool,
+        /// store the value as binary data
+        #[arg(long)]
+        blob: bool,
         /// use temporary config (cleared on reboot)
         #[arg(short, long)]
         temp: bool,
@@ -182,6 +319,22 @@ enum ModuleConfigCmd {
         #[arg(short, long)]
         temp: bool,
     },
+
+    /// Set and delete several entries at once from a JSON object or key=value lines on stdin
+    Apply {
+        /// apply to temporary config
+        #[arg(short, long)]
+        temp: bool,
+    },
+
+    /// Print persist and temp config as JSON
+    Export,
+
+    /// Replace config with an export read from stdin
+    Import,
+
+    /// Print config changes as JSON lines until interrupted
+    Watch,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -225,6 +378,7 @@ pub fn run() -> Result<()> {
     }
 
     let cli = Args::parse();
+    defs::init_root(cli.root.clone());
 
     log::info!("command: {:?}", cli.command);
 
@@ -239,6 +393,15 @@ pub fn run() -> Result<()> {
 
         Commands::UidListener => event::start_uid_listener(),
 
+        Commands::Backup { command } => match command {
+            BackupCmd::Create { file } => backup::create(&file),
+            BackupCmd::Restore { file } => backup::restore(&file),
+        },
+
+        Commands::Supervisor => supervisor::run(),
+
+        Commands::BootReport { last } => boot_report::print_reports(last),
+
         Commands::Insmod { module, params } => insmod::insmod(&module, &params),
 
         Commands::SoftReboot => event::soft_reboot(cli.superkey),
@@ -261,21 +424,37 @@ pub fn run() -> Result<()> {
         }
 
         Commands::Module { command } => {
+            // a sandbox root lives in our own namespace, init's view is irrelevant
             #[cfg(any(target_os = "linux", target_os = "android"))]
-            {
-                utils::switch_mnt_ns(1)?;
+            if defs::is_default_root() {
+                platform::get().switch_mnt_ns(1)?;
             }
             match command {
                 Module::Install { zip } => module::install_module(&zip),
-                Module::Uninstall { id } => module::uninstall_module(&id),
+                Module::Inspect { zip, json } => inspect::print_inspection(&zip, json),
+                Module::Restart { id } => {
+                    restart::restart_module(&id, cli.superkey.as_deref().unwrap_or(""))
+                }
+                Module::Services { json } => supervisor::print_services(json),
+                Module::Logs { id, stage } => module_log::print_logs(&id, stage.as_deref()),
+                Module::Verify { id, json } => integrity::print_verification(id.as_deref(), json),
+                Module::Uninstall { id, force } => module::uninstall_module(&id, force),
+                Module::Rollback { id } => snapshot::rollback(&id),
                 Module::UndoUninstall { id } => module::undo_uninstall_module(&id),
                 Module::Action { id } => module::run_action(&id),
                 Module::Lua { id, function } => {
                     lua::run_lua(&id, &function, false, true).map_err(|e| anyhow::anyhow!("{}", e))
                 }
-                Module::Enable { id } => module::enable_module(&id),
-                Module::Disable { id } => module::disable_module(&id),
+                Module::Enable { id, now } => module::enable_module(&id, now),
+                Module::Disable { id, now } => module::disable_module(&id, now),
                 Module::List => module::list_modules(),
+                Module::Trash { command } => match command {
+                    TrashCmd::List => trash::list(),
+                    TrashCmd::Restore { id } => trash::restore(&id),
+                    TrashCmd::Purge { id } => trash::purge(id.as_deref()),
+                },
+                Module::CheckUpdates { json } => module_update::print_updates(json),
+                Module::Update { id } => module_update::update_module(&id),
                 Module::Config { internal, command } => {
                     let module_id = match internal {
                         Some(internal_name) => format!("internal.{internal_name}"),
@@ -288,8 +467,18 @@ pub fn run() -> Result<()> {
 
                     match command {
                         ModuleConfigCmd::Get { key } => {
-                            // Use merge_configs to respect priority (temp overrides persist)
-                            let config = module_config::merge_configs(&module_id)?;
+                            // Binary values go out as they are
+                            if let Some(module_config::ConfigEntry {
+                                value: module_config::ConfigValue::Blob(bytes),
+                                ..
+                            }) = module_config::get_config_entry(&module_id, &key)?
+                            {
+                                use std::io::Write;
+                                std::io::stdout().write_all(&bytes)?;
+                                return Ok(());
+                            }
+                            // Temp overrides persist, schema defaults fill in the rest
+                            let config = module_config::effective_config(&module_id)?;
                             match config.get(&key) {
                                 Some(value) => {
                                     println!("{value}");
@@ -302,42 +491,46 @@ pub fn run() -> Result<()> {
                             key,
                             value,
                             stdin,
+                            blob,
                             temp,
                         } => {
                             // Validate key at CLI layer for better user experience
                             module_config::validate_config_key(&key)?;
 
                             // Read value from stdin or argument
-                            let value_str = match value {
-                                Some(v) if !stdin => v,
+                            let value = match value {
+                                Some(v) if !stdin => v.into_bytes(),
                                 _ => {
                                     // Read from stdin
                                     use std::io::Read;
-                                    let mut buffer = String::new();
+                                    let mut buffer = Vec::new();
                                     std::io::stdin()
-                                        .read_to_string(&mut buffer)
+                                        .read_to_end(&mut buffer)
                                         .context("Failed to read from stdin")?;
                                     buffer
                                 }
                             };
 
-                            // Validate value
-                            module_config::validate_config_value(&value_str)?;
-
                             let config_type = if temp {
                                 module_config::ConfigType::Temp
                             } else {
                                 module_config::ConfigType::Persist
                             };
-                            module_config::set_config_value(
-                                &module_id,
-                                &key,
-                                &value_str,
-                                config_type,
-                            )
+                            if blob {
+                                module_config::set_config_blob(&module_id, &key, value, config_type)
+                            } else {
+                                let value_str = String::from_utf8(value)
+                                    .context("Value is not UTF-8, use --blob")?;
+                                module_config::set_config_value(
+                                    &module_id,
+                                    &key,
+                                    &value_str,
+                                    config_type,
+                                )
+                            }
                         }
                         ModuleConfigCmd::List => {
-                            let config = module_config::merge_configs(&module_id)?;
+                            let config = module_config::effective_config(&module_id)?;
                             if config.is_empty() {
                                 println!("No config entries found");
                             } else {
@@ -363,6 +556,17 @@ pub fn run() -> Result<()> {
                             };
                             module_config::clear_config(&module_id, config_type)
                         }
+                        ModuleConfigCmd::Apply { temp } => {
+                            let config_type = if temp {
+                                module_config::ConfigType::Temp
+                            } else {
+                                module_config::ConfigType::Persist
+                            };
+                            config_batch::apply(&module_id, config_type)
+                        }
+                        ModuleConfigCmd::Export => config_batch::export(&module_id),
+                        ModuleConfigCmd::Import => config_batch::import(&module_id),
+                        ModuleConfigCmd::Watch => config_events::watch(&module_id),
                     }
                 }
             }
@@ -0,0 +1,367 @@
+//! Batch module config operations
+//!
+//! `apd module config apply` reads a JSON object or `key=value` lines from
+//! stdin and stores all of them with one write. In JSON, `null` deletes a key,
+//! numbers and booleans are taken as their text and objects and arrays as
+//! compact JSON. Lines that are empty or start with `#` are skipped.
+//!
+//! `apd module config export` prints the persist and temp config of a module
+//! with types and modification times:
+//!
+//! ```json
+//! {
+//!   "format": 1,
+//!   "module": "example",
+//!   "persist": { "port": { "type": "int", "value": 8080, "modified": 1700000000 } },
+//!   "temp": {}
+//! }
+//! ```
+//!
+//! Blobs are exported as hex. `apd module config import` reads such a
+//! document from stdin and replaces each config it contains; both are checked
+//! against the module's schema and limits before either is written, and if
+//! writing the second fails the first is put back.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Read},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config_schema,
+    module_config::{self, ConfigEntry, ConfigType, ConfigValue},
+};
+
+const EXPORT_FORMAT: u32 = 1;
+
+fn read_stdin() -> Result<String> {
+    let mut input = String::new();
+    io::stdin()
+        .read_to_string(&mut input)
+        .context("Failed to read from stdin")?;
+    Ok(input)
+}
+
+/// Changes given as a JSON object or as `key=value` lines
+fn parse_changes(input: &str) -> Result<Vec<(String, Option<String>)>> {
+    if input.trim_start().starts_with('{') {
+        let object: serde_json::Map<String, Value> =
+            serde_json::from_str(input).context("Invalid JSON object")?;
+        return Ok(object
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::Null => None,
+                    Value::String(s) => Some(s),
+                    other => Some(other.to_string()),
+                };
+                (key, value)
+            })
+            .collect());
+    }
+
+    let mut changes = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("line {}: expected key=value", i + 1);
+        };
+        changes.push((key.trim().to_string(), Some(value.to_string())));
+    }
+    Ok(changes)
+}
+
+/// `apd module config apply`
+pub fn apply(module_id: &str, config_type: ConfigType) -> Result<()> {
+    let changes = parse_changes(&read_stdin()?)?;
+    ensure!(!changes.is_empty(), "No config changes given");
+    module_config::apply_config(module_id, config_type, &changes)?;
+    println!("Applied {} change(s)", changes.len());
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    value: Value,
+    #[serde(default)]
+    modified: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Export {
+    format: u32,
+    #[serde(default)]
+    module: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    persist: Option<BTreeMap<String, ExportedEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temp: Option<BTreeMap<String, ExportedEntry>>,
+}
+
+fn export_entry(entry: ConfigEntry) -> ExportedEntry {
+    let (kind, value) = match entry.value {
+        ConfigValue::String(s) => ("string", Value::String(s)),
+        ConfigValue::Int(n) => ("int", Value::from(n)),
+        ConfigValue::Bool(b) => ("bool", Value::Bool(b)),
+        ConfigValue::Json(s) => ("json", Value::String(s)),
+        value @ ConfigValue::Blob(_) => ("blob", Value::String(value.to_text())),
+    };
+    ExportedEntry {
+        kind: kind.to_string(),
+        value,
+        modified: entry.modified,
+    }
+}
+
+fn import_entry(
+    schema: Option<&config_schema::Schema>,
+    key: &str,
+    entry: ExportedEntry,
+) -> Result<ConfigEntry> {
+    module_config::validate_config_key(key)?;
+    let text = match entry.value {
+        Value::String(s) => s,
+        other => other.to_string(),
+    };
+    let value = match (entry.kind.as_str(), schema.and_then(|s| s.entry(key))) {
+        ("blob", _) => {
+            if let Some(schema) = schema {
+                schema.check_blob(key)?;
+            }
+            ConfigValue::Blob(
+                module_config::decode_hex(&text).with_context(|| format!("{key}: bad blob"))?,
+            )
+        }
+        // a schema entry decides the type, the exported one is all there is otherwise
+        ("string" | "int" | "bool" | "json", Some(schema_entry)) => {
+            schema_entry.typed_value(&text)?
+        }
+        ("string", None) => ConfigValue::String(text),
+        ("int", None) => ConfigValue::Int(
+            text.parse()
+                .with_context(|| format!("{key}: invalid int"))?,
+        ),
+        ("bool", None) => match text.as_str() {
+            "true" => ConfigValue::Bool(true),
+            "false" => ConfigValue::Bool(false),
+            _ => bail!("{key}: invalid bool"),
+        },
+        ("json", None) => {
+            serde_json::from_str::<Value>(&text).with_context(|| format!("{key}: invalid JSON"))?;
+            ConfigValue::Json(text)
+        }
+        (other, _) => bail!("{key}: unknown type {other}"),
+    };
+    Ok(ConfigEntry {
+        value,
+        modified: entry.modified,
+    })
+}
+
+/// `apd module config export`
+pub fn export(module_id: &str) -> Result<()> {
+    let section = |config_type| -> Result<BTreeMap<String, ExportedEntry>> {
+        Ok(module_config::load_entries(module_id, config_type)?
+            .into_iter()
+            .map(|(key, entry)| (key, export_entry(entry)))
+            .collect())
+    };
+    let export = Export {
+        format: EXPORT_FORMAT,
+        module: module_id.to_string(),
+        persist: Some(section(ConfigType::Persist)?),
+        temp: Some(section(ConfigType::Temp)?),
+    };
+    println!("{}", serde_json::to_string_pretty(&export)?);
+    Ok(())
+}
+
+/// `apd module config import`
+pub fn import(module_id: &str) -> Result<()> {
+    let export: Export = serde_json::from_str(&read_stdin()?).context("Invalid config export")?;
+    import_config(module_id, export)
+}
+
+/// Put back a config file as it was before the import, `None` if it was missing
+fn restore(module_id: &str, config_type: ConfigType, old: Option<Vec<u8>>) -> Result<()> {
+    match old {
+        Some(data) => module_config::write_config(module_id, config_type, &data),
+        None => {
+            let path = module_config::get_config_path(module_id, config_type);
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+fn import_config(module_id: &str, export: Export) -> Result<()> {
+    ensure!(
+        export.format == EXPORT_FORMAT,
+        "Unsupported config export format {}",
+        export.format
+    );
+    if !export.module.is_empty() && export.module != module_id {
+        warn!("importing config of {} into {module_id}", export.module);
+    }
+
+    let schema = config_schema::load(module_id)?;
+    let limits = module_config::limits(module_id);
+    // build and encode both configs before writing anything
+    let mut imports = Vec::new();
+    for (config_type, section) in [
+        (ConfigType::Persist, export.persist),
+        (ConfigType::Temp, export.temp),
+    ] {
+        let Some(section) = section else {
+            continue;
+        };
+        let entries = section
+            .into_iter()
+            .map(|(key, entry)| {
+                let entry = import_entry(schema.as_ref(), &key, entry)?;
+                module_config::validate_config_value(&entry.value.encode(), &limits)
+                    .with_context(|| format!("Invalid config value for key '{key}'"))?;
+                Ok((key, entry))
+            })
+            .collect::<Result<HashMap<_, _>>>()
+            .with_context(|| format!("Invalid {} config", config_type.name()))?;
+        let data = module_config::encode_entries(module_id, &entries)
+            .with_context(|| format!("Invalid {} config", config_type.name()))?;
+        imports.push((config_type, data, entries.len()));
+    }
+    ensure!(!imports.is_empty(), "Nothing to import");
+
+    // a failed write puts back what was written before it
+    let mut written = Vec::new();
+    let mut olds = Vec::new();
+    for (config_type, data, _) in &imports {
+        let config_type = *config_type;
+        olds.push(module_config::load_config(module_id, config_type).unwrap_or_default());
+        let old_data = fs::read(module_config::get_config_path(module_id, config_type)).ok();
+        if let Err(e) = module_config::write_config(module_id, config_type, data) {
+            for (config_type, old_data) in written.into_iter().rev() {
+                if let Err(e) = restore(module_id, config_type, old_data) {
+                    warn!("Failed to restore {} config: {e:#}", config_type.name());
+                }
+            }
+            return Err(e.context(format!("Failed to import {} config", config_type.name())));
+        }
+        written.push((config_type, old_data));
+    }
+
+    for ((config_type, _, count), old) in imports.iter().zip(olds) {
+        let new = module_config::load_config(module_id, *config_type)?;
+        module_config::notify_changes(module_id, *config_type, &old, &new);
+        println!("Imported {count} {} config entries", config_type.name());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::{FakePlatform, with_fake};
+
+    fn change(key: &str, value: Option<&str>) -> (String, Option<String>) {
+        (key.to_string(), value.map(str::to_string))
+    }
+
+    #[test]
+    fn parses_json_changes() {
+        let mut changes =
+            parse_changes(r#"{"gone": null, "name": "x", "port": 80, "on": true, "list": [1, 2]}"#)
+                .unwrap();
+        changes.sort();
+        assert_eq!(
+            changes,
+            [
+                change("gone", None),
+                change("list", Some("[1,2]")),
+                change("name", Some("x")),
+                change("on", Some("true")),
+                change("port", Some("80")),
+            ]
+        );
+        assert!(parse_changes("{ broken").is_err());
+    }
+
+    #[test]
+    fn parses_key_value_lines() {
+        let input = "# comment\n\n  # indented comment\nname = a=b\nempty=\n";
+        assert_eq!(
+            parse_changes(input).unwrap(),
+            [change("name", Some(" a=b")), change("empty", Some(""))]
+        );
+        let err = parse_changes("ok=1\nmissing\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn entries_round_trip_through_export() {
+        for value in [
+            ConfigValue::String("text".to_string()),
+            ConfigValue::Int(-7),
+            ConfigValue::Bool(false),
+            ConfigValue::Json(r#"{"a":1}"#.to_string()),
+            ConfigValue::Blob(vec![0, 0xab]),
+        ] {
+            let entry = ConfigEntry {
+                value,
+                modified: 1_700_000_000,
+            };
+            // through JSON, as export prints it and import reads it
+            let json = serde_json::to_string(&export_entry(entry.clone())).unwrap();
+            let exported = serde_json::from_str(&json).unwrap();
+            assert_eq!(import_entry(None, "key", exported).unwrap(), entry);
+        }
+        let bad = ExportedEntry {
+            kind: "int".to_string(),
+            value: Value::from("seven"),
+            modified: 0,
+        };
+        assert!(import_entry(None, "key", bad).is_err());
+    }
+
+    #[test]
+    fn failed_import_keeps_the_old_config() {
+        with_fake(FakePlatform::default(), |_| {
+            let old = HashMap::from([("key".to_string(), "old".to_string())]);
+            module_config::save_config("mod_a", ConfigType::Persist, &old).unwrap();
+            // the temp config can not be replaced
+            let temp = module_config::get_config_path("mod_a", ConfigType::Temp);
+            fs::create_dir_all(temp.join("blocker")).unwrap();
+
+            let section = |value: &str| {
+                let entry = ExportedEntry {
+                    kind: "string".to_string(),
+                    value: Value::from(value),
+                    modified: 0,
+                };
+                Some(BTreeMap::from([("key".to_string(), entry)]))
+            };
+            let export = Export {
+                format: EXPORT_FORMAT,
+                module: String::new(),
+                persist: section("new"),
+                temp: section("new"),
+            };
+            assert!(import_config("mod_a", export).is_err());
+            assert_eq!(
+                module_config::load_config("mod_a", ConfigType::Persist).unwrap(),
+                old
+            );
+        });
+    }
+}
@@ -0,0 +1,289 @@
+//! Module config change notifications
+//!
+//! Whenever `module_config` stores a changed value or drops a key, the owning
+//! module is told through `<module>/on_config_change.sh` and the Lua
+//! `on_config_change(key, old, new)` callback of `<id>.lua`, both with their
+//! output in the module log `on_config_change`. The script gets the change in
+//! `AP_CONFIG_KEY`, `AP_CONFIG_TYPE` (`persist` or `temp`) and, unless they are
+//! unset or too big for the environment, `AP_CONFIG_OLD` and `AP_CONFIG_NEW`.
+//! Only active modules are notified, and changes made from inside a hook do not
+//! notify again. For the script that covers everything it runs while it is
+//! running; a daemon it leaves behind notifies as usual once the hook is done.
+//!
+//! `apd module config watch` prints every change of a module's config as one
+//! JSON object per line, whoever made it, and fails once the config dir is
+//! removed, e.g. by an uninstall.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+};
+
+use anyhow::{Result, bail};
+use log::{debug, warn};
+use notify::{Config, Event, EventKind, INotifyWatcher, RecursiveMode, Watcher, event::ModifyKind};
+use serde::Serialize;
+
+use crate::{
+    boot_report::Outcome,
+    defs, lua,
+    module::{run_stage_script_with_envs, script_timeout},
+    module_config::{self, ConfigType},
+    utils::ensure_dir_exists,
+};
+
+const HOOK: &str = "on_config_change";
+/// Set in the environment of `on_config_change.sh` to the pid of the apd
+/// running it
+const HOOK_ENV: &str = "AP_CONFIG_HOOK";
+/// Values longer than this are left out of the hook environment
+const MAX_ENV_VALUE_LEN: usize = 4096;
+
+static IN_HOOK: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize)]
+pub struct Change<'a> {
+    pub key: &'a str,
+    #[serde(rename = "type")]
+    pub config_type: &'static str,
+    pub old: Option<&'a str>,
+    pub new: Option<&'a str>,
+}
+
+fn run_hooks(module_id: &str, change: &Change) -> Result<()> {
+    let module = defs::module_dir().join(module_id);
+    let script = module.join(format!("{HOOK}.sh"));
+    if script.exists() {
+        let mut envs = vec![
+            (HOOK_ENV, std::process::id().to_string()),
+            ("AP_CONFIG_KEY", change.key.to_string()),
+            ("AP_CONFIG_TYPE", change.config_type.to_string()),
+        ];
+        for (name, value) in [("AP_CONFIG_OLD", change.old), ("AP_CONFIG_NEW", change.new)] {
+            if let Some(value) = value.filter(|v| v.len() <= MAX_ENV_VALUE_LEN) {
+                envs.push((name, value.to_string()));
+            }
+        }
+        let timeout = script_timeout(Some(&module));
+        match run_stage_script_with_envs(module_id, HOOK, &script, &envs, true, timeout)? {
+            Outcome::Exited(status) if !status.success() => {
+                bail!("{HOOK}.sh failed with {status}")
+            }
+            Outcome::TimedOut(timeout) => {
+                bail!("{HOOK}.sh timed out after {}s", timeout.as_secs())
+            }
+            _ => {}
+        }
+    }
+    lua::exec_module_hook(module_id, HOOK, (change.key, change.old, change.new))?;
+    Ok(())
+}
+
+fn parent_of(pid: u32) -> Option<u32> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Whether we run below a hook, i.e. the apd that started one is our ancestor
+fn in_hook_script() -> bool {
+    let Some(hook_apd) = std::env::var(HOOK_ENV)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+    else {
+        return false;
+    };
+    let mut pid = std::os::unix::process::parent_id();
+    while pid > 1 {
+        if pid == hook_apd {
+            return true;
+        }
+        let Some(parent) = parent_of(pid) else {
+            return false;
+        };
+        pid = parent;
+    }
+    false
+}
+
+fn is_active(module: &Path) -> bool {
+    module.is_dir()
+        && !module.join(defs::DISABLE_FILE_NAME).exists()
+        && !module.join(defs::REMOVE_FILE_NAME).exists()
+}
+
+/// Tell module `module_id` that `key` went from `old` to `new`.
+/// The change is already stored, so hook failures are only logged.
+pub fn notify_change(
+    module_id: &str,
+    config_type: ConfigType,
+    key: &str,
+    old: Option<&str>,
+    new: Option<&str>,
+) {
+    if old == new || !is_active(&defs::module_dir().join(module_id)) {
+        return;
+    }
+    if in_hook_script() || IN_HOOK.swap(true, Ordering::SeqCst) {
+        debug!("config of {module_id} changed by its own {HOOK} hook, not notifying");
+        return;
+    }
+    let change = Change {
+        key,
+        config_type: config_type.name(),
+        old,
+        new,
+    };
+    if let Err(e) = run_hooks(module_id, &change) {
+        warn!("{HOOK} of {module_id} for {key} failed: {e:#}");
+    }
+    IN_HOOK.store(false, Ordering::SeqCst);
+}
+
+fn load_all(module_id: &str) -> [HashMap<String, String>; 2] {
+    [ConfigType::Persist, ConfigType::Temp].map(|config_type| {
+        module_config::load_config(module_id, config_type).unwrap_or_else(|e| {
+            warn!("Failed to load {} config: {e:#}", config_type.name());
+            HashMap::new()
+        })
+    })
+}
+
+/// Every key that differs between `old` and `new`, in key order
+pub fn changes<'a>(
+    config_type: ConfigType,
+    old: &'a HashMap<String, String>,
+    new: &'a HashMap<String, String>,
+) -> Vec<Change<'a>> {
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .map(|key| Change {
+            key,
+            config_type: config_type.name(),
+            old: old.get(key).map(String::as_str),
+            new: new.get(key).map(String::as_str),
+        })
+        .filter(|change| change.old != change.new)
+        .collect()
+}
+
+/// `apd module config watch`
+pub fn watch(module_id: &str) -> Result<()> {
+    let dir = module_config::get_config_path(module_id, ConfigType::Persist)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    ensure_dir_exists(&dir)?;
+
+    // sends whether the watched dir itself is gone, which ends the watch
+    let (tx, rx) = mpsc::channel();
+    let watched = dir.clone();
+    let mut watcher = INotifyWatcher::new(
+        move |ev: notify::Result<Event>| match ev {
+            Ok(event) => {
+                let gone = matches!(
+                    event.kind,
+                    EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+                ) && event.paths.contains(&watched);
+                let relevant = event.paths.iter().any(|path| {
+                    path.file_name().is_some_and(|name| {
+                        name == defs::PERSIST_CONFIG_NAME || name == defs::TEMP_CONFIG_NAME
+                    })
+                });
+                if gone || relevant {
+                    let _ = tx.send(gone);
+                }
+            }
+            Err(err) => warn!("inotify error: {err}"),
+        },
+        Config::default(),
+    )?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    let mut current = load_all(module_id);
+    while let Ok(mut gone) = rx.recv() {
+        // one save is several events, take them all before reloading
+        while let Ok(next) = rx.try_recv() {
+            gone |= next;
+        }
+        let next = load_all(module_id);
+        for (i, config_type) in [ConfigType::Persist, ConfigType::Temp]
+            .into_iter()
+            .enumerate()
+        {
+            for change in changes(config_type, &current[i], &next[i]) {
+                println!("{}", serde_json::to_string(&change)?);
+            }
+        }
+        current = next;
+        if gone {
+            // uninstalled, or the configs were cleared: nothing left to watch
+            bail!("{} was removed", dir.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::platform::{FakePlatform, with_fake};
+
+    fn config(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn changes_lists_differing_keys_in_order() {
+        let old = config(&[("b.key", "1"), ("a.key", "1"), ("same", "x")]);
+        let new = config(&[("c.key", "1"), ("a.key", "2"), ("same", "x")]);
+        let changes = changes(ConfigType::Temp, &old, &new);
+        let found: Vec<_> = changes
+            .iter()
+            .map(|c| (c.key, c.config_type, c.old, c.new))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("a.key", "temp", Some("1"), Some("2")),
+                ("b.key", "temp", Some("1"), None),
+                ("c.key", "temp", None, Some("1")),
+            ]
+        );
+        assert!(super::changes(ConfigType::Persist, &old, &old).is_empty());
+    }
+
+    #[test]
+    fn watch_ends_when_the_config_dir_is_removed() {
+        with_fake(FakePlatform::default(), |_| {
+            let dir = module_config::get_config_path("mod_a", ConfigType::Persist)
+                .parent()
+                .unwrap()
+                .to_path_buf();
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let _ = tx.send(watch("mod_a"));
+            });
+            while !dir.exists() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            // give the watch time to be set up
+            thread::sleep(Duration::from_millis(200));
+            module_config::clear_module_configs("mod_a").unwrap();
+            let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(result.is_err());
+        });
+    }
+}
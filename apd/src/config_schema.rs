@@ -0,0 +1,383 @@
+//! Typed module config schema
+//!
+//! A module can describe its config keys in `<module>/config.schema.json`:
+//!
+//! ```json
+//! {
+//!   "entries": [
+//!     { "key": "log.level", "type": "enum", "values": ["debug", "info"], "default": "info" },
+//!     { "key": "port", "type": "int", "min": 1, "max": 65535, "default": 8080,
+//!       "description": "Listen port" }
+//!   ],
+//!   "limits": { "max_entries": 64, "max_value_len": 65536 }
+//! }
+//! ```
+//!
+//! Types are `bool`, `int`, `enum`, `string`, `json` and `blob`. `bool`, `int`
+//! and `json` values are stored typed, `blob` ones only through
+//! `set --blob`. `min`/`max` bound an `int` value or the length of a `string`.
+//! `limits` replaces the default entry count and value size caps of the
+//! module's config. `apd module config set` rejects
+//! values that do not fit their entry and stores them normalized (`true`/`false`,
+//! plain decimal, compact JSON), `get` and `list` fall back to the defaults.
+//! Keys the schema does not mention are stored unchecked.
+
+use std::{collections::HashMap, fs, io};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    defs,
+    module_config::{ConfigValue, Limits, validate_config_key},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryType {
+    Bool,
+    Int,
+    Enum,
+    String,
+    Json,
+    Blob,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    #[serde(rename = "type")]
+    pub kind: EntryType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Schema {
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<Limits>,
+}
+
+fn in_range(entry: &Entry, n: i64) -> bool {
+    entry.min.is_none_or(|min| n >= min) && entry.max.is_none_or(|max| n <= max)
+}
+
+fn describe_range(entry: &Entry) -> String {
+    match (entry.min, entry.max) {
+        (Some(min), Some(max)) => format!("{min}..={max}"),
+        (Some(min), None) => format!(">= {min}"),
+        (None, Some(max)) => format!("<= {max}"),
+        (None, None) => String::new(),
+    }
+}
+
+impl Entry {
+    /// Check `value` against the entry and return the form it is stored in
+    pub fn normalize(&self, value: &str) -> Result<String> {
+        let key = &self.key;
+        match self.kind {
+            EntryType::Bool => match value.trim() {
+                "true" | "1" | "yes" | "on" => Ok("true".to_string()),
+                "false" | "0" | "no" | "off" => Ok("false".to_string()),
+                _ => bail!("{key}: expected a bool, got '{value}'"),
+            },
+            EntryType::Int => {
+                let n: i64 = value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("{key}: expected an integer, got '{value}'"))?;
+                ensure!(
+                    in_range(self, n),
+                    "{key}: {n} is out of range {}",
+                    describe_range(self)
+                );
+                Ok(n.to_string())
+            }
+            EntryType::Enum => {
+                ensure!(
+                    self.values.iter().any(|v| v == value),
+                    "{key}: '{value}' is not one of {}",
+                    self.values.join(", ")
+                );
+                Ok(value.to_string())
+            }
+            EntryType::String => {
+                let len = value.chars().count() as i64;
+                ensure!(
+                    in_range(self, len),
+                    "{key}: length {len} is out of range {}",
+                    describe_range(self)
+                );
+                Ok(value.to_string())
+            }
+            EntryType::Json => {
+                let json: Value =
+                    serde_json::from_str(value).with_context(|| format!("{key}: invalid JSON"))?;
+                Ok(json.to_string())
+            }
+            EntryType::Blob => bail!("{key}: binary value, set it with --blob"),
+        }
+    }
+
+    /// [`Entry::normalize`] into the typed value that gets stored
+    pub fn typed_value(&self, value: &str) -> Result<ConfigValue> {
+        let value = self.normalize(value)?;
+        Ok(match self.kind {
+            EntryType::Bool => ConfigValue::Bool(value == "true"),
+            EntryType::Int => ConfigValue::Int(value.parse()?),
+            EntryType::Json => ConfigValue::Json(value),
+            _ => ConfigValue::String(value),
+        })
+    }
+
+    /// Default in stored form
+    pub fn default_value(&self) -> Option<String> {
+        let default = self.default.as_ref()?;
+        let raw = match (self.kind, default) {
+            (EntryType::Json, value) => value.to_string(),
+            (_, Value::String(s)) => s.clone(),
+            (_, value) => value.to_string(),
+        };
+        self.normalize(&raw).ok()
+    }
+
+    fn check(&self) -> Result<()> {
+        let key = &self.key;
+        ensure!(
+            self.kind != EntryType::Enum || !self.values.is_empty(),
+            "{key}: enum without values"
+        );
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            ensure!(min <= max, "{key}: min {min} is greater than max {max}");
+        }
+        if self.default.is_some() && self.default_value().is_none() {
+            bail!("{key}: default does not match the entry");
+        }
+        Ok(())
+    }
+}
+
+impl Schema {
+    pub fn entry(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.key == key)
+    }
+
+    /// Typed value for `key`, keys without an entry are stored as strings
+    pub fn typed_value(&self, key: &str, value: &str) -> Result<ConfigValue> {
+        match self.entry(key) {
+            Some(entry) => entry.typed_value(value),
+            None => Ok(ConfigValue::String(value.to_string())),
+        }
+    }
+
+    /// Binary values fit keys without an entry and `blob` entries
+    pub fn check_blob(&self, key: &str) -> Result<()> {
+        match self.entry(key) {
+            Some(entry) if entry.kind != EntryType::Blob => {
+                bail!(
+                    "{key}: {} entry, binary values need a blob entry",
+                    format!("{:?}", entry.kind).to_lowercase()
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Add the default of every entry `config` has no value for
+    pub fn fill_defaults(&self, config: &mut HashMap<String, String>) {
+        for entry in &self.entries {
+            if !config.contains_key(&entry.key)
+                && let Some(default) = entry.default_value()
+            {
+                config.insert(entry.key.clone(), default);
+            }
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let schema: Self = serde_json::from_str(content)?;
+        if let Some(limits) = &schema.limits {
+            limits.check()?;
+        }
+        for (i, entry) in schema.entries.iter().enumerate() {
+            validate_config_key(&entry.key)?;
+            ensure!(
+                schema.entries[..i].iter().all(|e| e.key != entry.key),
+                "duplicate key {}",
+                entry.key
+            );
+            entry.check()?;
+        }
+        Ok(schema)
+    }
+}
+
+/// Schema shipped by the installed module `id`, if any
+pub fn load(id: &str) -> Result<Option<Schema>> {
+    // internal.<name> configs and anything that is not a plain id have no module dir
+    if id.is_empty() || id.contains('/') || id.starts_with('.') {
+        return Ok(None);
+    }
+    let path = defs::module_dir()
+        .join(id)
+        .join(defs::CONFIG_SCHEMA_FILE_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Schema::parse(&content)
+        .map(Some)
+        .with_context(|| format!("invalid config schema of {id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn entry(value: Value) -> Entry {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn normalizes_values() {
+        let flag = entry(json!({ "key": "flag", "type": "bool" }));
+        assert_eq!(flag.normalize(" yes ").unwrap(), "true");
+        assert_eq!(flag.normalize("0").unwrap(), "false");
+        assert!(flag.normalize("maybe").is_err());
+
+        let port = entry(json!({ "key": "port", "type": "int", "min": 1, "max": 65535 }));
+        assert_eq!(port.normalize(" 080 ").unwrap(), "80");
+        assert!(port.normalize("0").is_err());
+        assert!(port.normalize("65536").is_err());
+        assert!(port.normalize("eighty").is_err());
+
+        let level = entry(json!({ "key": "level", "type": "enum", "values": ["debug", "info"] }));
+        assert_eq!(level.normalize("info").unwrap(), "info");
+        assert!(level.normalize("INFO").is_err());
+
+        let name = entry(json!({ "key": "name", "type": "string", "max": 3 }));
+        assert_eq!(name.normalize("äöü").unwrap(), "äöü");
+        assert!(name.normalize("abcd").is_err());
+
+        let rules = entry(json!({ "key": "rules", "type": "json" }));
+        assert_eq!(
+            rules.normalize("{ \"a\": [1, 2] }").unwrap(),
+            r#"{"a":[1,2]}"#
+        );
+        assert!(rules.normalize("{").is_err());
+
+        let data = entry(json!({ "key": "data", "type": "blob" }));
+        assert!(data.normalize("00ff").is_err());
+    }
+
+    #[test]
+    fn typed_values() {
+        let schema = Schema::parse(
+            r#"{ "entries": [
+                { "key": "flag", "type": "bool" },
+                { "key": "port", "type": "int" },
+                { "key": "rules", "type": "json" },
+                { "key": "level", "type": "enum", "values": ["info"] }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            schema.typed_value("flag", "on").unwrap(),
+            ConfigValue::Bool(true)
+        );
+        assert_eq!(
+            schema.typed_value("port", "8080").unwrap(),
+            ConfigValue::Int(8080)
+        );
+        assert_eq!(
+            schema.typed_value("rules", "[ 1 ]").unwrap(),
+            ConfigValue::Json("[1]".to_string())
+        );
+        assert_eq!(
+            schema.typed_value("level", "info").unwrap(),
+            ConfigValue::String("info".to_string())
+        );
+        assert_eq!(
+            schema.typed_value("other", "on").unwrap(),
+            ConfigValue::String("on".to_string())
+        );
+        assert!(schema.typed_value("port", "x").is_err());
+    }
+
+    #[test]
+    fn default_values() {
+        let port = entry(json!({ "key": "port", "type": "int", "default": 8080 }));
+        assert_eq!(port.default_value().as_deref(), Some("8080"));
+        let flag = entry(json!({ "key": "flag", "type": "bool", "default": "on" }));
+        assert_eq!(flag.default_value().as_deref(), Some("true"));
+        let rules = entry(json!({ "key": "rules", "type": "json", "default": { "a": 1 } }));
+        assert_eq!(rules.default_value().as_deref(), Some(r#"{"a":1}"#));
+        let bad = entry(json!({ "key": "port", "type": "int", "max": 10, "default": 11 }));
+        assert_eq!(bad.default_value(), None);
+        let none = entry(json!({ "key": "port", "type": "int" }));
+        assert_eq!(none.default_value(), None);
+
+        let schema = Schema {
+            entries: vec![port, flag, none],
+            limits: None,
+        };
+        let mut config = HashMap::from([("port".to_string(), "1".to_string())]);
+        schema.fill_defaults(&mut config);
+        assert_eq!(config["port"], "1");
+        assert_eq!(config["flag"], "true");
+        assert!(!config.contains_key("none"));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_schemas() {
+        assert!(Schema::parse("{}").unwrap().entries.is_empty());
+        for (schema, error) in [
+            (
+                r#"{ "entries": [{ "key": "level", "type": "enum" }] }"#,
+                "enum without values",
+            ),
+            (
+                r#"{ "entries": [{ "key": "port", "type": "int", "min": 2, "max": 1 }] }"#,
+                "min 2 is greater than max 1",
+            ),
+            (
+                r#"{ "entries": [{ "key": "flag", "type": "bool", "default": "maybe" }] }"#,
+                "default does not match",
+            ),
+            (
+                r#"{ "entries": [{ "key": "port", "type": "int" },
+                                 { "key": "port", "type": "bool" }] }"#,
+                "duplicate key port",
+            ),
+            (
+                r#"{ "entries": [{ "key": "1st", "type": "int" }] }"#,
+                "Invalid config key",
+            ),
+            (
+                r#"{ "entries": [{ "key": "port", "type": "float" }] }"#,
+                "unknown variant",
+            ),
+            (
+                r#"{ "limits": { "max_entries": 100000 } }"#,
+                "max_entries 100000 exceeds",
+            ),
+        ] {
+            let err = Schema::parse(schema).unwrap_err();
+            assert!(format!("{err:#}").contains(error), "{schema}: {err:#}");
+        }
+    }
+}
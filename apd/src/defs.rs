@@ -1,9 +1,15 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
 use const_format::concatcp;
 
 pub const ADB_DIR: &str = "/data/adb/";
 pub const WORKING_DIR: &str = concatcp!(ADB_DIR, "ap/");
 pub const BINARY_DIR: &str = concatcp!(WORKING_DIR, "bin/");
 pub const APATCH_LOG_FOLDER: &str = concatcp!(WORKING_DIR, "log/");
+pub const MODULE_LOG_FOLDER: &str = concatcp!(APATCH_LOG_FOLDER, "modules/");
 
 pub const AP_RC_PATH: &str = concatcp!(WORKING_DIR, ".aprc");
 pub const GLOBAL_NAMESPACE_FILE: &str = concatcp!(ADB_DIR, ".global_namespace_enable");
@@ -22,6 +28,16 @@ pub const MODULE_ACTION_SH: &str = "action.sh";
 pub const DISABLE_FILE_NAME: &str = "disable";
 pub const UPDATE_FILE_NAME: &str = "update";
 pub const REMOVE_FILE_NAME: &str = "remove";
+// consecutive stage script timeouts of a module
+pub const TIMEOUT_COUNT_FILE_NAME: &str = ".timeouts";
+// name of the trusted key the module zip was signed with
+pub const SIGNER_FILE_NAME: &str = ".signer";
+// long-running services a module wants supervised, see supervisor.rs
+pub const SERVICES_FILE_NAME: &str = "services.json";
+// hashes, modes, owners and labels of the module files at install time
+pub const INTEGRITY_FILE_NAME: &str = ".integrity.json";
+// typed config keys with defaults, see config_schema.rs
+pub const CONFIG_SCHEMA_FILE_NAME: &str = "config.schema.json";
 
 // Metamodule support
 pub const METAMODULE_MOUNT_SCRIPT: &str = "metamount.sh";
@@ -36,5 +52,120 @@ pub const TEMP_CONFIG_NAME: &str = "tmp.config";
 
 pub const PTS_NAME: &str = "pts";
 
+// Root permission and su state shared with the manager
+pub const PACKAGE_CONFIG_PATH: &str = concatcp!(WORKING_DIR, "package_config");
+pub const SU_PATH_FILE: &str = concatcp!(WORKING_DIR, "su_path");
+pub const JAILBREAK_FILE: &str = concatcp!(WORKING_DIR, "jailbreak");
+
+// Boot attempt counter and modules changed since the last good boot
+pub const BOOT_STATE_FILE: &str = concatcp!(WORKING_DIR, "boot_state.json");
+
+// Module zip signatures: trusted public keys and enforce/warn/off
+pub const TRUSTED_SIGNERS_FILE: &str = concatcp!(WORKING_DIR, "trusted_signers");
+pub const SIGNATURE_POLICY_FILE: &str = concatcp!(WORKING_DIR, "signature_policy");
+
+// What post-fs-data does with modules whose files changed: enforce, warn or off
+pub const INTEGRITY_POLICY_FILE: &str = concatcp!(WORKING_DIR, "integrity_policy");
+
+// How module zip extraction treats symlink entries: reject or contained
+pub const ZIP_SYMLINK_POLICY_FILE: &str = concatcp!(WORKING_DIR, "zip_symlink_policy");
+
+// Previous versions of updated modules, see snapshot.rs
+pub const MODULE_SNAPSHOT_DIR: &str = concatcp!(WORKING_DIR, "module_snapshots/");
+
+// Uninstalled modules kept for restore, and how many days they are kept
+pub const MODULE_TRASH_DIR: &str = concatcp!(WORKING_DIR, "module_trash/");
+pub const TRASH_RETENTION_FILE: &str = concatcp!(WORKING_DIR, "trash_retention_days");
+
+// Process groups of module stage scripts, for `apd module restart`
+pub const MODULE_RUN_DIR: &str = concatcp!(WORKING_DIR, "run/");
+
+// Last state of supervised module services, written by `apd supervisor`
+pub const SERVICE_STATE_FILE: &str = concatcp!(WORKING_DIR, "service_state.json");
+
+// Default timeout in seconds for blocking stage scripts, 0 disables it
+pub const SCRIPT_TIMEOUT_FILE: &str = concatcp!(WORKING_DIR, "script_timeout");
+
+// Maintained by PackageManager, read to keep root grants in sync with installed apps
+pub const SYSTEM_PACKAGES_LIST: &str = "/data/system/packages.list";
+
+// Shared files of the old Lua getConfig/setConfig, one per key, migrated as modules read them
+pub const LUA_CONFIG_DIR: &str = concatcp!(ADB_DIR, "config/");
+// Keys of LUA_CONFIG_DIR a module has looked up, one per line in its config dir
+pub const LUA_MIGRATED_NAME: &str = "lua_migrated";
+
 pub const VERSION_CODE: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION_CODE"));
 pub const VERSION_NAME: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION_NAME"));
+
+// Filesystem root
+//
+// All the paths above describe the on-device layout. They are resolved against
+// a runtime root (`/` on a device), so the daemon can be pointed at a sandbox
+// tree with `apd --root <dir>` or `APD_ROOT=<dir>`.
+pub const ROOT_ENV: &str = "APD_ROOT";
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Pin the filesystem root. `None` falls back to `$APD_ROOT`, then `/`.
+/// Only the first call has any effect, so call this before touching any path.
+pub fn init_root(root: Option<PathBuf>) {
+    let _ = ROOT.set(root.unwrap_or_else(root_from_env));
+}
+
+fn root_from_env() -> PathBuf {
+    std::env::var_os(ROOT_ENV)
+        .filter(|root| !root.is_empty())
+        .map_or_else(|| PathBuf::from("/"), PathBuf::from)
+}
+
+/// The filesystem root every apd path is resolved against
+pub fn root() -> &'static Path {
+    ROOT.get_or_init(root_from_env)
+}
+
+/// Whether apd operates on the real device tree rather than a sandbox root
+pub fn is_default_root() -> bool {
+    root() == Path::new("/")
+}
+
+/// Resolve an absolute on-device path (e.g. [`MODULE_DIR`]) under [`root`]
+pub fn resolve<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    root().join(path.strip_prefix("/").unwrap_or(path))
+}
+
+pub fn adb_dir() -> PathBuf {
+    resolve(ADB_DIR)
+}
+
+pub fn working_dir() -> PathBuf {
+    resolve(WORKING_DIR)
+}
+
+pub fn binary_dir() -> PathBuf {
+    resolve(BINARY_DIR)
+}
+
+pub fn log_dir() -> PathBuf {
+    resolve(APATCH_LOG_FOLDER)
+}
+
+pub fn daemon_path() -> PathBuf {
+    resolve(DAEMON_PATH)
+}
+
+pub fn module_dir() -> PathBuf {
+    resolve(MODULE_DIR)
+}
+
+pub fn module_update_dir() -> PathBuf {
+    resolve(MODULE_UPDATE_DIR)
+}
+
+pub fn metamodule_dir() -> PathBuf {
+    resolve(METAMODULE_DIR)
+}
+
+pub fn module_config_dir() -> PathBuf {
+    resolve(MODULE_CONFIG_DIR)
+}
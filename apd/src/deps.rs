@@ -0,0 +1,174 @@
+//! Module dependencies and conflicts
+//!
+//! module.prop may declare `requires=<id>[>=versionCode],...` and
+//! `conflicts=<id>,...`. Both are checked against the active modules when a
+//! module is installed, and `requires` again when one is uninstalled.
+
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+use log::warn;
+
+use crate::{
+    defs,
+    module::{ModuleType, foreach_module, read_module_prop},
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Requirement {
+    pub id: String,
+    pub min_version_code: Option<i64>,
+}
+
+fn split_list(value: Option<&String>) -> impl Iterator<Item = &str> {
+    value
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+pub fn parse_requires(props: &HashMap<String, String>) -> Result<Vec<Requirement>> {
+    split_list(props.get("requires"))
+        .map(|item| match item.split_once(">=") {
+            Some((id, version)) => {
+                let Ok(version) = version.trim().parse() else {
+                    bail!("invalid versionCode in requires: {item}");
+                };
+                Ok(Requirement {
+                    id: id.trim().to_string(),
+                    min_version_code: Some(version),
+                })
+            }
+            None => Ok(Requirement {
+                id: item.to_string(),
+                min_version_code: None,
+            }),
+        })
+        .collect()
+}
+
+pub fn parse_conflicts(props: &HashMap<String, String>) -> Vec<String> {
+    split_list(props.get("conflicts"))
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn version_code(props: &HashMap<String, String>) -> i64 {
+    props
+        .get("versionCode")
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// module.prop of every active module, keyed by id. A module waiting in
+/// modules_update counts with its new version, so a chain of modules can be
+/// installed without rebooting in between.
+fn active_modules() -> Result<HashMap<String, HashMap<String, String>>> {
+    let mut modules = HashMap::new();
+    if !defs::module_dir().exists() {
+        return Ok(modules);
+    }
+    foreach_module(ModuleType::Active, |module| {
+        let Some(id) = module.file_name().and_then(|n| n.to_str()) else {
+            return Ok(());
+        };
+        let updated = defs::module_update_dir().join(id);
+        let props = read_module_prop(&updated).or_else(|_| read_module_prop(module));
+        if let Ok(props) = props {
+            modules.insert(id.to_string(), props);
+        }
+        Ok(())
+    })?;
+
+    // freshly installed modules only exist in modules_update until the next boot
+    let update_dir = defs::module_update_dir();
+    if update_dir.exists() {
+        foreach_module(ModuleType::Updated, |module| {
+            let Some(id) = module.file_name().and_then(|n| n.to_str()) else {
+                return Ok(());
+            };
+            let installed = defs::module_dir().join(id);
+            if !installed.exists()
+                && let Ok(props) = read_module_prop(module)
+            {
+                modules.insert(id.to_string(), props);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(modules)
+}
+
+/// Refuse to install `module_id` when a requirement is not met or it
+/// conflicts with an active module, in either direction
+pub fn check_install(module_id: &str, props: &HashMap<String, String>) -> Result<()> {
+    let active = active_modules()?;
+    let mut problems = Vec::new();
+
+    for requirement in parse_requires(props)? {
+        match active.get(&requirement.id) {
+            None => problems.push(format!("requires {}, which is not active", requirement.id)),
+            Some(dep) => {
+                if let Some(min) = requirement.min_version_code
+                    && version_code(dep) < min
+                {
+                    problems.push(format!(
+                        "requires {} >= {min}, but versionCode {} is installed",
+                        requirement.id,
+                        version_code(dep)
+                    ));
+                }
+            }
+        }
+    }
+
+    for conflict in parse_conflicts(props) {
+        if conflict != module_id && active.contains_key(&conflict) {
+            problems.push(format!("conflicts with active module {conflict}"));
+        }
+    }
+    for (id, other) in &active {
+        if id != module_id && parse_conflicts(other).iter().any(|c| c == module_id) {
+            problems.push(format!("active module {id} declares a conflict with it"));
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!("Cannot install {module_id}: {}", problems.join("; "));
+    }
+    Ok(())
+}
+
+/// Active modules that declare a requirement on `module_id`
+pub fn dependents_of(module_id: &str) -> Result<Vec<String>> {
+    let mut dependents: Vec<String> = active_modules()?
+        .into_iter()
+        .filter(|(id, props)| {
+            id != module_id
+                && parse_requires(props)
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|r| r.id == module_id)
+        })
+        .map(|(id, _)| id)
+        .collect();
+    dependents.sort();
+    Ok(dependents)
+}
+
+/// Refuse to uninstall a module other active modules still depend on
+pub fn check_uninstall(module_id: &str, force: bool) -> Result<()> {
+    let dependents = dependents_of(module_id)?;
+    if dependents.is_empty() {
+        return Ok(());
+    }
+    let dependents = dependents.join(", ");
+    if force {
+        warn!("uninstalling {module_id}, which is required by: {dependents}");
+        return Ok(());
+    }
+    bail!(
+        "{module_id} is required by active modules: {dependents} (use --force to uninstall anyway)"
+    );
+}
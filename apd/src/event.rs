@@ -1,4 +1,3 @@
-use crate::sepolicy::get_policy_main;
 use anyhow::{Context, Result};
 use libc::SIGPWR;
 use log::{info, warn};
@@ -10,7 +9,7 @@ use signal_hook::{consts::signal::*, iterator::Signals};
 use std::{
     env, fs,
     os::unix::{fs::PermissionsExt, process::CommandExt},
-    path::{Path, PathBuf},
+    path::PathBuf,
     process::Command,
     sync::{Arc, Mutex},
     thread,
@@ -18,40 +17,46 @@ use std::{
 };
 
 use crate::{
-    assets, defs, lua, metamodule, module, restorecon, supercall,
+    assets, boot_guard,
+    boot_report::StageReport,
+    defs, integrity, lua, metamodule, module, platform, restorecon, supercall,
     supercall::{init_load_su_path, refresh_ap_package_list},
+    supervisor,
     utils::{self, switch_cgroups},
 };
 
 pub fn report_kernel(superkey: Option<String>, event: &str, state: &str) {
-    let args = [
-        superkey.unwrap_or("su".to_string()),
-        "event".to_string(),
-        event.to_string(),
-        state.to_string(),
-    ];
-    let args_ref: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
     // Best-effort notification to the kernel; a failed report must not abort
     // boot stages such as post-fs-data.
-    if let Err(e) = utils::run_command("truncate", &args_ref, None)
-        .and_then(|mut child| child.wait().map_err(anyhow::Error::from))
-    {
+    if let Err(e) = platform::get().report_event(superkey.as_deref(), event, state) {
         warn!("report kernel event {event}/{state} failed: {e}");
     }
 }
 
 pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
+    run_post_data_fs(superkey, false)
+}
+
+/// post-fs-data of a real boot, or re-run by [`soft_reboot`]. A soft reboot is
+/// no new boot attempt: it never reaches boot-completed to reset the counter.
+fn run_post_data_fs(superkey: Option<String>, soft_reboot: bool) -> Result<()> {
+    let mut report = StageReport::new("post-fs-data");
+    let result = post_data_fs(superkey, soft_reboot, &mut report);
+    report.finish(&result);
+    result
+}
+
+fn post_data_fs(
+    superkey: Option<String>,
+    soft_reboot: bool,
+    report: &mut StageReport,
+) -> Result<()> {
     utils::umask(0);
     report_kernel(superkey.clone(), "post-fs-data", "before");
-    use std::process::Stdio;
     #[cfg(unix)]
     init_load_su_path(&superkey);
 
-    let mut sepol = get_policy_main(&["magiskpolicy".to_string(), "--live".to_string()])?;
-    sepol.magisk_rules();
-    sepol
-        .to_file("/sys/fs/selinux/load")
-        .context("Cannot apply policy")?;
+    platform::get().apply_magisk_policy()?;
 
     info!("Re-privilege apd profile after injecting sepolicy");
     supercall::privilege_apd_profile(&superkey);
@@ -68,18 +73,18 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
     }
 
     // Create log environment
-    if !Path::new(defs::APATCH_LOG_FOLDER).exists() {
-        fs::create_dir(defs::APATCH_LOG_FOLDER).expect("Failed to create log folder");
+    let log_dir = defs::log_dir();
+    if !log_dir.exists() {
+        fs::create_dir(&log_dir).expect("Failed to create log folder");
         let permissions = fs::Permissions::from_mode(0o700);
-        fs::set_permissions(defs::APATCH_LOG_FOLDER, permissions)
-            .expect("Failed to set permissions");
+        fs::set_permissions(&log_dir, permissions).expect("Failed to set permissions");
     }
+    // boot reports and module logs keep their own history, leave them alone
     let command_string = format!(
-        "rm -rf {}*.old.log; for file in {}*; do mv \"$file\" \"$file.old.log\"; done",
-        defs::APATCH_LOG_FOLDER,
-        defs::APATCH_LOG_FOLDER
+        "rm -rf {0}/*.old.log; for file in {0}/*; do case \"${{file##*/}}\" in boot-*.json|modules) continue;; esac; mv \"$file\" \"$file.old.log\"; done",
+        log_dir.display()
     );
-    let mut args = vec!["-c", &command_string];
+    let args = vec!["-c", &command_string];
     // for all file to .old
     let result = utils::run_command("sh", &args, None)?.wait()?;
     if result.success() {
@@ -87,43 +92,7 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
     } else {
         info!("Failed to delete .old files.");
     }
-    let logcat_path = format!("{}logcat.log", defs::APATCH_LOG_FOLDER);
-    let dmesg_path = format!("{}dmesg.log", defs::APATCH_LOG_FOLDER);
-    let bootlog = fs::File::create(dmesg_path)?;
-    args = vec![
-        "-s",
-        "9",
-        "45s",
-        "logcat",
-        "-b",
-        "main,system,crash",
-        "DrmLibFs:S",
-        "-f",
-        &logcat_path,
-        "logcatcher-bootlog:S",
-    ];
-    let _ = unsafe {
-        Command::new("timeout")
-            .process_group(0)
-            .pre_exec(|| {
-                switch_cgroups();
-                Ok(())
-            })
-            .args(args)
-            .spawn()
-    };
-    args = vec!["-s", "9", "120s", "dmesg", "-w"];
-    let _result = unsafe {
-        Command::new("timeout")
-            .process_group(0)
-            .pre_exec(|| {
-                switch_cgroups();
-                Ok(())
-            })
-            .args(args)
-            .stdout(Stdio::from(bootlog))
-            .spawn()
-    };
+    platform::get().start_log_capture(&log_dir)?;
 
     let key = "KERNELPATCH_VERSION";
     match env::var(key) {
@@ -137,6 +106,12 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
         Err(_) => println!("{} not found", key),
     }
 
+    if soft_reboot {
+        info!("soft reboot, not counted as a boot attempt");
+    } else if let Err(e) = report.step("boot_guard", None, boot_guard::begin_boot) {
+        warn!("bootloop protection failed: {e:#}");
+    }
+
     let safe_mode = utils::is_safe_mode(superkey.clone());
 
     if safe_mode {
@@ -151,18 +126,22 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
         }
     } else {
         // Then exec common post-fs-data scripts
-        if let Err(e) = module::exec_common_scripts("post-fs-data.d", true) {
+        if let Err(e) = module::exec_common_scripts("post-fs-data.d", true, report) {
             warn!("exec common post-fs-data scripts failed: {}", e);
         }
     }
-    let module_update_dir = defs::MODULE_UPDATE_DIR; //save module place
-    let module_dir = defs::MODULE_DIR; // run modules place
-    let module_update_flag = Path::new(defs::WORKING_DIR).join(defs::UPDATE_FILE_NAME); // if update ,there will be renewed modules file
+    let module_update_dir = defs::module_update_dir(); //save module place
+    let module_dir = defs::module_dir(); // run modules place
+    let module_update_flag = defs::working_dir().join(defs::UPDATE_FILE_NAME); // if update ,there will be renewed modules file
     assets::ensure_binaries().with_context(|| "binary missing")?;
 
-    if Path::new(defs::MODULE_UPDATE_DIR).exists() {
-        module::handle_updated_modules()?;
-        fs::remove_dir_all(module_update_dir)?;
+    if module_update_dir.exists() {
+        report.step(
+            "handle_updated_modules",
+            None,
+            module::handle_updated_modules,
+        )?;
+        fs::remove_dir_all(&module_update_dir)?;
     }
 
     if safe_mode {
@@ -173,33 +152,46 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
         return Ok(());
     }
 
-    if let Err(e) = module::prune_modules() {
+    if let Err(e) = report.step("prune_modules", None, module::prune_modules) {
         warn!("prune modules failed: {}", e);
     }
 
-    if let Err(e) = restorecon::restorecon() {
+    if let Err(e) = report.step("verify_integrity", None, integrity::check_modules) {
+        warn!("module integrity check failed: {e:#}");
+    }
+
+    if let Err(e) = report.step("restorecon", None, restorecon::restorecon) {
         warn!("restorecon failed: {}", e);
     }
 
     // load sepolicy.rule
-    if module::load_sepolicy_rule().is_err() {
+    if report
+        .step("load_sepolicy_rule", None, module::load_sepolicy_rule)
+        .is_err()
+    {
         warn!("load sepolicy.rule failed");
     }
 
-    if let Err(e) = metamodule::exec_mount_script(module_dir) {
+    if let Err(e) = report.step("metamount.sh", None, || {
+        metamodule::exec_mount_script(&module_dir)
+    }) {
         warn!("execute metamodule mount failed: {e}");
     }
 
     // exec modules post-fs-data scripts
-    // TODO: Add timeout
-    if let Err(e) = module::exec_stage_script("post-fs-data", true) {
+    if let Err(e) = module::exec_stage_script("post-fs-data", true, report) {
         warn!("exec post-fs-data scripts failed: {}", e);
     }
-    if let Err(e) = lua::exec_stage_lua("post-fs-data", true, superkey.as_deref().unwrap_or("")) {
+    if let Err(e) = lua::exec_stage_lua(
+        "post-fs-data",
+        true,
+        superkey.as_deref().unwrap_or(""),
+        report,
+    ) {
         warn!("Failed to exec post-fs-data lua: {}", e);
     }
     // load system.prop
-    if let Err(e) = module::load_system_prop() {
+    if let Err(e) = report.step("load_system_prop", None, module::load_system_prop) {
         warn!("load system.prop failed: {}", e);
     }
 
@@ -214,6 +206,16 @@ pub fn on_post_data_fs(superkey: Option<String>) -> Result<()> {
 }
 
 fn run_stage(stage: &str, superkey: Option<String>, block: bool) {
+    if let Err(e) = boot_guard::mark_stage(stage) {
+        warn!("Failed to record boot stage {stage}: {e:#}");
+    }
+
+    let mut report = StageReport::new(stage);
+    run_stage_scripts(stage, superkey, block, &mut report);
+    report.finish(&Ok(()));
+}
+
+fn run_stage_scripts(stage: &str, superkey: Option<String>, block: bool, report: &mut StageReport) {
     utils::umask(0);
 
     if utils::has_magisk() {
@@ -230,17 +232,17 @@ fn run_stage(stage: &str, superkey: Option<String>, block: bool) {
     }
 
     // execute metamodule stage script first (priority)
-    if let Err(e) = metamodule::exec_stage_script(stage, block) {
+    if let Err(e) = metamodule::exec_stage_script(stage, block, report) {
         warn!("Failed to exec metamodule {stage} script: {e}");
     }
 
-    if let Err(e) = module::exec_common_scripts(&format!("{stage}.d"), block) {
+    if let Err(e) = module::exec_common_scripts(&format!("{stage}.d"), block, report) {
         warn!("Failed to exec common {stage} scripts: {e}");
     }
-    if let Err(e) = module::exec_stage_script(stage, block) {
+    if let Err(e) = module::exec_stage_script(stage, block, report) {
         warn!("Failed to exec {stage} scripts: {e}");
     }
-    if let Err(e) = lua::exec_stage_lua(stage, block, superkey.as_deref().unwrap_or("")) {
+    if let Err(e) = lua::exec_stage_lua(stage, block, superkey.as_deref().unwrap_or(""), report) {
         warn!("Failed to exec {stage} lua: {e}");
     }
 }
@@ -248,6 +250,9 @@ fn run_stage(stage: &str, superkey: Option<String>, block: bool) {
 pub fn on_services(superkey: Option<String>) -> Result<()> {
     info!("on_services triggered!");
     run_stage("service", superkey, false);
+    if let Err(e) = supervisor::spawn() {
+        warn!("Failed to start service supervisor: {e:#}");
+    }
 
     Ok(())
 }
@@ -255,7 +260,7 @@ pub fn on_services(superkey: Option<String>) -> Result<()> {
 fn run_uid_monitor() {
     info!("Trigger run_uid_monitor!");
 
-    let mut command = &mut Command::new("/data/adb/apd");
+    let mut command = &mut Command::new(defs::daemon_path());
     {
         command = command.process_group(0);
         command = unsafe {
@@ -288,8 +293,8 @@ pub fn start_uid_listener() -> Result<()> {
     println!("[start_uid_listener] Registering...");
 
     // create inotify instance
-    const SYS_PACKAGES_LIST_TMP: &str = "/data/system/packages.list.tmp";
-    let sys_packages_list_tmp = PathBuf::from(&SYS_PACKAGES_LIST_TMP);
+    let sys_packages_list_tmp =
+        defs::resolve(defs::SYSTEM_PACKAGES_LIST).with_extension("list.tmp");
     let dir: PathBuf = sys_packages_list_tmp.parent().unwrap().into();
 
     let (tx, rx) = std::sync::mpsc::channel();
@@ -349,39 +354,37 @@ pub fn start_uid_listener() -> Result<()> {
 /// and re-apply the service stage. Used by jailbreak mode so that a runtime-loaded
 /// `kernelpatch.ko` stays active (a full reboot would drop it).
 pub fn soft_reboot(superkey: Option<String>) -> Result<()> {
-    use std::process::Command;
+    let platform = platform::get();
 
     // Detach from the caller (app root shell) first: `stop` tears down the
     // framework including the app/zygote tree this process was spawned from, so
     // without daemonizing the `start` below would never be reached.
-    utils::daemonize()?;
+    platform.daemonize()?;
 
     info!("emulating soft reboot!");
-    utils::switch_mnt_ns(1)?;
+    platform.switch_mnt_ns(1)?;
     std::env::set_current_dir("/").with_context(|| "failed to chdir to /")?;
 
-    if let Err(e) = crate::resetprop::set_prop("sys.boot_completed", "0") {
+    if let Err(e) = platform.setprop("sys.boot_completed", "0") {
         warn!("reset boot completed failed: {e}");
     }
 
     info!("stop");
-    let status = Command::new("stop").status().context("stop failed")?;
-    if !status.success() {
-        warn!("stop exited with status: {status}");
+    if let Err(e) = platform.stop_framework() {
+        warn!("{e:#}");
     }
 
     info!("post-fs-data");
     // Never abort the soft reboot here: the framework must always be restarted.
     // The daemonized stdin (dev null) keeps the supercall/truncate redirects from
     // blocking, so re-applying the boot stages is safe.
-    if let Err(e) = on_post_data_fs(superkey.clone()) {
+    if let Err(e) = run_post_data_fs(superkey.clone(), true) {
         warn!("post-fs-data failed during soft reboot: {e:#}");
     }
 
     info!("start");
-    let status = Command::new("start").status().context("start failed")?;
-    if !status.success() {
-        warn!("start exited with status: {status}");
+    if let Err(e) = platform.start_framework() {
+        warn!("{e:#}");
     }
 
     info!("services");
@@ -389,3 +392,114 @@ pub fn soft_reboot(superkey: Option<String>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::{Effect, FakePlatform, Platform, with_fake};
+
+    fn add_module(id: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = defs::module_dir().join(id);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("module.prop"),
+            format!("id={id}\nname={id}\nversion=1\nversionCode=1\n"),
+        )
+        .unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    fn event(event: &str, state: &str) -> Effect {
+        Effect::ReportEvent {
+            event: event.to_string(),
+            state: state.to_string(),
+        }
+    }
+
+    #[test]
+    fn post_fs_data_applies_modules() {
+        with_fake(FakePlatform::default(), |fake| {
+            let module = add_module(
+                "mod_a",
+                &[
+                    ("system.prop", "ro.test.a=1\n"),
+                    ("sepolicy.rule", "allow a b c d\n"),
+                ],
+            );
+            on_post_data_fs(None).unwrap();
+
+            let effects = fake.effects();
+            assert_eq!(effects.first(), Some(&event("post-fs-data", "before")));
+            assert_eq!(effects.last(), Some(&event("post-fs-data", "after")));
+            assert!(effects.contains(&Effect::MagiskPolicy));
+            assert!(effects.contains(&Effect::StartLogCapture(defs::log_dir())));
+            assert!(effects.contains(&Effect::PolicyRuleFile(module.join("sepolicy.rule"))));
+            assert!(effects.contains(&Effect::LoadPropFile(module.join("system.prop"))));
+            assert_eq!(fake.getprop("ro.test.a").as_deref(), Some("1"));
+        });
+    }
+
+    #[test]
+    fn post_fs_data_in_safe_mode_disables_modules() {
+        let safe_modes = [
+            FakePlatform::default().with_safemode(true),
+            FakePlatform::default().with_prop("persist.sys.safemode", "1"),
+        ];
+        for fake in safe_modes {
+            with_fake(fake, |fake| {
+                let module = add_module("mod_a", &[("system.prop", "ro.test.a=1\n")]);
+                on_post_data_fs(Some("key".to_string())).unwrap();
+
+                assert!(module.join(defs::DISABLE_FILE_NAME).exists());
+                assert!(
+                    !fake
+                        .effects()
+                        .contains(&Effect::LoadPropFile(module.join("system.prop")))
+                );
+            });
+        }
+    }
+
+    #[test]
+    fn soft_reboot_restarts_framework_around_post_fs_data() {
+        with_fake(FakePlatform::default(), |fake| {
+            soft_reboot(None).unwrap();
+
+            let effects = fake.effects();
+            assert_eq!(
+                effects[..4],
+                [
+                    Effect::Daemonize,
+                    Effect::SwitchMntNs(1),
+                    Effect::SetProp {
+                        name: "sys.boot_completed".to_string(),
+                        value: "0".to_string(),
+                    },
+                    Effect::StopFramework,
+                ]
+            );
+            let position = |effect: &Effect| effects.iter().position(|e| e == effect).unwrap();
+            assert!(position(&event("post-fs-data", "after")) < position(&Effect::StartFramework));
+        });
+    }
+
+    #[test]
+    fn soft_reboots_are_no_boot_attempts() {
+        with_fake(FakePlatform::default(), |_| {
+            let module = add_module("mod_a", &[]);
+            boot_guard::track_updated_modules(&["mod_a".to_string()]).unwrap();
+            for _ in 0..3 {
+                soft_reboot(None).unwrap();
+            }
+            assert!(!module.join(defs::DISABLE_FILE_NAME).exists());
+
+            let state: serde_json::Value =
+                serde_json::from_slice(&fs::read(defs::resolve(defs::BOOT_STATE_FILE)).unwrap())
+                    .unwrap();
+            assert_eq!(state["attempts"], 0);
+        });
+    }
+}
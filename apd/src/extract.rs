@@ -0,0 +1,708 @@
+//! Hardened extraction of untrusted module zips
+//!
+//! Every entry is checked before anything touches the disk: names must stay
+//! inside the target directory, symlinks follow a [`SymlinkPolicy`], and the
+//! declared sizes must fit the [`Limits`]. All rejected entries are reported
+//! together. While extracting, the decoded bytes are counted again, so a
+//! header that lies about its size cannot get past the limits either. The
+//! checks sit on top of the decoded stream and therefore apply the same way to
+//! every compression method the zip crate is built with (stored, deflate,
+//! deflate64, lzma, xz).
+//!
+//! Symlinks are checked against each other as well: no entry may sit below a
+//! symlink of the archive and no symlink may resolve through one. Extraction
+//! opens every directory with `O_NOFOLLOW` relative to its parent, so a
+//! symlink already on disk is never followed either.
+
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fmt, fs,
+    io::{self, Read, Seek, Write},
+    os::{fd::OwnedFd, unix::fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use log::warn;
+use rustix::{
+    fd::AsFd,
+    fs::{AtFlags, CWD, Mode, OFlags, mkdirat, openat, symlinkat, unlinkat},
+    io::Errno,
+};
+
+use crate::defs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Refuse archives that contain any symlink
+    Reject,
+    /// Allow relative symlinks that resolve inside the target directory
+    Contained,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Sum of the uncompressed sizes of all entries
+    pub max_total_size: u64,
+    pub max_entries: usize,
+    /// Highest uncompressed / compressed ratio of an entry
+    pub max_ratio: u64,
+    /// Entries smaller than this are exempt from the ratio check, tiny files
+    /// full of padding compress very well without being a bomb
+    pub ratio_threshold: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_total_size: 2 << 30,
+            max_entries: 65536,
+            max_ratio: 500,
+            ratio_threshold: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub symlinks: SymlinkPolicy,
+    pub limits: Limits,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            symlinks: SymlinkPolicy::Contained,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Defaults, with the symlink policy taken from `WORKING_DIR/zip_symlink_policy`
+    /// (`reject` or `contained`)
+    pub fn load() -> Self {
+        let symlinks = match fs::read_to_string(defs::resolve(defs::ZIP_SYMLINK_POLICY_FILE)) {
+            Ok(policy) if policy.trim() == "reject" => SymlinkPolicy::Reject,
+            Ok(policy) if policy.trim() != "contained" => {
+                warn!(
+                    "invalid zip symlink policy: {}, rejecting symlinks",
+                    policy.trim()
+                );
+                SymlinkPolicy::Reject
+            }
+            _ => SymlinkPolicy::Contained,
+        };
+        Self {
+            symlinks,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub entry: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.entry, self.reason)
+    }
+}
+
+/// Relative path of an entry inside the target, or why it is not allowed
+fn entry_path(name: &str) -> Result<PathBuf, String> {
+    if name.contains('\0') {
+        return Err("name contains a NUL byte".to_string());
+    }
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::RootDir | Component::Prefix(_) => {
+                return Err("absolute path".to_string());
+            }
+            Component::ParentDir => {
+                if !path.pop() {
+                    return Err("escapes the target directory".to_string());
+                }
+            }
+            Component::CurDir => {}
+            Component::Normal(part) => path.push(part),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Err("empty path".to_string());
+    }
+    Ok(path)
+}
+
+/// Whether a symlink at `link` (relative to the target) pointing to `dest`
+/// stays inside the target. `dest` is resolved lexically, so it must not pass
+/// through any of the archive's `symlinks` on the way.
+fn symlink_contained(link: &Path, dest: &str, symlinks: &HashSet<PathBuf>) -> bool {
+    if dest.is_empty() || dest.contains('\0') || Path::new(dest).is_absolute() {
+        return false;
+    }
+    let mut path = link.parent().map(Path::to_path_buf).unwrap_or_default();
+    for component in Path::new(dest).components() {
+        if symlinks.contains(&path) {
+            return false;
+        }
+        match component {
+            Component::ParentDir => {
+                if !path.pop() {
+                    return false;
+                }
+            }
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// The first of the archive's `symlinks` that `path` would be written through
+fn symlinked_ancestor<'a>(path: &Path, symlinks: &'a HashSet<PathBuf>) -> Option<&'a PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .find_map(|ancestor| symlinks.get(ancestor))
+}
+
+fn read_symlink_target<R: Read>(entry: &mut R) -> Result<String> {
+    let mut dest = String::new();
+    // a path, not a payload
+    entry.take(4096).read_to_string(&mut dest)?;
+    Ok(dest)
+}
+
+fn exceeds_ratio(size: u64, compressed: u64, limits: &Limits) -> bool {
+    size >= limits.ratio_threshold && size / compressed.max(1) > limits.max_ratio
+}
+
+/// Check every entry without extracting anything
+pub fn check<R: Read + Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    options: &Options,
+) -> Result<Vec<Rejection>> {
+    let limits = &options.limits;
+    let mut rejections = Vec::new();
+    let mut reject = |entry: &str, reason: String| {
+        rejections.push(Rejection {
+            entry: entry.to_string(),
+            reason,
+        });
+    };
+
+    if archive.len() > limits.max_entries {
+        reject(
+            "*",
+            format!(
+                "{} entries, more than the limit of {}",
+                archive.len(),
+                limits.max_entries
+            ),
+        );
+    }
+
+    let mut symlinks = HashSet::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.is_symlink()
+            && let Ok(path) = entry_path(entry.name())
+        {
+            symlinks.insert(path);
+        }
+    }
+
+    let mut total: u64 = 0;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().to_string();
+        let path = match entry_path(&name) {
+            Ok(path) => path,
+            Err(reason) => {
+                reject(&name, reason);
+                continue;
+            }
+        };
+        if let Some(link) = symlinked_ancestor(&path, &symlinks) {
+            reject(&name, format!("inside symlink {}", link.display()));
+            continue;
+        }
+
+        if entry.is_symlink() {
+            match options.symlinks {
+                SymlinkPolicy::Reject => reject(&name, "symlinks are not allowed".to_string()),
+                SymlinkPolicy::Contained => {
+                    let dest = read_symlink_target(&mut entry)?;
+                    if !symlink_contained(&path, &dest, &symlinks) {
+                        reject(
+                            &name,
+                            format!("symlink to {dest} leaves the target directory"),
+                        );
+                    }
+                }
+            }
+            continue;
+        }
+
+        total = total.saturating_add(entry.size());
+        if exceeds_ratio(entry.size(), entry.compressed_size(), limits) {
+            reject(
+                &name,
+                format!(
+                    "compression ratio {} exceeds {}",
+                    entry.size() / entry.compressed_size().max(1),
+                    limits.max_ratio
+                ),
+            );
+        }
+    }
+
+    if total > limits.max_total_size {
+        reject(
+            "*",
+            format!(
+                "{total} bytes uncompressed, more than the limit of {}",
+                limits.max_total_size
+            ),
+        );
+    }
+    Ok(rejections)
+}
+
+/// Copy at most `limit` bytes, failing if the source has more
+fn copy_limited<R: Read, W: Write>(src: &mut R, dst: &mut W, limit: u64) -> io::Result<u64> {
+    let copied = io::copy(&mut src.take(limit), dst)?;
+    if copied == limit && src.read(&mut [0])? != 0 {
+        return Err(io::Error::other("entry is larger than declared"));
+    }
+    Ok(copied)
+}
+
+/// Open directory `name` in `parent` without following a symlink, creating it
+/// if needed
+fn open_dir(parent: impl AsFd, name: &OsStr) -> io::Result<OwnedFd> {
+    match mkdirat(&parent, name, Mode::from_raw_mode(0o755)) {
+        Ok(()) | Err(Errno::EXIST) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+    Ok(openat(parent, name, flags, Mode::empty())?)
+}
+
+/// Directory of `relative` below `root`, opened one component at a time
+fn open_parent(root: &OwnedFd, relative: &Path) -> io::Result<OwnedFd> {
+    let mut dir = root.try_clone()?;
+    for component in relative.parent().into_iter().flat_map(Path::components) {
+        dir = open_dir(&dir, component.as_os_str())?;
+    }
+    Ok(dir)
+}
+
+/// Extract `archive` into `target` after [`check`] accepted every entry
+pub fn extract<R: Read + Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    target: &Path,
+    options: &Options,
+) -> Result<()> {
+    let rejections = check(archive, options)?;
+    if !rejections.is_empty() {
+        let list: Vec<String> = rejections.iter().map(ToString::to_string).collect();
+        bail!(
+            "unsafe module zip, rejected entries:\n  {}",
+            list.join("\n  ")
+        );
+    }
+
+    fs::create_dir_all(target)?;
+    let root = openat(
+        CWD,
+        target,
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+    .with_context(|| format!("Failed to open {}", target.display()))?;
+    let limits = &options.limits;
+    let mut remaining = limits.max_total_size;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().to_string();
+        // already validated by check()
+        let Ok(relative) = entry_path(&name) else {
+            bail!("{name}: invalid path");
+        };
+        let Some(file_name) = relative.file_name() else {
+            bail!("{name}: invalid path");
+        };
+        // never write through a symlink, whether an earlier entry or the
+        // target itself put it there
+        let parent = open_parent(&root, &relative)
+            .with_context(|| format!("{name}: failed to open its directory"))?;
+
+        if entry.is_dir() {
+            open_dir(&parent, file_name)
+                .with_context(|| format!("{name}: failed to create directory"))?;
+            continue;
+        }
+
+        if entry.is_symlink() {
+            let dest = read_symlink_target(&mut entry)?;
+            let _ = unlinkat(&parent, file_name, AtFlags::empty());
+            symlinkat(dest.as_str(), &parent, file_name)
+                .with_context(|| format!("{name}: failed to create symlink"))?;
+            continue;
+        }
+
+        let compressed = entry.compressed_size();
+        let declared = entry.size();
+        let flags =
+            OFlags::WRONLY | OFlags::CREATE | OFlags::TRUNC | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+        let mut file = fs::File::from(
+            openat(&parent, file_name, flags, Mode::from_raw_mode(0o644))
+                .with_context(|| format!("{name}: failed to create {}", relative.display()))?,
+        );
+        let written = copy_limited(&mut entry, &mut file, declared.min(remaining))
+            .with_context(|| format!("{name}: failed to extract"))?;
+        remaining -= written;
+        if exceeds_ratio(written, compressed, limits) {
+            bail!("{name}: compression ratio exceeds {}", limits.max_ratio);
+        }
+
+        if let Some(mode) = entry.unix_mode() {
+            // no setuid/setgid/sticky from an untrusted archive
+            file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "apd-extract-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    enum Entry<'a> {
+        File(&'a str, &'a [u8]),
+        Symlink(&'a str, &'a str),
+    }
+
+    fn build(entries: &[Entry], method: CompressionMethod) -> zip::ZipArchive<Cursor<Vec<u8>>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default()
+            .compression_method(method)
+            .unix_permissions(0o644);
+        for entry in entries {
+            match entry {
+                Entry::File(name, content) => {
+                    writer.start_file(*name, options).unwrap();
+                    writer.write_all(content).unwrap();
+                }
+                Entry::Symlink(name, dest) => writer.add_symlink(*name, *dest, options).unwrap(),
+            }
+        }
+        let data = writer.finish().unwrap().into_inner();
+        zip::ZipArchive::new(Cursor::new(data)).unwrap()
+    }
+
+    fn rejected(archive: &mut zip::ZipArchive<Cursor<Vec<u8>>>, options: &Options) -> Vec<String> {
+        check(archive, options)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.entry)
+            .collect()
+    }
+
+    #[test]
+    fn extracts_regular_module() {
+        for method in [
+            CompressionMethod::Stored,
+            CompressionMethod::Deflated,
+            CompressionMethod::Xz,
+        ] {
+            let dir = TempDir::new();
+            let mut archive = build(
+                &[
+                    Entry::File("module.prop", b"id=test\n"),
+                    Entry::File("system/bin/tool", b"#!/bin/sh\n"),
+                    Entry::Symlink("system/bin/alias", "tool"),
+                ],
+                method,
+            );
+            extract(&mut archive, &dir.0, &Options::default()).unwrap();
+            assert_eq!(
+                fs::read(dir.0.join("system/bin/tool")).unwrap(),
+                b"#!/bin/sh\n"
+            );
+            assert_eq!(
+                fs::read_link(dir.0.join("system/bin/alias")).unwrap(),
+                Path::new("tool")
+            );
+        }
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in data {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    /// Write an archive of already compressed `(name, mode, content, data)`
+    /// entries, for methods the zip crate reads but cannot write
+    fn build_raw(
+        method: u16,
+        flags: u16,
+        entries: &[(&str, u32, &[u8], &[u8])],
+    ) -> zip::ZipArchive<Cursor<Vec<u8>>> {
+        let mut data = Vec::new();
+        let mut central = Vec::new();
+        for (name, mode, content, compressed) in entries {
+            let offset = data.len() as u32;
+            let mut header = Vec::new();
+            header.extend_from_slice(&63u16.to_le_bytes()); // version needed
+            header.extend_from_slice(&flags.to_le_bytes());
+            header.extend_from_slice(&method.to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes()); // time
+            header.extend_from_slice(&0x21u16.to_le_bytes()); // date, 1980-01-01
+            header.extend_from_slice(&crc32(content).to_le_bytes());
+            header.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes()); // extra field
+
+            data.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            data.extend_from_slice(&header);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(compressed);
+
+            central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            central.extend_from_slice(&(3u16 << 8 | 63).to_le_bytes()); // made by unix
+            central.extend_from_slice(&header);
+            central.extend_from_slice(&[0; 6]); // comment, disk, internal attributes
+            central.extend_from_slice(&(mode << 16).to_le_bytes());
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let central_offset = data.len() as u32;
+        data.extend_from_slice(&central);
+        data.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        data.extend_from_slice(&[0; 4]); // disk numbers
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        data.extend_from_slice(&central_offset.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes()); // comment
+        zip::ZipArchive::new(Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn extracts_deflate64_and_lzma() {
+        // made with python: raw `zlib` deflate, which Deflate64 decodes the same
+        // way as long as there are no 258 byte matches, and `zipfile`'s LZMA
+        // (version, properties, raw stream with end marker)
+        let deflate64: [&[u8]; 3] = [
+            &[0xcb, 0x4c, 0xb1, 0x2d, 0x49, 0x2d, 0x2e, 0xe1, 0x02, 0x00],
+            &[
+                0x53, 0x56, 0xd4, 0x4f, 0xca, 0xcc, 0xd3, 0x2f, 0xce, 0xe0, 0x02, 0x00,
+            ],
+            &[0x2b, 0xc9, 0xcf, 0xcf, 0x01, 0x00],
+        ];
+        let lzma: [&[u8]; 3] = [
+            &[
+                0x09, 0x04, 0x05, 0x00, 0x5d, 0x00, 0x00, 0x80, 0x00, 0x00, 0x34, 0x99, 0x03, 0xa5,
+                0x59, 0xa1, 0x91, 0x3f, 0x09, 0x31, 0x9e, 0x81, 0xff, 0xff, 0x8c, 0x76, 0x00, 0x00,
+            ],
+            &[
+                0x09, 0x04, 0x05, 0x00, 0x5d, 0x00, 0x00, 0x80, 0x00, 0x00, 0x11, 0x88, 0x42, 0x46,
+                0x3d, 0xf4, 0x16, 0x34, 0x73, 0x0a, 0x0d, 0xd3, 0x9b, 0x5c, 0xff, 0xff, 0xde, 0x04,
+                0x00, 0x00,
+            ],
+            &[
+                0x09, 0x04, 0x05, 0x00, 0x5d, 0x00, 0x00, 0x80, 0x00, 0x00, 0x3a, 0x1b, 0xec, 0xd9,
+                0x07, 0xff, 0xff, 0xff, 0xff, 0x80, 0x00, 0x00, 0x00,
+            ],
+        ];
+        // method 9 is Deflate64, 14 LZMA with flag bit 1 for the end marker
+        for (method, flags, compressed, expected) in [
+            (9, 0, deflate64, CompressionMethod::Deflate64),
+            (14, 2, lzma, CompressionMethod::Lzma),
+        ] {
+            let mut archive = build_raw(
+                method,
+                flags,
+                &[
+                    ("module.prop", 0o100_644, b"id=test\n", compressed[0]),
+                    ("system/bin/tool", 0o100_755, b"#!/bin/sh\n", compressed[1]),
+                    ("system/bin/alias", 0o120_777, b"tool", compressed[2]),
+                ],
+            );
+            assert_eq!(archive.by_index(0).unwrap().compression(), expected);
+
+            let dir = TempDir::new();
+            extract(&mut archive, &dir.0, &Options::default()).unwrap();
+            assert_eq!(fs::read(dir.0.join("module.prop")).unwrap(), b"id=test\n");
+            assert_eq!(
+                fs::read(dir.0.join("system/bin/tool")).unwrap(),
+                b"#!/bin/sh\n"
+            );
+            assert_eq!(
+                fs::read_link(dir.0.join("system/bin/alias")).unwrap(),
+                Path::new("tool")
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_zip_slip_and_absolute_paths() {
+        let mut archive = build(
+            &[
+                Entry::File("module.prop", b"id=test\n"),
+                Entry::File("../evil.sh", b"x"),
+                Entry::File("system/../../evil.sh", b"x"),
+                Entry::File("/data/adb/evil.sh", b"x"),
+            ],
+            CompressionMethod::Stored,
+        );
+        assert_eq!(
+            rejected(&mut archive, &Options::default()),
+            ["../evil.sh", "system/../../evil.sh", "/data/adb/evil.sh"]
+        );
+
+        let dir = TempDir::new();
+        let err = extract(&mut archive, &dir.0, &Options::default()).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("../evil.sh: escapes the target directory"));
+        assert!(message.contains("/data/adb/evil.sh: absolute path"));
+        assert!(!dir.0.join("module.prop").exists());
+    }
+
+    #[test]
+    fn symlink_policy() {
+        let mut archive = build(
+            &[
+                Entry::Symlink("inside", "system/bin/tool"),
+                Entry::Symlink("system/up", "../module.prop"),
+                Entry::Symlink("escape", "../../data"),
+                Entry::Symlink("absolute", "/data/adb"),
+            ],
+            CompressionMethod::Stored,
+        );
+        assert_eq!(
+            rejected(&mut archive, &Options::default()),
+            ["escape", "absolute"]
+        );
+
+        let options = Options {
+            symlinks: SymlinkPolicy::Reject,
+            ..Options::default()
+        };
+        assert_eq!(rejected(&mut archive, &options).len(), 4);
+    }
+
+    #[test]
+    fn rejects_chained_symlink_escape() {
+        // every link stays inside on its own, but c resolves a/b first
+        let mut archive = build(
+            &[
+                Entry::Symlink("a/b", ".."),
+                Entry::Symlink("c", "a/b/.."),
+                Entry::Symlink("a/b/d", "tool"),
+            ],
+            CompressionMethod::Stored,
+        );
+        assert_eq!(rejected(&mut archive, &Options::default()), ["c", "a/b/d"]);
+    }
+
+    #[test]
+    fn never_writes_through_symlinked_dir() {
+        let mut archive = build(
+            &[
+                Entry::Symlink("lib", "system"),
+                Entry::File("lib/evil.sh", b"x"),
+            ],
+            CompressionMethod::Stored,
+        );
+        assert_eq!(rejected(&mut archive, &Options::default()), ["lib/evil.sh"]);
+
+        // nor through one that is already in the target
+        let outside = TempDir::new();
+        let dir = TempDir::new();
+        std::os::unix::fs::symlink(&outside.0, dir.0.join("system")).unwrap();
+        let mut archive = build(
+            &[Entry::File("system/bin/evil.sh", b"x")],
+            CompressionMethod::Stored,
+        );
+        assert!(extract(&mut archive, &dir.0, &Options::default()).is_err());
+        assert!(!outside.0.join("bin").exists());
+    }
+
+    #[test]
+    fn rejects_size_and_ratio_bombs() {
+        let zeros = vec![0u8; 4 << 20];
+        for method in [CompressionMethod::Deflated, CompressionMethod::Xz] {
+            let mut archive = build(&[Entry::File("bomb", &zeros)], method);
+            assert_eq!(rejected(&mut archive, &Options::default()), ["bomb"]);
+        }
+
+        let mut archive = build(
+            &[Entry::File("a", &[1; 4096]), Entry::File("b", &[2; 4096])],
+            CompressionMethod::Stored,
+        );
+        let options = Options {
+            limits: Limits {
+                max_total_size: 6000,
+                ..Limits::default()
+            },
+            ..Options::default()
+        };
+        assert_eq!(rejected(&mut archive, &options), ["*"]);
+        let options = Options {
+            limits: Limits {
+                max_entries: 1,
+                ..Limits::default()
+            },
+            ..Options::default()
+        };
+        assert_eq!(rejected(&mut archive, &options), ["*"]);
+    }
+}
@@ -0,0 +1,189 @@
+//! `apd module inspect <zip>`: what installing a zip would do, without running
+//! anything from it
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    defs, deps, extract, metamodule,
+    module::{self, InstallBlock},
+    signature,
+};
+
+/// Files at the top of a module that apd or the installer run
+const SCRIPTS: &[&str] = &[
+    "customize.sh",
+    "post-fs-data.sh",
+    "post-mount.sh",
+    "service.sh",
+    "boot-completed.sh",
+    "uninstall.sh",
+    defs::MODULE_ACTION_SH,
+    defs::METAMODULE_MOUNT_SCRIPT,
+    defs::METAMODULE_METAINSTALL_SCRIPT,
+    defs::METAMODULE_METAUNINSTALL_SCRIPT,
+];
+
+#[derive(Debug, Default, Serialize)]
+pub struct Inspection {
+    pub id: Option<String>,
+    pub module_prop: BTreeMap<String, String>,
+    pub is_metamodule: bool,
+    pub needs_mount: bool,
+    pub scripts: Vec<String>,
+    pub sepolicy_rule: bool,
+    pub system_prop: bool,
+    pub webroot: bool,
+    pub lua: Vec<String>,
+    pub signer: Option<String>,
+    /// Reasons `module install` would refuse the zip right now
+    pub rejections: Vec<String>,
+    /// Things worth a look that do not block installation
+    pub warnings: Vec<String>,
+}
+
+pub fn inspect_zip(zip: &Path) -> Result<Inspection> {
+    let mut inspection = Inspection::default();
+
+    let archive = zip::ZipArchive::new(fs::File::open(zip)?)?;
+    let names: Vec<&str> = archive.file_names().collect();
+    let top_level = |name: &str| names.contains(&name);
+
+    inspection.scripts = SCRIPTS
+        .iter()
+        .filter(|script| top_level(script))
+        .map(ToString::to_string)
+        .collect();
+    inspection.sepolicy_rule = top_level("sepolicy.rule");
+    inspection.system_prop = top_level("system.prop");
+    let webroot = format!("{}/", defs::MODULE_WEB_DIR);
+    inspection.webroot = names.iter().any(|name| name.starts_with(&webroot));
+    inspection.lua = names
+        .iter()
+        .filter(|name| !name.contains('/') && name.ends_with(".lua"))
+        .map(ToString::to_string)
+        .collect();
+    inspection.lua.sort();
+    inspection.needs_mount = module::zip_needs_mount(zip)?;
+
+    let mut archive = zip::ZipArchive::new(fs::File::open(zip)?)?;
+    for rejection in extract::check(&mut archive, &extract::Options::load())? {
+        inspection
+            .rejections
+            .push(format!("unsafe entry {rejection}"));
+    }
+
+    let module_prop = match module::read_zip_module_prop(zip) {
+        Ok(module_prop) => module_prop,
+        Err(e) => {
+            inspection.rejections.push(format!("module.prop: {e:#}"));
+            return Ok(inspection);
+        }
+    };
+    inspection.is_metamodule = metamodule::is_metamodule(&module_prop);
+    inspection.module_prop = module_prop.clone().into_iter().collect();
+
+    let assessment = signature::assess(zip);
+    inspection.signer = assessment.signer.clone();
+    if let Some(problem) = &assessment.problem {
+        if assessment.rejected() {
+            inspection
+                .rejections
+                .push(format!("signature check failed: {problem}"));
+        } else if assessment.policy == signature::Policy::Warn {
+            inspection
+                .warnings
+                .push(format!("signature check failed: {problem}"));
+        }
+    }
+
+    let module_id = match module::zip_module_id(&module_prop) {
+        Ok(module_id) => module_id,
+        Err(e) => {
+            inspection.rejections.push(format!("{e:#}"));
+            return Ok(inspection);
+        }
+    };
+    inspection.id = Some(module_id.to_string());
+
+    if let Err(e) = deps::check_install(module_id, &module_prop) {
+        inspection.rejections.push(format!("{e:#}"));
+    }
+    let block = module::metamodule_install_block(
+        module_id,
+        inspection.is_metamodule,
+        inspection.needs_mount,
+    );
+    match block {
+        Some(InstallBlock::MetamoduleBusy { disabled }) => {
+            let action = if disabled {
+                "is disabled; re-enable or uninstall it, then reboot"
+            } else {
+                "has pending changes; reboot first"
+            };
+            inspection
+                .rejections
+                .push(format!("a metamodule with a custom installer {action}"));
+        }
+        Some(InstallBlock::OtherMetamodule(existing)) => inspection
+            .rejections
+            .push(format!("metamodule {existing} is already installed")),
+        None => {}
+    }
+
+    if inspection.scripts.iter().any(|s| s == "customize.sh") {
+        inspection
+            .warnings
+            .push("customize.sh runs as root during installation".to_string());
+    }
+    Ok(inspection)
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        items.join(", ")
+    }
+}
+
+pub fn print_inspection(zip: &str, json: bool) -> Result<()> {
+    let inspection = inspect_zip(Path::new(zip))?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&inspection)?);
+        return Ok(());
+    }
+
+    println!("module.prop:");
+    for (key, value) in &inspection.module_prop {
+        println!("  {key}={value}");
+    }
+    println!("metamodule: {}", yes_no(inspection.is_metamodule));
+    println!("needs mount: {}", yes_no(inspection.needs_mount));
+    println!("scripts: {}", list(&inspection.scripts));
+    println!("sepolicy.rule: {}", yes_no(inspection.sepolicy_rule));
+    println!("system.prop: {}", yes_no(inspection.system_prop));
+    println!("webroot: {}", yes_no(inspection.webroot));
+    println!("lua: {}", list(&inspection.lua));
+    println!(
+        "signer: {}",
+        inspection.signer.as_deref().unwrap_or("unsigned")
+    );
+    for warning in &inspection.warnings {
+        println!("warning: {warning}");
+    }
+    if inspection.rejections.is_empty() {
+        println!("install: allowed");
+    } else {
+        for rejection in &inspection.rejections {
+            println!("install: rejected: {rejection}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,347 @@
+//! Per-module file integrity manifests
+//!
+//! Installing a module records every file below its directory in
+//! `<module>/.integrity.json`: SHA-256 of regular files, symlink targets,
+//! mode, owner and SELinux context. `apd module verify [id]` compares the
+//! module against it and reports modified, added and missing files as well as
+//! label drift.
+//!
+//! `WORKING_DIR/integrity_policy` decides what post-fs-data does with a module
+//! whose files changed: `off` (the default) does not check, `warn` logs it and
+//! `enforce` disables the module before it is activated. Label drift alone is
+//! only ever reported. Modules that write into their own directory at runtime
+//! will trip `enforce`.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use jwalk::{Parallelism::Serial, WalkDir};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    defs,
+    module::{ModuleType, foreach_module, mark_module_state},
+    restorecon, snapshot,
+};
+
+const FORMAT_VERSION: u32 = 1;
+
+/// Files apd itself creates or removes in a module directory at runtime
+const UNTRACKED: &[&str] = &[
+    defs::INTEGRITY_FILE_NAME,
+    defs::DISABLE_FILE_NAME,
+    defs::REMOVE_FILE_NAME,
+    defs::UPDATE_FILE_NAME,
+    defs::TIMEOUT_COUNT_FILE_NAME,
+    defs::SIGNER_FILE_NAME,
+    snapshot::ROLLBACK_MARKER,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    Enforce,
+    Warn,
+    #[default]
+    Off,
+}
+
+pub fn policy() -> Policy {
+    let Ok(content) = fs::read_to_string(defs::resolve(defs::INTEGRITY_POLICY_FILE)) else {
+        return Policy::default();
+    };
+    match content.trim() {
+        "enforce" => Policy::Enforce,
+        "warn" => Policy::Warn,
+        "off" => Policy::Off,
+        other => {
+            warn!("invalid integrity policy: {other}, warning only");
+            Policy::Warn
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    kind: Kind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    format: u32,
+    files: BTreeMap<String, Record>,
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Records for everything below `dir`, keyed by relative path
+fn scan(dir: &Path) -> Result<BTreeMap<String, Record>> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(dir)
+        .parallelism(Serial)
+        .skip_hidden(false)
+        .sort(true)
+    {
+        let path = entry?.path();
+        let relative = path.strip_prefix(dir)?.to_string_lossy().to_string();
+        if relative.is_empty() || UNTRACKED.contains(&relative.as_str()) {
+            continue;
+        }
+
+        let metadata = fs::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+        let mut record = Record {
+            kind: Kind::Other,
+            sha256: None,
+            target: None,
+            mode: metadata.permissions().mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            context: restorecon::lgetfilecon(&path)
+                .ok()
+                .map(|con| con.trim_end_matches('\0').to_string())
+                .filter(|con| !con.is_empty()),
+        };
+        if file_type.is_symlink() {
+            record.kind = Kind::Symlink;
+            record.target = Some(fs::read_link(&path)?.to_string_lossy().to_string());
+        } else if file_type.is_dir() {
+            record.kind = Kind::Dir;
+        } else if file_type.is_file() {
+            record.kind = Kind::File;
+            record.sha256 = Some(sha256_file(&path)?);
+        }
+        files.insert(relative, record);
+    }
+    Ok(files)
+}
+
+/// Write the manifest of a freshly installed module directory
+pub fn record(module_dir: &Path) -> Result<()> {
+    let manifest = Manifest {
+        format: FORMAT_VERSION,
+        files: scan(module_dir)?,
+    };
+    let path = module_dir.join(defs::INTEGRITY_FILE_NAME);
+    fs::write(&path, serde_json::to_vec(&manifest)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub id: String,
+    /// Whether the module has a manifest to compare against
+    pub manifest: bool,
+    pub modified: Vec<String>,
+    pub added: Vec<String>,
+    pub missing: Vec<String>,
+    pub label_drift: Vec<String>,
+}
+
+impl Report {
+    /// Content, mode, owner or the set of files changed
+    pub fn tampered(&self) -> bool {
+        !self.modified.is_empty() || !self.added.is_empty() || !self.missing.is_empty()
+    }
+}
+
+fn describe_changes(old: &Record, new: &Record) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.kind != new.kind {
+        changes.push(format!("{:?} -> {:?}", old.kind, new.kind).to_lowercase());
+        return changes;
+    }
+    if old.sha256 != new.sha256 {
+        changes.push("content".to_string());
+    }
+    if old.target != new.target {
+        changes.push(format!(
+            "target {} -> {}",
+            old.target.as_deref().unwrap_or_default(),
+            new.target.as_deref().unwrap_or_default()
+        ));
+    }
+    if old.mode != new.mode {
+        changes.push(format!("mode {:o} -> {:o}", old.mode, new.mode));
+    }
+    if (old.uid, old.gid) != (new.uid, new.gid) {
+        changes.push(format!(
+            "owner {}:{} -> {}:{}",
+            old.uid, old.gid, new.uid, new.gid
+        ));
+    }
+    changes
+}
+
+pub fn verify(module_dir: &Path) -> Result<Report> {
+    let mut report = Report {
+        id: module_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        ..Report::default()
+    };
+    let manifest = match fs::read(module_dir.join(defs::INTEGRITY_FILE_NAME)) {
+        Ok(manifest) => manifest,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(report),
+        Err(e) => return Err(e.into()),
+    };
+    let manifest: Manifest = serde_json::from_slice(&manifest)
+        .with_context(|| format!("invalid integrity manifest of {}", report.id))?;
+    if manifest.format != FORMAT_VERSION {
+        bail!(
+            "unsupported integrity manifest format {} of {}",
+            manifest.format,
+            report.id
+        );
+    }
+    report.manifest = true;
+
+    let current = scan(module_dir)?;
+    for (path, old) in &manifest.files {
+        let Some(new) = current.get(path) else {
+            report.missing.push(path.clone());
+            continue;
+        };
+        let changes = describe_changes(old, new);
+        if !changes.is_empty() {
+            report
+                .modified
+                .push(format!("{path} ({})", changes.join(", ")));
+        }
+        // files that were unlabeled at install get labeled by restorecon on boot
+        if old.context.is_some() && old.context != new.context {
+            report.label_drift.push(format!(
+                "{path} ({} -> {})",
+                old.context.as_deref().unwrap_or_default(),
+                new.context.as_deref().unwrap_or("unlabeled")
+            ));
+        }
+    }
+    report.added = current
+        .keys()
+        .filter(|path| !manifest.files.contains_key(*path))
+        .cloned()
+        .collect();
+    Ok(report)
+}
+
+/// post-fs-data: apply the integrity policy to every active module
+pub fn check_modules() -> Result<()> {
+    let policy = policy();
+    if policy == Policy::Off {
+        return Ok(());
+    }
+    foreach_module(ModuleType::Active, |module_dir| {
+        let report = match verify(module_dir) {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("Failed to verify {}: {e:#}", module_dir.display());
+                return Ok(());
+            }
+        };
+        for drift in &report.label_drift {
+            warn!("{}: label drift {drift}", report.id);
+        }
+        if !report.tampered() {
+            return Ok(());
+        }
+        warn!(
+            "{}: files changed since install: {} modified, {} added, {} missing",
+            report.id,
+            report.modified.len(),
+            report.added.len(),
+            report.missing.len()
+        );
+        if policy == Policy::Enforce {
+            info!("disabling {} per integrity policy", report.id);
+            mark_module_state(&report.id, defs::DISABLE_FILE_NAME, true)?;
+        }
+        Ok(())
+    })
+}
+
+fn print_section(title: &str, items: &[String]) {
+    for item in items {
+        println!("  {title}: {item}");
+    }
+}
+
+/// `apd module verify [id]`
+pub fn print_verification(id: Option<&str>, json: bool) -> Result<()> {
+    let mut reports = Vec::new();
+    match id {
+        Some(id) => {
+            let module_dir = defs::module_dir().join(id);
+            if !module_dir.is_dir() {
+                bail!("module {id} is not installed");
+            }
+            reports.push(verify(&module_dir)?);
+        }
+        None => foreach_module(ModuleType::All, |module_dir| {
+            reports.push(verify(module_dir)?);
+            Ok(())
+        })?,
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else {
+        for report in &reports {
+            let status = if !report.manifest {
+                "no manifest"
+            } else if report.tampered() {
+                "changed"
+            } else if !report.label_drift.is_empty() {
+                "label drift"
+            } else {
+                "ok"
+            };
+            println!("{}: {status}", report.id);
+            print_section("modified", &report.modified);
+            print_section("added", &report.added);
+            print_section("missing", &report.missing);
+            print_section("label", &report.label_drift);
+        }
+    }
+
+    let tampered = reports.iter().filter(|report| report.tampered()).count();
+    if tampered > 0 {
+        bail!("{tampered} module(s) changed since install");
+    }
+    Ok(())
+}
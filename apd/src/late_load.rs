@@ -5,10 +5,9 @@ use anyhow::{Result, bail};
 use log::{info, warn};
 use regex_lite::Regex;
 use std::ffi::CStr;
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::path::PathBuf;
 
-use crate::{insmod, sepolicy};
+use crate::{defs, platform};
 
 /// Parse a Kernel Module Interface (KMI) name like `android14-5.15` from a
 /// kernel release string, e.g. `5.15.123-android14-4-g12345678-abcd1234`.
@@ -44,6 +43,7 @@ pub fn run(
     kmi: Option<String>,
     package_name: Option<String>,
 ) -> Result<()> {
+    let platform = platform::get();
     let module = match module {
         Some(path) => path,
         None => {
@@ -52,7 +52,7 @@ pub fn run(
                 None => bail!("cannot detect kernel KMI (androidX-Y.Z)"),
             };
             info!("detected KMI: {kmi}");
-            let path = PathBuf::from(format!("/data/adb/ap/{kmi}_kernelpatch.ko"));
+            let path = defs::working_dir().join(format!("{kmi}_kernelpatch.ko"));
             if !path.exists() {
                 bail!(
                     "kernel module not found: {} (download it to this path first)",
@@ -65,19 +65,19 @@ pub fn run(
 
     // Skip if the module is already loaded (the manager restart re-triggers the
     // app-zygote, which would otherwise fail with EEXIST on a second load).
-    if Path::new("/sys/module/kernelpatch").exists() {
+    if platform.is_kernel_module_loaded("kernelpatch") {
         info!("kernelpatch module already loaded, skip loading");
     } else {
         // Load the module without version check.
-        insmod::insmod(&module, &[])?;
+        platform.load_kernel_module(&module, &[])?;
     }
 
     // Apply Magisk sepolicy live so the loaded module can work.
-    sepolicy::apply_magisk_policy_live()?;
+    platform.apply_magisk_policy()?;
 
     // Mark jailbreak mode active.
-    let _ = std::fs::create_dir_all("/data/adb/ap");
-    let _ = std::fs::write("/data/adb/ap/jailbreak", "");
+    let _ = std::fs::create_dir_all(defs::working_dir());
+    let _ = std::fs::write(defs::resolve(defs::JAILBREAK_FILE), "");
 
     println!("late-load complete: {}", module.display());
 
@@ -85,19 +85,17 @@ pub fn run(
     // module and reflects the jailbroken (rooted) state.
     if let Some(pkg) = package_name {
         info!("restarting manager {pkg}...");
-        let _ = Command::new("am").args(["force-stop", &pkg]).status();
-        let _ = Command::new("am")
-            .args([
-                "start",
-                "-n",
-                &format!("{pkg}/me.bmax.apatch.ui.MainActivity"),
-            ])
-            .status();
+        let _ = platform.am(&["force-stop", &pkg]);
+        let _ = platform.am(&[
+            "start",
+            "-n",
+            &format!("{pkg}/me.bmax.apatch.ui.MainActivity"),
+        ]);
     }
 
     // The jailbreak needs permissive SELinux to run, but once the module, policy
     // and manager are all in place, restore enforcing.
-    if let Err(e) = std::fs::write("/sys/fs/selinux/enforce", "1") {
+    if let Err(e) = platform.set_selinux_enforcing(true) {
         warn!("failed to set SELinux enforcing: {e}");
     } else {
         info!("SELinux set to enforcing");
@@ -105,3 +103,61 @@ pub fn run(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::{Effect, FakePlatform, with_fake};
+
+    #[test]
+    fn parses_kmi() {
+        assert_eq!(
+            parse_kmi("5.15.123-android14-4-g12345678-abcd1234").as_deref(),
+            Some("android14-5.15")
+        );
+        assert_eq!(parse_kmi("6.1.0-generic"), None);
+    }
+
+    #[test]
+    fn loads_module_once_and_restarts_manager() {
+        with_fake(FakePlatform::default(), |fake| {
+            let module = defs::working_dir().join("android14-6.1_kernelpatch.ko");
+            run(
+                Some(module.clone()),
+                None,
+                Some("me.bmax.apatch".to_string()),
+            )
+            .unwrap();
+            assert_eq!(
+                fake.effects(),
+                [
+                    Effect::LoadKernelModule(module.clone()),
+                    Effect::MagiskPolicy,
+                    Effect::Am(vec!["force-stop".to_string(), "me.bmax.apatch".to_string()]),
+                    Effect::Am(vec![
+                        "start".to_string(),
+                        "-n".to_string(),
+                        "me.bmax.apatch/me.bmax.apatch.ui.MainActivity".to_string(),
+                    ]),
+                    Effect::SelinuxEnforcing(true),
+                ]
+            );
+            assert!(defs::resolve(defs::JAILBREAK_FILE).exists());
+
+            // already loaded, only the policy is applied again
+            run(Some(module), None, None).unwrap();
+            assert_eq!(
+                fake.effects()[5..],
+                [Effect::MagiskPolicy, Effect::SelinuxEnforcing(true)]
+            );
+        });
+    }
+
+    #[test]
+    fn needs_the_module_of_the_kmi() {
+        with_fake(FakePlatform::default(), |fake| {
+            assert!(run(None, Some("android14-6.1".to_string()), None).is_err());
+            assert!(fake.effects().is_empty());
+        });
+    }
+}
@@ -1,25 +1,189 @@
+use crate::boot_report::StageReport;
+use crate::defs;
 use crate::module::*;
-use crate::utils::*;
-use anyhow::Result;
+use crate::module_config::{self, ConfigEntry, ConfigType, ConfigValue};
+use crate::module_log;
+use crate::utils::ensure_dir_exists;
+use anyhow::{Context, Result};
 use log::{info, warn};
-use mlua::{Function, Lua, Result as LuaResult, Table};
-use std::{fs, path::Path};
+use mlua::{Function, IntoLuaMulti, Lua, Result as LuaResult, Table, Value, Variadic};
+use std::{fs, io::Write, path::Path};
 
-pub fn save_text<P: AsRef<Path>>(filename: P, content: &str) -> std::io::Result<()> {
-    let _ = ensure_dir_exists("/data/adb/config");
-    let path = format!("/data/adb/config/{}", filename.as_ref().display());
-    fs::write(&path, content)?;
+fn lua_error(e: anyhow::Error) -> mlua::Error {
+    mlua::Error::external(format!("{e:#}"))
+}
+
+/// Store a value of the old shared store in the persist config of module `id`.
+/// Not a change the module made, so no notification.
+fn store_legacy_value(id: &str, key: &str, value: &str) -> Result<()> {
+    let mut config = module_config::load_entries(id, ConfigType::Persist)?;
+    config.insert(
+        key.to_string(),
+        ConfigEntry::new(ConfigValue::String(value.to_string())),
+    );
+    module_config::save_entries(id, ConfigType::Persist, &config)
+}
+
+fn record_legacy_key(marker: &Path, key: &str) -> Result<()> {
+    if let Some(dir) = marker.parent() {
+        ensure_dir_exists(dir)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(marker)
+        .with_context(|| format!("Failed to open {}", marker.display()))?;
+    writeln!(file, "{key}")?;
     Ok(())
 }
 
-pub fn load_text<P: AsRef<Path>>(filename: P) -> std::io::Result<String> {
-    let _ = ensure_dir_exists("/data/adb/config");
-    let path = format!("/data/adb/config/{}", filename.as_ref().display());
-    fs::read_to_string(path)
+/// Value the old shared `setConfig` stored for `key`, moved into the persist
+/// config of module `id` the first time the module misses it. Each key is
+/// looked up once: the marker next to the config lists them, so deleting the
+/// value does not bring the old one back. Files that can not be read or do not
+/// fit the config are skipped; the shared files stay for the other modules.
+fn migrate_legacy_key(id: &str, key: &str) -> Option<String> {
+    let marker = module_config::get_config_path(id, ConfigType::Persist)
+        .with_file_name(defs::LUA_MIGRATED_NAME);
+    let migrated = fs::read_to_string(&marker).unwrap_or_default();
+    if migrated.lines().any(|line| line == key) {
+        return None;
+    }
+
+    let path = defs::resolve(defs::LUA_CONFIG_DIR).join(key);
+    let value = match fs::read_to_string(&path) {
+        Ok(value) => match store_legacy_value(id, key, &value) {
+            Ok(()) => {
+                info!("migrated {} into config of {id}", path.display());
+                Some(value)
+            }
+            Err(e) => {
+                warn!("skipping {}: {e:#}", path.display());
+                None
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("skipping {}: {e}", path.display());
+            None
+        }
+    };
+    if let Err(e) = record_legacy_key(&marker, key) {
+        warn!("{e:#}");
+    }
+    value
+}
+
+/// Effective value of `key` for module `id`; binary values come back as they are
+fn config_get(lua: &Lua, id: &str, key: &str) -> Result<Value> {
+    module_config::validate_config_key(key)?;
+    if let Some(ConfigEntry {
+        value: ConfigValue::Blob(bytes),
+        ..
+    }) = module_config::get_config_entry(id, key)?
+    {
+        return Ok(Value::String(lua.create_string(bytes)?));
+    }
+    Ok(match module_config::effective_config(id)?.remove(key) {
+        Some(value) => Value::String(lua.create_string(value)?),
+        None => Value::Nil,
+    })
+}
+
+/// Store a Lua value for module `id`; strings that are not UTF-8 become blobs
+fn config_set(id: &str, key: &str, value: Value, temp: bool) -> Result<()> {
+    let config_type = if temp {
+        ConfigType::Temp
+    } else {
+        ConfigType::Persist
+    };
+    let text = match value {
+        Value::String(s) => match s.to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => {
+                return module_config::set_config_blob(id, key, s.as_bytes().to_vec(), config_type);
+            }
+        },
+        Value::Integer(n) => n.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        _ => anyhow::bail!("{key}: only strings, numbers and booleans can be stored"),
+    };
+    module_config::set_config_value(id, key, &text, config_type)
+}
+
+/// `config.get/set/delete/list` of module `id`
+fn config_table(lua: &Lua, id: &str) -> LuaResult<Table> {
+    let table = lua.create_table()?;
+    let module = id.to_string();
+    table.set(
+        "get",
+        lua.create_function(move |lua, key: String| {
+            config_get(lua, &module, &key).map_err(lua_error)
+        })?,
+    )?;
+    let module = id.to_string();
+    table.set(
+        "set",
+        lua.create_function(
+            move |_, (key, value, temp): (String, Value, Option<bool>)| {
+                config_set(&module, &key, value, temp.unwrap_or(false)).map_err(lua_error)
+            },
+        )?,
+    )?;
+    let module = id.to_string();
+    table.set(
+        "delete",
+        lua.create_function(move |_, (key, temp): (String, Option<bool>)| {
+            let config_type = if temp.unwrap_or(false) {
+                ConfigType::Temp
+            } else {
+                ConfigType::Persist
+            };
+            module_config::delete_config_value(&module, &key, config_type).map_err(lua_error)
+        })?,
+    )?;
+    let module = id.to_string();
+    table.set(
+        "list",
+        lua.create_function(move |_, ()| {
+            module_config::effective_config(&module).map_err(lua_error)
+        })?,
+    )?;
+    Ok(table)
+}
+
+/// Globals as the code of module `id` sees them: `_G` plus its own `config`
+/// and the older `getConfig`/`setConfig`, which now use the same store
+fn module_env(lua: &Lua, id: &str) -> LuaResult<Table> {
+    let env = lua.create_table()?;
+    let config = config_table(lua, id)?;
+    let module = id.to_string();
+    // missing keys come from the old store, or read as "" like they always did
+    env.set(
+        "getConfig",
+        lua.create_function(move |lua, key: String| {
+            match config_get(lua, &module, &key).map_err(lua_error)? {
+                Value::Nil => {
+                    let value = migrate_legacy_key(&module, &key).unwrap_or_default();
+                    Ok(Value::String(lua.create_string(value)?))
+                }
+                value => Ok(value),
+            }
+        })?,
+    )?;
+    env.set("setConfig", config.get::<Function>("set")?)?;
+    env.set("config", config)?;
+    let inherit: Function = lua
+        .load(
+            "return function(env) return setmetatable(env, { __index = _G, __newindex = _G }) end",
+        )
+        .eval()?;
+    inherit.call(env)
 }
 
 pub fn load_all_lua_modules(lua: &Lua) -> LuaResult<()> {
-    let modules_dir = Path::new("/data/adb/modules");
+    let modules_dir = defs::module_dir();
 
     let modules: Table = match lua.globals().get("modules") {
         Ok(t) => t,
@@ -31,11 +195,7 @@ pub fn load_all_lua_modules(lua: &Lua) -> LuaResult<()> {
     };
 
     if modules_dir.exists() {
-        for entry in fs::read_dir(modules_dir)
-            .unwrap_or_else(|_| fs::read_dir("/dev/null").unwrap())
-            .flatten()
-        {
-            let path = entry.path();
+        for path in sorted_module_entries(&modules_dir).unwrap_or_default() {
             if path.is_dir() {
                 let id = path.file_name().unwrap().to_string_lossy().to_string();
                 let package: Table = lua.globals().get("package")?;
@@ -51,6 +211,7 @@ pub fn load_all_lua_modules(lua: &Lua) -> LuaResult<()> {
                             match lua
                                 .load(&code)
                                 .set_name(&*lua_file.to_string_lossy())
+                                .set_environment(module_env(lua, &id)?)
                                 .eval::<Table>()
                             {
                                 Ok(module) => {
@@ -93,41 +254,135 @@ pub fn install_module_lua(lua: &Lua) -> LuaResult<Function> {
             .map_err(|e| mlua::Error::external(format!("install_module failed: {}", e)))
     })
 }
-pub fn save_text_lua(lua: &Lua) -> LuaResult<Function> {
-    lua.create_function(|_, (filename, content): (String, String)| {
-        save_text(&filename, &content)
-            .map_err(|e| mlua::Error::external(format!("save filed: {}", e)))?;
-        Ok(())
-    })
+
+/// The global `print`, `info` and `warn`, for [`set_hook_output`] to fall back to
+fn output_defaults(lua: &Lua) -> LuaResult<Table> {
+    let defaults = lua.create_table()?;
+    for name in ["print", "info", "warn"] {
+        defaults.set(name, lua.globals().get::<Value>(name)?)?;
+    }
+    Ok(defaults)
 }
-pub fn read_text_lua(lua: &Lua) -> LuaResult<Function> {
-    lua.create_function(|_, filename: String| {
-        let content = match load_text(&filename) {
-            Ok(s) => s,
-            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
-            Err(e) => return Err(mlua::Error::external(format!("read failed: {}", e))),
-        };
-        Ok(content)
-    })
+
+/// Point `print`, `info` and `warn` of the hook about to run at the stage log
+/// of module `id`; `defaults` are put back if the log cannot be opened
+fn set_hook_output(lua: &Lua, id: &str, stage: &str, defaults: &Table) -> LuaResult<()> {
+    let globals = lua.globals();
+    let log = match module_log::open(id, stage) {
+        Ok(log) => log,
+        Err(e) => {
+            warn!("Failed to open {stage} log of {id}: {e:#}");
+            for name in ["print", "info", "warn"] {
+                globals.set(name, defaults.get::<Value>(name)?)?;
+            }
+            return Ok(());
+        }
+    };
+
+    let out = log.try_clone().map_err(mlua::Error::external)?;
+    globals.set(
+        "print",
+        lua.create_function(move |_, args: Variadic<Value>| {
+            let line = args
+                .iter()
+                .map(Value::to_string)
+                .collect::<LuaResult<Vec<_>>>()?
+                .join("\t");
+            writeln!(&out, "{line}").map_err(mlua::Error::external)
+        })?,
+    )?;
+    let out = log.try_clone().map_err(mlua::Error::external)?;
+    globals.set(
+        "info",
+        lua.create_function(move |_, msg: String| {
+            info!("[Lua] {}", msg);
+            writeln!(&out, "[info] {msg}").map_err(mlua::Error::external)
+        })?,
+    )?;
+    globals.set(
+        "warn",
+        lua.create_function(move |_, msg: String| {
+            warn!("[Lua] {}", msg);
+            writeln!(&log, "[warn] {msg}").map_err(mlua::Error::external)
+        })?,
+    )?;
+    Ok(())
 }
 
-pub fn exec_stage_lua(stage: &str, wait: bool, superkey: &str) -> Result<()> {
+pub fn exec_stage_lua(
+    stage: &str,
+    _wait: bool,
+    superkey: &str,
+    report: &mut StageReport,
+) -> Result<()> {
     let stage_safe = stage.replace('-', "_");
-    run_lua(superkey, &stage_safe, true, wait).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let step_name = format!("{stage}.lua");
+    let lua = new_lua().map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let modules: mlua::Table = lua
+        .globals()
+        .get("modules")
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    let defaults = output_defaults(&lua).map_err(|e| anyhow::anyhow!("{}", e))?;
+    // same order as the stage scripts, so priority= applies
+    for path in sorted_module_entries(&defs::module_dir())? {
+        let Some(module_id) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let Ok(module_table) = modules.get::<mlua::Table>(module_id) else {
+            continue;
+        };
+        let Ok(func_obj) = module_table.get::<mlua::Function>(stage_safe.as_str()) else {
+            continue;
+        };
+        // one broken hook must not keep the other modules from running
+        let _ = report.step(&step_name, Some(module_id), || {
+            set_hook_output(&lua, module_id, stage, &defaults)
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            func_obj
+                .call::<()>(superkey)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        });
+    }
     Ok(())
 }
 
-pub fn run_lua(id: &str, function: &str, on_each_module: bool, _wait: bool) -> mlua::Result<()> {
+/// Call the `hook` callback of module `id` with `args` and its output in the
+/// module log. Returns whether the module has such a callback.
+pub fn exec_module_hook(id: &str, hook: &str, args: impl IntoLuaMulti) -> Result<bool> {
+    let lua = new_lua().map_err(|e| anyhow::anyhow!("{}", e))?;
+    let modules: Table = lua
+        .globals()
+        .get("modules")
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    let Ok(module_table) = modules.get::<Table>(id) else {
+        return Ok(false);
+    };
+    let Ok(func_obj) = module_table.get::<Function>(hook) else {
+        return Ok(false);
+    };
+    let defaults = output_defaults(&lua).map_err(|e| anyhow::anyhow!("{}", e))?;
+    set_hook_output(&lua, id, hook, &defaults).map_err(|e| anyhow::anyhow!("{}", e))?;
+    func_obj
+        .call::<()>(args)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok(true)
+}
+
+fn new_lua() -> mlua::Result<Lua> {
     let lua = unsafe { Lua::unsafe_new() };
 
     let func = install_module_lua(&lua)?;
     lua.globals().set("install_module", func)?;
     lua.globals().set("info", info_lua(&lua)?)?;
     lua.globals().set("warn", warn_lua(&lua)?)?;
-    lua.globals().set("setConfig", save_text_lua(&lua)?)?;
-    lua.globals().set("getConfig", read_text_lua(&lua)?)?;
 
     load_all_lua_modules(&lua)?;
+    Ok(lua)
+}
+
+pub fn run_lua(id: &str, function: &str, on_each_module: bool, _wait: bool) -> mlua::Result<()> {
+    let lua = new_lua()?;
 
     let modules: mlua::Table = lua.globals().get("modules")?;
     if on_each_module {
@@ -145,3 +400,66 @@ pub fn run_lua(id: &str, function: &str, on_each_module: bool, _wait: bool) -> m
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::platform::{FakePlatform, with_fake};
+
+    fn legacy(files: &[(&str, &[u8])]) {
+        let dir = defs::resolve(defs::LUA_CONFIG_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        for (key, value) in files {
+            fs::write(dir.join(key), value).unwrap();
+        }
+    }
+
+    fn persist() -> HashMap<String, String> {
+        module_config::load_config("mod_a", ConfigType::Persist).unwrap()
+    }
+
+    #[test]
+    fn migrates_only_the_keys_looked_up() {
+        with_fake(FakePlatform::default(), |_| {
+            legacy(&[("a.key", b"1"), ("b.key", b"2")]);
+            assert_eq!(migrate_legacy_key("mod_a", "a.key").as_deref(), Some("1"));
+            assert_eq!(persist().keys().collect::<Vec<_>>(), ["a.key"]);
+
+            // once deleted, a key stays deleted
+            module_config::delete_config_value("mod_a", "a.key", ConfigType::Persist).unwrap();
+            assert_eq!(migrate_legacy_key("mod_a", "a.key"), None);
+            assert_eq!(migrate_legacy_key("mod_a", "none"), None);
+            assert_eq!(migrate_legacy_key("mod_a", "b.key").as_deref(), Some("2"));
+            assert_eq!(persist().keys().collect::<Vec<_>>(), ["b.key"]);
+            // other modules still get the shared values
+            assert!(migrate_legacy_key("mod_b", "a.key").is_some());
+        });
+    }
+
+    #[test]
+    fn skips_values_that_can_not_be_migrated() {
+        with_fake(FakePlatform::default(), |_| {
+            let big = vec![b'x'; module_config::DEFAULT_MAX_VALUE_LEN + 1];
+            legacy(&[("big", &big), ("binary", &[0xff, 0xfe])]);
+            fs::create_dir_all(defs::resolve(defs::LUA_CONFIG_DIR).join("dir")).unwrap();
+            for key in ["big", "binary", "dir"] {
+                assert_eq!(migrate_legacy_key("mod_a", key), None);
+            }
+            assert!(persist().is_empty());
+            let marker = module_config::get_config_path("mod_a", ConfigType::Persist)
+                .with_file_name(defs::LUA_MIGRATED_NAME);
+            assert_eq!(fs::read_to_string(marker).unwrap(), "big\nbinary\ndir\n");
+
+            // a full config keeps what it has
+            let full: HashMap<String, String> = (0..module_config::DEFAULT_MAX_CONFIG_COUNT)
+                .map(|i| (format!("key{i}"), i.to_string()))
+                .collect();
+            module_config::save_config("mod_a", ConfigType::Persist, &full).unwrap();
+            legacy(&[("extra", b"1")]);
+            assert_eq!(migrate_legacy_key("mod_a", "extra"), None);
+            assert_eq!(persist(), full);
+        });
+    }
+}
@@ -1,22 +1,41 @@
 mod apd;
 mod assets;
+mod backup;
+mod boot_guard;
+mod boot_report;
 mod cli;
+mod config_batch;
+mod config_events;
+mod config_schema;
 mod defs;
+mod deps;
 mod event;
+mod extract;
 mod insmod;
+mod inspect;
+mod integrity;
 mod late_load;
 mod lua;
 mod magica;
 mod metamodule;
 mod module;
 mod module_config;
+mod module_log;
+mod module_update;
 mod package;
+mod platform;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 mod pty;
 mod resetprop;
+mod restart;
 mod restorecon;
+mod scheduler;
 mod sepolicy;
+mod signature;
+mod snapshot;
 mod supercall;
+mod supervisor;
+mod trash;
 mod utils;
 fn main() -> anyhow::Result<()> {
     cli::run()
@@ -13,7 +13,7 @@ use std::{
 use anyhow::{Context, Result, ensure};
 use log::{info, warn};
 
-use crate::{assets, defs, module::ModuleType::All};
+use crate::{assets, boot_report::StageReport, defs, module::ModuleType::All};
 
 /// Determine whether the provided module properties mark it as a metamodule
 pub fn is_metamodule(props: &HashMap<String, String>) -> bool {
@@ -26,7 +26,8 @@ pub fn is_metamodule(props: &HashMap<String, String>) -> bool {
 /// Get metamodule path if it exists
 /// The metamodule is stored in /data/adb/modules/{id} with a symlink at /data/adb/metamodule
 pub fn get_metamodule_path() -> Option<PathBuf> {
-    let path = Path::new(defs::METAMODULE_DIR);
+    let path = defs::metamodule_dir();
+    let path = path.as_path();
 
     // Check if symlink exists and resolve it
     if path.is_symlink()
@@ -96,7 +97,7 @@ pub fn check_install_safety() -> Result<(), bool> {
         .join(defs::METAMODULE_METAINSTALL_SCRIPT)
         .exists()
         || metamodule_path.file_name().is_some_and(|module_id| {
-            Path::new(defs::MODULE_UPDATE_DIR)
+            defs::module_update_dir()
                 .join(module_id)
                 .join(defs::METAMODULE_METAINSTALL_SCRIPT)
                 .exists()
@@ -125,8 +126,9 @@ pub fn ensure_symlink<P>(module_path: P) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    // METAMODULE_DIR might have trailing slash, so we need to trim it
-    let symlink_path = Path::new(defs::METAMODULE_DIR.trim_end_matches('/'));
+    // METAMODULE_DIR might have trailing slash, components() drops it
+    let symlink_path: PathBuf = defs::metamodule_dir().components().collect();
+    let symlink_path = symlink_path.as_path();
     let module_path = module_path.as_ref();
 
     info!(
@@ -158,7 +160,8 @@ where
 
 /// Remove the metamodule symlink
 pub fn remove_symlink() -> Result<()> {
-    let symlink_path = Path::new(defs::METAMODULE_DIR.trim_end_matches('/'));
+    let symlink_path: PathBuf = defs::metamodule_dir().components().collect();
+    let symlink_path = symlink_path.as_path();
 
     if symlink_path.is_symlink() {
         std::fs::remove_file(symlink_path)
@@ -237,7 +240,7 @@ pub fn exec_metauninstall_script(module_id: &str) -> Result<()> {
 
     info!("Executing metamodule metauninstall.sh for module: {module_id}",);
 
-    let result = Command::new(assets::BUSYBOX_PATH)
+    let result = Command::new(assets::busybox_path())
         .args(["sh", metauninstall_path.to_str().unwrap()])
         .current_dir(metauninstall_path.parent().unwrap())
         .envs(crate::module::get_common_script_envs(
@@ -257,14 +260,14 @@ pub fn exec_metauninstall_script(module_id: &str) -> Result<()> {
 }
 
 /// Execute metamodule mount script
-pub fn exec_mount_script(module_dir: &str) -> Result<()> {
+pub fn exec_mount_script(module_dir: &Path) -> Result<()> {
     let Some(mount_script) = check_metamodule_script(defs::METAMODULE_MOUNT_SCRIPT) else {
         return Ok(());
     };
 
     info!("Executing mount script for metamodule");
 
-    let result = Command::new(assets::BUSYBOX_PATH)
+    let result = Command::new(assets::busybox_path())
         .args(["sh", mount_script.to_str().unwrap()])
         .envs(crate::module::get_common_script_envs(
             get_metamodule_id().as_deref(),
@@ -283,13 +286,24 @@ pub fn exec_mount_script(module_dir: &str) -> Result<()> {
 }
 
 /// Execute metamodule script for a specific stage
-pub fn exec_stage_script(stage: &str, block: bool) -> Result<()> {
-    let Some(script_path) = check_metamodule_script(&format!("{stage}.sh")) else {
+pub fn exec_stage_script(stage: &str, block: bool, report: &mut StageReport) -> Result<()> {
+    let script_name = format!("{stage}.sh");
+    let Some(script_path) = check_metamodule_script(&script_name) else {
         return Ok(());
     };
 
     info!("Executing metamodule {stage}.sh");
-    crate::module::exec_script(&script_path, block)?;
+    let module_id = get_metamodule_id();
+    report.step(&script_name, module_id.as_deref(), || {
+        let timeout = block
+            .then(|| crate::module::script_timeout(get_metamodule_path().as_deref()))
+            .flatten();
+        match &module_id {
+            Some(id) => crate::module::run_stage_script(id, stage, &script_path, block, timeout),
+            None if block => crate::module::run_script_with_timeout(&script_path, timeout),
+            None => crate::module::run_script(&script_path, false).map(Into::into),
+        }
+    })?;
     info!("Metamodule {stage}.sh executed successfully");
     Ok(())
 }
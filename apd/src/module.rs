@@ -1,4 +1,3 @@
-use crate::sepolicy::get_policy_main;
 use crate::{lua, module_config};
 use anyhow::{Context, Result, anyhow, bail, ensure};
 use const_format::concatcp;
@@ -6,23 +5,27 @@ use is_executable::is_executable;
 use java_properties::PropertiesIter;
 use log::{debug, info, warn};
 #[cfg(unix)]
-use std::os::unix::{prelude::PermissionsExt, process::CommandExt};
+use std::os::unix::{fs::OpenOptionsExt, prelude::PermissionsExt, process::CommandExt};
 use std::{
     collections::HashMap,
     env::var as env_var,
     fs::{self, remove_dir_all},
-    io::{Cursor, Read},
+    io::{self, Cursor, Read},
     path::{Path, PathBuf},
-    process::Command,
-    str::FromStr,
+    process::{Child, Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
 };
 
 #[allow(clippy::wildcard_imports)]
 use crate::utils::*;
 use crate::{
     assets,
-    defs::{self, MODULE_DIR, MODULE_UPDATE_DIR},
-    metamodule, restorecon,
+    boot_report::{Outcome, StageReport},
+    config_schema, defs, deps, extract, integrity, metamodule, module_log, platform, restart,
+    restorecon,
+    scheduler::{self, Job},
+    signature, snapshot, trash,
 };
 
 const INSTALLER_CONTENT: &str = include_str!("../assets/installer.sh");
@@ -35,6 +38,18 @@ const INSTALL_MODULE_SCRIPT: &str = concatcp!(
     "\n"
 );
 
+/// Seconds a blocking stage script may run unless configured otherwise
+const DEFAULT_SCRIPT_TIMEOUT_SECS: u64 = 60;
+/// Time a timed out script gets between SIGTERM and SIGKILL
+const SCRIPT_KILL_GRACE: Duration = Duration::from_secs(3);
+/// module.prop / module config key overriding the timeout, in seconds (0 = none)
+const SCRIPT_TIMEOUT_KEY: &str = "scriptTimeout";
+/// module.prop key of modules whose enable/disable hooks fully apply the change
+const LIVE_TOGGLE_KEY: &str = "liveToggle";
+/// Consecutive timeouts after which a module is disabled
+const MAX_SCRIPT_TIMEOUTS: u32 = 3;
+const SCRIPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(PartialEq, Eq)]
 pub enum ModuleType {
     All,
@@ -50,18 +65,26 @@ fn exec_install_script(module_file: &str, is_metamodule: bool, module_id: &str)
     let install_script =
         metamodule::get_install_script(is_metamodule, INSTALLER_CONTENT, INSTALL_MODULE_SCRIPT)?;
 
-    let result = Command::new(assets::BUSYBOX_PATH)
+    let mut command = Command::new(assets::busybox_path());
+    command
         .args(["sh", "-c", &install_script])
         .envs(get_common_script_envs(Some(module_id)))
         .env("OUTFD", "1")
         .env("ZIPFILE", realpath)
-        .status()?;
+        // the zip is already in modules_update/<id>, see extract.rs
+        .env("APD_EXTRACTED", "1");
+    // installer.sh hard-codes NVBASE=/data/adb unless told otherwise
+    if !defs::is_default_root() {
+        command.env("NVBASE", defs::adb_dir().as_os_str());
+    }
+    let result = command.status()?;
     ensure!(result.success(), "Failed to install module script");
     Ok(())
 }
 
 pub fn handle_updated_modules() -> Result<()> {
-    let modules_root = Path::new(MODULE_DIR);
+    let modules_root = defs::module_dir();
+    let mut updated = Vec::new();
     foreach_module(ModuleType::Updated, |updated_module| {
         if !updated_module.is_dir() {
             return Ok(());
@@ -71,13 +94,28 @@ pub fn handle_updated_modules() -> Result<()> {
             let module_dir = modules_root.join(name);
             let mut disabled = false;
             let mut removed = false;
+            let id = name.to_string_lossy();
+            let rollback = snapshot::pending_rollback(&id, updated_module);
             if module_dir.exists() {
                 // If the old module is disabled, we need to also disable the new one
                 disabled = module_dir.join(defs::DISABLE_FILE_NAME).exists();
                 removed = module_dir.join(defs::REMOVE_FILE_NAME).exists();
-                remove_dir_all(&module_dir)?;
+                // keep the outgoing version around, unless it is the one being rolled
+                // back or just the placeholder of a first install
+                if rollback.is_some() || !module_dir.join("module.prop").exists() {
+                    remove_dir_all(&module_dir)?;
+                } else if let Err(e) = snapshot::take(&id, &module_dir) {
+                    warn!("Failed to snapshot {id}: {e:#}");
+                    remove_dir_all(&module_dir)?;
+                }
             }
             std::fs::rename(updated_module, &module_dir)?;
+            if let Some(snapshot) = rollback
+                && let Err(e) = snapshot::finish_rollback(&id, &module_dir, &snapshot)
+            {
+                warn!("Failed to finish rollback of {id}: {e:#}");
+            }
+            updated.push(id.to_string());
             if removed {
                 let path = module_dir.join(defs::REMOVE_FILE_NAME);
                 if let Err(e) = ensure_file_exists(&path) {
@@ -92,6 +130,9 @@ pub fn handle_updated_modules() -> Result<()> {
         }
         Ok(())
     })?;
+    if let Err(e) = crate::boot_guard::track_updated_modules(&updated) {
+        warn!("Failed to track updated modules: {e:#}");
+    }
     Ok(())
 }
 
@@ -107,11 +148,19 @@ pub fn get_common_script_envs(module_id: Option<&str>) -> Vec<(&'static str, Str
             format!(
                 "{}:{}",
                 env_var("PATH").unwrap_or_default(),
-                defs::BINARY_DIR.trim_end_matches('/')
+                defs::binary_dir()
+                    .display()
+                    .to_string()
+                    .trim_end_matches('/')
             ),
         ),
     ];
 
+    // Let nested `apd` invocations from scripts resolve the same tree
+    if !defs::is_default_root() {
+        envs.push((defs::ROOT_ENV, defs::root().display().to_string()));
+    }
+
     if let Some(id) = module_id {
         envs.push(("AP_MODULE", id.to_string()));
     }
@@ -124,6 +173,10 @@ pub fn get_common_script_envs(module_id: Option<&str>) -> Vec<(&'static str, Str
 // if someone(such as the module) install a module before the boot_completed
 // then it may cause some problems, just forbid it
 fn ensure_boot_completed() -> Result<()> {
+    // a sandbox root is not the live module tree, so there is no boot to race with
+    if !defs::is_default_root() {
+        return Ok(());
+    }
     // ensure getprop sys.boot_completed == 1
     if getprop("sys.boot_completed").as_deref() != Some("1") {
         bail!("Android is Booting!");
@@ -131,12 +184,12 @@ fn ensure_boot_completed() -> Result<()> {
     Ok(())
 }
 
-fn mark_update() -> Result<()> {
-    ensure_file_exists(concatcp!(defs::WORKING_DIR, defs::UPDATE_FILE_NAME))
+pub fn mark_update() -> Result<()> {
+    ensure_file_exists(defs::working_dir().join(defs::UPDATE_FILE_NAME))
 }
 
-fn mark_module_state(module: &str, flag_file: &str, create_or_delete: bool) -> Result<()> {
-    let module_state_file = Path::new(defs::MODULE_DIR).join(module).join(flag_file);
+pub fn mark_module_state(module: &str, flag_file: &str, create_or_delete: bool) -> Result<()> {
+    let module_state_file = defs::module_dir().join(module).join(flag_file);
     if create_or_delete {
         ensure_file_exists(module_state_file)
     } else {
@@ -146,17 +199,43 @@ fn mark_module_state(module: &str, flag_file: &str, create_or_delete: bool) -> R
         Ok(())
     }
 }
+
+/// `priority=` from module.prop, 0 when missing or invalid
+fn module_priority(module: &Path) -> i32 {
+    read_module_prop(module)
+        .ok()
+        .and_then(|props| props.get("priority")?.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Entries of a modules directory in their effective order: ascending
+/// `priority=`, then module id. Later modules win where they overlap, e.g. for
+/// the same property in system.prop.
+pub fn sorted_module_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries: Vec<(i32, PathBuf)> = fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| {
+            let path = entry.path();
+            (module_priority(&path), path)
+        })
+        .collect();
+    entries.sort_by(|(a_priority, a), (b_priority, b)| {
+        a_priority
+            .cmp(b_priority)
+            .then_with(|| a.file_name().cmp(&b.file_name()))
+    });
+    Ok(entries.into_iter().map(|(_, path)| path).collect())
+}
+
 pub fn foreach_module(
     module_type: ModuleType,
     mut f: impl FnMut(&Path) -> Result<()>,
 ) -> Result<()> {
-    let modules_dir = Path::new(match module_type {
-        ModuleType::Updated => MODULE_UPDATE_DIR,
-        _ => defs::MODULE_DIR,
-    });
-    let dir = std::fs::read_dir(modules_dir)?;
-    for entry in dir.flatten() {
-        let path = entry.path();
+    let modules_dir = match module_type {
+        ModuleType::Updated => defs::module_update_dir(),
+        _ => defs::module_dir(),
+    };
+    for path in sorted_module_entries(&modules_dir)? {
         if !path.is_dir() {
             warn!("{} is not a directory, skip", path.display());
             continue;
@@ -189,12 +268,7 @@ pub fn load_sepolicy_rule() -> Result<()> {
         }
 
         info!("load policy: {}", rule_file.display());
-        let mut _sepol = get_policy_main(&[
-            "magiskpolicy".to_string(),
-            "--live".to_string(),
-            "--apply".to_string(),
-            rule_file.display().to_string(),
-        ])?;
+        platform::get().apply_policy_rule_file(&rule_file)?;
 
         Ok(())
     })?;
@@ -203,13 +277,172 @@ pub fn load_sepolicy_rule() -> Result<()> {
 }
 
 pub fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
-    info!("exec {}", path.as_ref().display());
+    run_script(path, wait).map(|_| ())
+}
+
+/// Like [`exec_script`], but hands back the exit status when waiting
+pub fn run_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<Option<ExitStatus>> {
+    run_command(script_command(path.as_ref()), path.as_ref(), wait)
+}
+
+fn run_command(mut command: Command, path: &Path, wait: bool) -> Result<Option<ExitStatus>> {
+    info!("exec {}", path.display());
 
-    let is_module_script = path.as_ref().starts_with(defs::MODULE_DIR);
+    let result = if wait {
+        command.status().map(Some)
+    } else {
+        command.spawn().map(|_| None)
+    };
+    result.map_err(|err| anyhow!("Failed to exec {}: {}", path.display(), err))
+}
+
+/// Run a script to completion, killing its whole process group once `timeout` expires
+pub fn run_script_with_timeout<T: AsRef<Path>>(
+    path: T,
+    timeout: Option<Duration>,
+) -> Result<Outcome> {
+    run_command_with_timeout(script_command(path.as_ref()), path.as_ref(), timeout)
+}
+
+fn run_command_with_timeout(
+    mut command: Command,
+    path: &Path,
+    timeout: Option<Duration>,
+) -> Result<Outcome> {
+    let Some(timeout) = timeout else {
+        return run_command(command, path, true).map(Into::into);
+    };
+    info!("exec {} (timeout {}s)", path.display(), timeout.as_secs());
+
+    let child = command
+        .spawn()
+        .map_err(|err| anyhow!("Failed to exec {}: {}", path.display(), err))?;
+    wait_with_timeout(child, path, timeout)
+}
+
+fn wait_with_timeout(mut child: Child, path: &Path, timeout: Duration) -> Result<Outcome> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Outcome::Exited(status));
+        }
+        thread::sleep(SCRIPT_POLL_INTERVAL);
+    }
+
+    warn!(
+        "{} timed out after {}s, killing its process group",
+        path.display(),
+        timeout.as_secs()
+    );
+    // the script leads its own process group, so this takes its children down too
+    let pgid = child.id() as libc::pid_t;
+    unsafe { libc::kill(-pgid, libc::SIGTERM) };
+    let deadline = Instant::now() + SCRIPT_KILL_GRACE;
+    while Instant::now() < deadline && !has_exited(pgid) {
+        thread::sleep(SCRIPT_POLL_INTERVAL);
+    }
+    // the leader is not reaped yet, so the group id cannot have been reused
+    unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    let _ = child.wait();
+    Ok(Outcome::TimedOut(timeout))
+}
+
+/// Whether child `pid` has exited, without reaping it
+pub fn has_exited(pid: libc::pid_t) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    ret != 0 || unsafe { info.si_pid() } != 0
+}
+
+fn parse_timeout(value: &str) -> Option<Option<Duration>> {
+    let secs: u64 = value.trim().parse().ok()?;
+    Some((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+/// Timeout for a blocking stage script of `module`: module config first, then
+/// module.prop, then the global default. `None` means wait forever.
+pub fn script_timeout(module: Option<&Path>) -> Option<Duration> {
+    if let Some(module) = module {
+        let id = module.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let config = module_config::merge_configs(id).unwrap_or_default();
+        let prop = read_module_prop(module).unwrap_or_default();
+        if let Some(timeout) = config
+            .get(SCRIPT_TIMEOUT_KEY)
+            .or_else(|| prop.get(SCRIPT_TIMEOUT_KEY))
+            .and_then(|v| parse_timeout(v))
+        {
+            return timeout;
+        }
+    }
+
+    fs::read_to_string(defs::resolve(defs::SCRIPT_TIMEOUT_FILE))
+        .ok()
+        .and_then(|v| parse_timeout(&v))
+        .unwrap_or(Some(Duration::from_secs(DEFAULT_SCRIPT_TIMEOUT_SECS)))
+}
+
+/// Count consecutive stage script timeouts of a module and disable it once
+/// it keeps hanging boot
+fn track_script_timeout(module: &Path, timed_out: bool) {
+    let count_file = module.join(defs::TIMEOUT_COUNT_FILE_NAME);
+    if !timed_out {
+        let _ = fs::remove_file(count_file);
+        return;
+    }
+
+    let count = fs::read_to_string(&count_file)
+        .ok()
+        .and_then(|c| c.trim().parse::<u32>().ok())
+        .unwrap_or(0)
+        + 1;
+    if count < MAX_SCRIPT_TIMEOUTS {
+        if let Err(e) = fs::write(&count_file, count.to_string()) {
+            warn!("Failed to write {}: {e}", count_file.display());
+        }
+        return;
+    }
+
+    warn!(
+        "{} timed out {count} times in a row, disabling it",
+        module.display()
+    );
+    let _ = fs::remove_file(&count_file);
+    let id = module.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    if let Err(e) = disable_module(id, false) {
+        warn!("Failed to disable module {id}: {e}");
+    }
+}
+
+/// busybox leading its own process group, outside of apd's cgroups
+pub fn busybox_command() -> Command {
+    let mut command = Command::new(assets::busybox_path());
+    #[cfg(unix)]
+    {
+        command.process_group(0);
+        unsafe {
+            command.pre_exec(|| {
+                // ignore the error?
+                switch_cgroups();
+                Ok(())
+            });
+        }
+    }
+    command
+}
+
+fn script_command(path: &Path) -> Command {
+    let modules_dir = defs::module_dir();
+    let is_module_script = path.starts_with(&modules_dir);
     // Extract module_id from path if it matches /data/adb/modules/{id}/...
     let module_id = if is_module_script {
-        path.as_ref()
-            .strip_prefix(defs::MODULE_DIR)
+        path.strip_prefix(&modules_dir)
             .ok()
             .and_then(|p| p.components().next())
             .and_then(|c| c.as_os_str().to_str())
@@ -221,57 +454,98 @@ pub fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
     if is_module_script && module_id.is_none() {
         debug!(
             "Failed to extract module_id from script path '{}'. Script will run without AP_MODULE environment variable.",
-            path.as_ref().display()
+            path.display()
         );
     }
 
-    let mut command = &mut Command::new(assets::BUSYBOX_PATH);
-    #[cfg(unix)]
-    {
-        command = command.process_group(0);
-        command = unsafe {
-            command.pre_exec(|| {
-                // ignore the error?
-                switch_cgroups();
-                Ok(())
-            })
-        };
-    }
-    command = command
-        .current_dir(path.as_ref().parent().unwrap())
+    let mut command = busybox_command();
+    command
+        .current_dir(path.parent().unwrap())
         .arg("sh")
-        .arg(path.as_ref())
+        .arg(path)
         .envs(get_common_script_envs(module_id.as_deref()));
+    command
+}
 
-    let result = if wait {
-        command.status().map(|_| ())
-    } else {
-        command.spawn().map(|_| ())
-    };
-    result.map_err(|err| anyhow!("Failed to exec {}: {}", path.as_ref().display(), err))
+/// Run the `stage` script of module `id` with its output in the module's stage log
+pub fn run_stage_script(
+    id: &str,
+    stage: &str,
+    path: &Path,
+    block: bool,
+    timeout: Option<Duration>,
+) -> Result<Outcome> {
+    run_stage_script_with_envs(id, stage, path, &[], block, timeout)
+}
+
+/// [`run_stage_script`] with `envs` on top of the common script environment
+pub fn run_stage_script_with_envs(
+    id: &str,
+    stage: &str,
+    path: &Path,
+    envs: &[(&str, String)],
+    block: bool,
+    timeout: Option<Duration>,
+) -> Result<Outcome> {
+    let mut command = script_command(path);
+    command.envs(envs.iter().map(|(k, v)| (k, v)));
+    module_log::redirect(&mut command, id, stage);
+    match timeout {
+        Some(timeout) if block => info!("exec {} (timeout {}s)", path.display(), timeout.as_secs()),
+        _ => info!("exec {}", path.display()),
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|err| anyhow!("Failed to exec {}: {}", path.display(), err))?;
+    // whatever the script leaves behind can be found again by `module restart`
+    restart::track_process_group(id, stage, child.id());
+
+    match timeout {
+        _ if !block => Ok(Outcome::Spawned),
+        Some(timeout) => wait_with_timeout(child, path, timeout),
+        None => Ok(Outcome::Exited(child.wait()?)),
+    }
 }
 
-pub fn exec_stage_script(stage: &str, block: bool) -> Result<()> {
+pub fn exec_stage_script(stage: &str, block: bool, report: &mut StageReport) -> Result<()> {
+    let script_name = format!("{stage}.sh");
+    let mut jobs = Vec::new();
     foreach_active_module(|module| {
-        let script_path = module.join(format!("{stage}.sh"));
-        if !script_path.exists() {
+        let script = module.join(&script_name);
+        if !script.exists() {
             return Ok(());
         }
 
-        exec_script(&script_path, block)
+        let Some(id) = module.file_name().and_then(|n| n.to_str()) else {
+            return Ok(());
+        };
+        jobs.push(Job {
+            id: id.to_string(),
+            module: module.to_path_buf(),
+            script,
+        });
+        Ok(())
     })?;
+    scheduler::run_jobs(&jobs, block, &script_name, report, |job| {
+        if !block {
+            return run_stage_script(&job.id, stage, &job.script, false, None);
+        }
+        let timeout = script_timeout(Some(&job.module));
+        let outcome = run_stage_script(&job.id, stage, &job.script, true, timeout)?;
+        track_script_timeout(&job.module, matches!(outcome, Outcome::TimedOut(_)));
+        Ok(outcome)
+    });
     Ok(())
 }
 
-pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
-    let script_dir = Path::new(defs::ADB_DIR).join(dir);
+pub fn exec_common_scripts(dir: &str, wait: bool, report: &mut StageReport) -> Result<()> {
+    let script_dir = defs::adb_dir().join(dir);
     if !script_dir.exists() {
         info!("{} not exists, skip", script_dir.display());
         return Ok(());
     }
 
-    let dir = fs::read_dir(&script_dir)?;
-    for entry in dir.flatten() {
+    for entry in fs::read_dir(&script_dir)?.flatten() {
         let path = entry.path();
 
         if !is_executable(&path) {
@@ -279,7 +553,14 @@ pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
             continue;
         }
 
-        exec_script(path, wait)?;
+        let name = format!("{dir}/{}", entry.file_name().to_string_lossy());
+        if wait {
+            report.step(&name, None, || {
+                run_script_with_timeout(&path, script_timeout(None))
+            })?;
+        } else {
+            report.step(&name, None, || run_script(&path, false))?;
+        }
     }
 
     Ok(())
@@ -293,7 +574,7 @@ pub fn load_system_prop() -> Result<()> {
         }
         info!("load {} system.prop", module.display());
 
-        crate::resetprop::load_system_prop_file(&system_prop)?;
+        platform::get().load_prop_file(&system_prop)?;
 
         Ok(())
     })?;
@@ -335,21 +616,23 @@ pub fn prune_modules() -> Result<()> {
             warn!("Failed to exec uninstaller: {e}");
         }
 
-        // Clear module configs before removing module directory
-        if let Err(e) = module_config::clear_module_configs(module_id) {
-            warn!("Failed to clear configs for {module_id}: {e}");
-        }
-
-        // Finally remove the module directory
-        if let Err(e) = remove_dir_all(module) {
-            warn!("Failed to remove {}: {e}", module.display());
+        // Finally move the module and its configs to the trash
+        if let Err(e) = trash::move_to_trash(module_id, module) {
+            warn!("Failed to move {module_id} to trash: {e:#}");
+            if let Err(e) = module_config::clear_module_configs(module_id) {
+                warn!("Failed to clear configs for {module_id}: {e}");
+            }
+            if let Err(e) = remove_dir_all(module) {
+                warn!("Failed to remove {}: {e}", module.display());
+            }
         }
 
         Ok(())
     })?;
+    trash::purge_expired();
 
     // clean up metamodule record if none remain
-    let has_remaining = std::fs::read_dir(defs::MODULE_DIR)?
+    let has_remaining = std::fs::read_dir(defs::module_dir())?
         .filter_map(std::result::Result::ok)
         .any(|entry| entry.path().join("module.prop").exists());
 
@@ -360,39 +643,23 @@ pub fn prune_modules() -> Result<()> {
     Ok(())
 }
 
-fn _install_module(zip: &str) -> Result<()> {
-    ensure_boot_completed()?;
-
-    // print banner
-    println!(include_str!("./../../banner"));
-
-    assets::ensure_binaries().with_context(|| "binary missing")?;
-
-    // first check if workding dir is usable
-    ensure_dir_exists(defs::WORKING_DIR).with_context(|| "Failed to create working dir")?;
-    ensure_dir_exists(defs::BINARY_DIR).with_context(|| "Failed to create bin dir")?;
-
-    // read the module_id from zip
+/// Parse module.prop straight out of a module zip
+pub fn read_zip_module_prop(zip: &Path) -> Result<HashMap<String, String>> {
     let mut buffer: Vec<u8> = Vec::new();
-    let zip_path = PathBuf::from_str(zip)?;
-    let zip_path = zip_path.canonicalize()?;
-    {
-        let mut archive = zip::ZipArchive::new(fs::File::open(&zip_path)?)?;
-        archive.by_name("module.prop")?.read_to_end(&mut buffer)?;
-    }
+    let mut archive = zip::ZipArchive::new(fs::File::open(zip)?)?;
+    archive.by_name("module.prop")?.read_to_end(&mut buffer)?;
+
     let mut module_prop = HashMap::new();
     PropertiesIter::new_with_encoding(Cursor::new(buffer), encoding_rs::UTF_8).read_into(
         |k, v| {
             module_prop.insert(k, v);
         },
     )?;
-    info!("module prop: {:?}", module_prop);
-
-    let Some(module_id) = module_prop.get("id") else {
-        bail!("module id not found in module.prop!");
-    };
-    let module_id = module_id.trim();
+    Ok(module_prop)
+}
 
+/// Check a module id coming from outside, e.g. a zip or a backup
+pub fn validate_module_id(module_id: &str) -> Result<()> {
     // The id becomes a directory name under MODULE_DIR and is interpolated into
     // shell commands by the manager; reject path traversal at this trust boundary
     // (same rule as KernelSU and module_config.rs).
@@ -400,23 +667,119 @@ fn _install_module(zip: &str) -> Result<()> {
     if !id_re.is_match(module_id) {
         bail!("invalid module id: {module_id}");
     }
+    Ok(())
+}
+
+/// The validated id of a module about to be installed
+pub fn zip_module_id(module_prop: &HashMap<String, String>) -> Result<&str> {
+    let Some(module_id) = module_prop.get("id") else {
+        bail!("module id not found in module.prop!");
+    };
+    let module_id = module_id.trim();
+    validate_module_id(module_id)?;
+    Ok(module_id)
+}
+
+/// Whether the module needs mounting (has system/ dir and no skip_mount file)
+pub fn zip_needs_mount(zip: &Path) -> Result<bool> {
+    let archive = zip::ZipArchive::new(fs::File::open(zip)?)?;
+    let has_system = archive.file_names().any(|name| name.starts_with("system/"));
+    let has_skip_mount = archive.file_names().any(|name| name == "skip_mount");
+    Ok(has_system && !has_skip_mount)
+}
+
+/// Metamodule rules that keep a module from being installed right now
+pub enum InstallBlock {
+    /// A metamodule with a custom installer is disabled or has pending changes
+    MetamoduleBusy { disabled: bool },
+    /// Another metamodule is already installed
+    OtherMetamodule(String),
+}
+
+pub fn metamodule_install_block(
+    module_id: &str,
+    is_metamodule: bool,
+    needs_mount: bool,
+) -> Option<InstallBlock> {
+    if !is_metamodule {
+        if needs_mount && let Err(disabled) = metamodule::check_install_safety() {
+            return Some(InstallBlock::MetamoduleBusy { disabled });
+        }
+        return None;
+    }
+
+    if metamodule::has_metamodule()
+        && let Some(existing_path) = metamodule::get_metamodule_path()
+    {
+        let existing_id = read_module_prop(&existing_path)
+            .ok()
+            .and_then(|m| m.get("id").cloned())
+            .unwrap_or_else(|| "unknown".to_string());
+        if existing_id != module_id {
+            return Some(InstallBlock::OtherMetamodule(existing_id));
+        }
+    }
+    None
+}
+
+fn _install_module(zip: &str) -> Result<()> {
+    ensure_boot_completed()?;
+
+    // print banner
+    println!(include_str!("./../../banner"));
+
+    assets::ensure_binaries().with_context(|| "binary missing")?;
+
+    // first check if workding dir is usable
+    ensure_dir_exists(defs::working_dir()).with_context(|| "Failed to create working dir")?;
+    ensure_dir_exists(defs::binary_dir()).with_context(|| "Failed to create bin dir")?;
+
+    // verify, extract and install from a copy nobody else can write, so the
+    // zip cannot be swapped between the checks and the extraction
+    let zip_path = copy_zip(Path::new(zip))?;
+    let result = install_zip(&zip_path);
+    let _ = fs::remove_file(&zip_path);
+    result
+}
+
+/// Copy `zip` to a root-only file in the working dir
+fn copy_zip(zip: &Path) -> Result<PathBuf> {
+    let copy = defs::working_dir().join(format!("install-{}.zip", std::process::id()));
+    let _ = fs::remove_file(&copy);
+    let mut src =
+        fs::File::open(zip).with_context(|| format!("Failed to open {}", zip.display()))?;
+    let mut dst = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&copy)
+        .with_context(|| format!("Failed to create {}", copy.display()))?;
+    if let Err(e) = io::copy(&mut src, &mut dst) {
+        let _ = fs::remove_file(&copy);
+        return Err(e).with_context(|| format!("Failed to copy {}", zip.display()));
+    }
+    Ok(copy)
+}
+
+fn install_zip(zip_path: &Path) -> Result<()> {
+    let module_prop = read_zip_module_prop(zip_path)?;
+    info!("module prop: {:?}", module_prop);
+
+    let module_id = zip_module_id(&module_prop)?;
+
+    let signer = signature::check_install(zip_path)?;
+    deps::check_install(module_id, &module_prop)?;
 
     // Check if this module is a metamodule
     let is_metamodule = metamodule::is_metamodule(&module_prop);
 
-    // Check if module needs mounting (has system/ dir and no skip_mount file)
-    let needs_mount = {
-        let zip_file = fs::File::open(&zip_path)?;
-        let archive = zip::ZipArchive::new(zip_file)?;
-        let has_system = archive.file_names().any(|name| name.starts_with("system/"));
-        let has_skip_mount = archive.file_names().any(|name| name == "skip_mount");
-        has_system && !has_skip_mount
-    };
+    let needs_mount = zip_needs_mount(zip_path)?;
 
     // Check if it's safe to install regular module
-    if !is_metamodule
-        && needs_mount
-        && let Err(is_disabled) = metamodule::check_install_safety()
+    let block = metamodule_install_block(module_id, is_metamodule, needs_mount);
+    if let Some(InstallBlock::MetamoduleBusy {
+        disabled: is_disabled,
+    }) = block
     {
         println!("\n❌ Installation Blocked");
         println!("┌────────────────────────────────");
@@ -433,62 +796,71 @@ fn _install_module(zip: &str) -> Result<()> {
         bail!("Metamodule installation blocked");
     }
 
-    let modules_dir = Path::new(defs::MODULE_DIR);
-    let modules_update_dir = Path::new(defs::MODULE_UPDATE_DIR);
-    if !Path::new(modules_dir).exists() {
-        fs::create_dir(modules_dir).expect("Failed to create modules folder");
+    let modules_dir = defs::module_dir();
+    let modules_update_dir = defs::module_update_dir();
+    if !modules_dir.exists() {
+        fs::create_dir(&modules_dir).expect("Failed to create modules folder");
         let permissions = fs::Permissions::from_mode(0o700);
-        fs::set_permissions(modules_dir, permissions).expect("Failed to set permissions");
+        fs::set_permissions(&modules_dir, permissions).expect("Failed to set permissions");
     }
 
     if is_metamodule {
         info!("Installing metamodule: {module_id}");
 
         // Check if there's already a metamodule installed
-        if metamodule::has_metamodule()
-            && let Some(existing_path) = metamodule::get_metamodule_path()
-        {
-            let existing_id = read_module_prop(&existing_path)
-                .ok()
-                .and_then(|m| m.get("id").cloned())
-                .unwrap_or_else(|| "unknown".to_string());
-
-            if existing_id != module_id {
-                println!("\n❌ Installation Failed");
-                println!("┌────────────────────────────────");
-                println!("│ A metamodule is already installed");
-                println!("│   Current metamodule: {existing_id}");
-                println!("│");
-                println!("│ Only one metamodule can be active at a time.");
-                println!("│");
-                println!("│ To install this metamodule:");
-                println!("│   1. Uninstall the current metamodule");
-                println!("│   2. Reboot your device");
-                println!("│   3. Install the new metamodule");
-                println!("└─────────────────────────────────\n");
-                bail!("Cannot install multiple metamodules");
-            }
+        if let Some(InstallBlock::OtherMetamodule(existing_id)) = &block {
+            println!("\n❌ Installation Failed");
+            println!("┌────────────────────────────────");
+            println!("│ A metamodule is already installed");
+            println!("│   Current metamodule: {existing_id}");
+            println!("│");
+            println!("│ Only one metamodule can be active at a time.");
+            println!("│");
+            println!("│ To install this metamodule:");
+            println!("│   1. Uninstall the current metamodule");
+            println!("│   2. Reboot your device");
+            println!("│   3. Install the new metamodule");
+            println!("└─────────────────────────────────\n");
+            bail!("Cannot install multiple metamodules");
         }
     }
 
-    let module_dir = format!("{}{}", modules_dir.display(), module_id);
-    let _module_update_dir = format!("{}{}", modules_update_dir.display(), module_id);
-    info!("module dir: {}", module_dir);
-    if !Path::new(&module_dir.clone()).exists() {
-        fs::create_dir(module_dir.clone()).expect("Failed to create module folder");
+    let module_dir = modules_dir.join(module_id);
+    let _module_update_dir = modules_update_dir.join(module_id);
+    info!("module dir: {}", module_dir.display());
+    if !module_dir.exists() {
+        fs::create_dir(&module_dir).expect("Failed to create module folder");
         let permissions = fs::Permissions::from_mode(0o700);
-        fs::set_permissions(module_dir.clone(), permissions).expect("Failed to set permissions");
+        fs::set_permissions(&module_dir, permissions).expect("Failed to set permissions");
+    }
+    // unzip the image and move it to modules_update/<id> dir, replacing
+    // whatever was staged before
+    if _module_update_dir.exists() {
+        remove_dir_all(&_module_update_dir)?;
     }
-    // unzip the image and move it to modules_update/<id> dir
-    let file = fs::File::open(zip)?;
+    let file = fs::File::open(zip_path)?;
     let mut archive = zip::ZipArchive::new(file)?;
-    archive.extract(&_module_update_dir)?;
+    if let Err(e) = extract::extract(&mut archive, &_module_update_dir, &extract::Options::load()) {
+        let _ = remove_dir_all(&_module_update_dir);
+        return Err(e);
+    }
+    // only `apd module rollback` stages rollbacks
+    let _ = fs::remove_file(_module_update_dir.join(snapshot::ROLLBACK_MARKER));
 
     println!("- Running module installer");
-    exec_install_script(zip, is_metamodule, module_id)?;
+    exec_install_script(&zip_path.to_string_lossy(), is_metamodule, module_id)?;
+
+    for dir in [&_module_update_dir, &module_dir] {
+        if dir.exists() {
+            signature::record_signer(dir, signer.as_deref())?;
+        }
+    }
+    if _module_update_dir.exists() {
+        integrity::record(&_module_update_dir)?;
+    }
 
     // set permission and selinux context for $MOD/system
-    let module_system_dir = PathBuf::from(module_dir.clone()).join("system");
+    let module_system_dir = module_dir.join("system");
     if module_system_dir.exists() {
         #[cfg(unix)]
         fs::set_permissions(&module_system_dir, fs::Permissions::from_mode(0o755))?;
@@ -509,7 +881,7 @@ pub fn install_module(zip: &str) -> Result<()> {
     _install_module(zip)
 }
 
-pub fn _uninstall_module(id: &str, update_dir: &str) -> Result<()> {
+pub fn _uninstall_module(id: &str, update_dir: &Path) -> Result<()> {
     let dir = Path::new(update_dir);
     ensure!(dir.exists(), "No module installed");
 
@@ -538,8 +910,7 @@ pub fn _uninstall_module(id: &str, update_dir: &str) -> Result<()> {
     }
 
     // santity check
-    let target_module_path = format!("{update_dir}/{id}");
-    let target_module = Path::new(&target_module_path);
+    let target_module = update_dir.join(id);
     if target_module.exists() {
         let remove_file = target_module.join(defs::REMOVE_FILE_NAME);
         if !remove_file.exists() {
@@ -550,13 +921,14 @@ pub fn _uninstall_module(id: &str, update_dir: &str) -> Result<()> {
     let _ = mark_module_state(id, defs::REMOVE_FILE_NAME, true);
     Ok(())
 }
-pub fn uninstall_module(id: &str) -> Result<()> {
-    _uninstall_module(id, defs::MODULE_DIR)?;
+pub fn uninstall_module(id: &str, force: bool) -> Result<()> {
+    deps::check_uninstall(id, force)?;
+    _uninstall_module(id, &defs::module_dir())?;
     mark_update()?;
     Ok(())
 }
 
-pub fn _undo_uninstall_module(id: &str, update_dir: &str) -> Result<()> {
+pub fn _undo_uninstall_module(id: &str, update_dir: &Path) -> Result<()> {
     let dir = Path::new(update_dir);
     ensure!(dir.exists(), "No module installed");
 
@@ -592,7 +964,7 @@ pub fn _undo_uninstall_module(id: &str, update_dir: &str) -> Result<()> {
     Ok(())
 }
 pub fn undo_uninstall_module(id: &str) -> Result<()> {
-    _undo_uninstall_module(id, defs::MODULE_DIR)?;
+    _undo_uninstall_module(id, &defs::module_dir())?;
     mark_update()?;
     Ok(())
 }
@@ -620,8 +992,8 @@ pub fn read_module_prop(module_path: &Path) -> Result<HashMap<String, String>> {
 }
 
 pub fn run_action(id: &str) -> Result<()> {
-    let action_script_path = format!("/data/adb/modules/{}/action.sh", id);
-    if Path::new(&action_script_path).exists() {
+    let action_script_path = defs::module_dir().join(id).join(defs::MODULE_ACTION_SH);
+    if action_script_path.exists() {
         let _ = exec_script(&action_script_path, true);
     } else {
         //if no action.sh, try to run lua action
@@ -630,9 +1002,8 @@ pub fn run_action(id: &str) -> Result<()> {
     Ok(())
 }
 
-fn _change_module_state(module_dir: &str, mid: &str, enable: bool) -> Result<()> {
-    let src_module_path = format!("{module_dir}/{mid}");
-    let src_module = Path::new(&src_module_path);
+fn _change_module_state(module_dir: &Path, mid: &str, enable: bool) -> Result<()> {
+    let src_module = module_dir.join(mid);
     ensure!(src_module.exists(), "module: {} not found!", mid);
 
     let disable_path = src_module.join(defs::DISABLE_FILE_NAME);
@@ -652,37 +1023,71 @@ fn _change_module_state(module_dir: &str, mid: &str, enable: bool) -> Result<()>
 }
 
 pub fn _enable_module(id: &str, update_dir: &Path) -> Result<()> {
-    if let Some(module_dir_str) = update_dir.to_str() {
-        _change_module_state(module_dir_str, id, true)
-    } else {
-        info!("Enable module failed: Invalid path");
-        Err(anyhow::anyhow!("Invalid module directory"))
+    _change_module_state(update_dir, id, true)
+}
+
+pub fn enable_module(id: &str, now: bool) -> Result<()> {
+    let update_dir = defs::module_dir();
+    _enable_module(id, &update_dir)?;
+    if now {
+        run_toggle_hooks(id, true)?;
     }
+    Ok(())
 }
 
-pub fn enable_module(id: &str) -> Result<()> {
-    let update_dir = Path::new(defs::MODULE_DIR);
-    _enable_module(id, update_dir)?;
+/// Run the `on_enable` / `on_disable` hooks of a module right away: the
+/// `on_enable.sh` / `on_disable.sh` script, then the Lua callback of the same name
+fn run_toggle_hooks(id: &str, enable: bool) -> Result<()> {
+    let module = defs::module_dir().join(id);
+    let hook = if enable { "on_enable" } else { "on_disable" };
+    let mut ran = false;
+
+    let script = module.join(format!("{hook}.sh"));
+    if script.exists() {
+        ran = true;
+        let outcome = run_stage_script(id, hook, &script, true, script_timeout(Some(&module)))?;
+        match outcome {
+            Outcome::Exited(status) if !status.success() => {
+                bail!("{hook}.sh of {id} failed with {status}")
+            }
+            Outcome::TimedOut(timeout) => {
+                bail!("{hook}.sh of {id} timed out after {}s", timeout.as_secs())
+            }
+            _ => {}
+        }
+    }
+    ran |= lua::exec_module_hook(id, hook, ())
+        .with_context(|| format!("Lua {hook} of {id} failed"))?;
+
+    let live = read_module_prop(&module)
+        .ok()
+        .and_then(|props| props.get(LIVE_TOGGLE_KEY).cloned())
+        .is_some_and(|v| v.trim() == "true");
+    match (ran, live) {
+        (_, true) => println!("- {id} is live-toggleable, the change is in effect now"),
+        (true, false) => println!(
+            "- Ran {hook} hooks, {id} is not declared live-toggleable, reboot to fully apply"
+        ),
+        (false, false) => println!("- {id} has no {hook} hooks, reboot to apply"),
+    }
     Ok(())
 }
 
 pub fn _disable_module(id: &str, update_dir: &Path) -> Result<()> {
-    if let Some(module_dir_str) = update_dir.to_str() {
-        _change_module_state(module_dir_str, id, false)
-    } else {
-        info!("Disable module failed: Invalid path");
-        Err(anyhow::anyhow!("Invalid module directory"))
-    }
+    _change_module_state(update_dir, id, false)
 }
 
-pub fn disable_module(id: &str) -> Result<()> {
-    let module_dir = Path::new(defs::MODULE_DIR);
-    _disable_module(id, module_dir)?;
+pub fn disable_module(id: &str, now: bool) -> Result<()> {
+    let module_dir = defs::module_dir();
+    _disable_module(id, &module_dir)?;
+    if now {
+        run_toggle_hooks(id, false)?;
+    }
 
     Ok(())
 }
 
-pub fn _disable_all_modules(dir: &str) -> Result<()> {
+pub fn _disable_all_modules(dir: &Path) -> Result<()> {
     let dir = fs::read_dir(dir)?;
     for entry in dir.flatten() {
         let path = entry.path();
@@ -701,11 +1106,11 @@ pub fn disable_all_modules() -> Result<()> {
         return Ok(());
     }
     mark_update()?;
-    _disable_all_modules(defs::MODULE_DIR)?;
+    _disable_all_modules(&defs::module_dir())?;
     Ok(())
 }
 
-fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
+fn _list_modules(path: &Path) -> Vec<HashMap<String, String>> {
     // Load all module configs once to minimize I/O overhead
     let all_configs = match module_config::get_all_module_configs() {
         Ok(configs) => configs,
@@ -716,15 +1121,13 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
     };
 
     // first check enabled modules
-    let dir = fs::read_dir(path);
-    let Ok(dir) = dir else {
+    let Ok(entries) = sorted_module_entries(path) else {
         return Vec::new();
     };
 
     let mut modules: Vec<HashMap<String, String>> = Vec::new();
 
-    for entry in dir.flatten() {
-        let path = entry.path();
+    for path in entries {
         info!("path: {}", path.display());
         let module_prop = path.join("module.prop");
         if !module_prop.exists() {
@@ -749,7 +1152,7 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
         }
 
         if !module_prop_map.contains_key("id") || module_prop_map["id"].is_empty() {
-            match entry.file_name().to_str() {
+            match path.file_name().and_then(|n| n.to_str()) {
                 Some(id) => {
                     info!("Use dir name as module id: {}", id);
                     module_prop_map.insert("id".to_owned(), id.to_owned());
@@ -775,6 +1178,23 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
         module_prop_map.insert("remove".to_owned(), remove.to_string());
         module_prop_map.insert("web".to_owned(), web.to_string());
         module_prop_map.insert("action".to_owned(), action.to_string());
+        module_prop_map.insert(
+            "signer".to_owned(),
+            signature::read_signer(&path).unwrap_or_default(),
+        );
+        // position in which boot stages visit the module
+        module_prop_map.insert("order".to_owned(), modules.len().to_string());
+        module_prop_map.insert("priority".to_owned(), module_priority(&path).to_string());
+        // serialized so the manager can render a settings page
+        match config_schema::load(&module_prop_map["id"]) {
+            Ok(Some(schema)) => {
+                if let Ok(schema) = serde_json::to_string(&schema) {
+                    module_prop_map.insert("configSchema".to_owned(), schema);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("{e:#}"),
+        }
 
         // Apply module config overrides and extract managed features
         if let Some(module_id) = module_prop_map.get("id")
@@ -793,7 +1213,7 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
 }
 
 pub fn list_modules() -> Result<()> {
-    let modules = _list_modules(defs::MODULE_DIR);
+    let modules = _list_modules(&defs::module_dir());
     println!("{}", serde_json::to_string_pretty(&modules)?);
     Ok(())
 }
@@ -1,23 +1,45 @@
+//! Per-module config store
+//!
+//! Every module has a persist and a temp config (cleared at post-fs-data)
+//! under `WORKING_DIR/module_configs/<id>/`. Both use the `APTM` binary
+//! format. Version 2 stores a type tag and a modification time with every
+//! value and ends in a SHA-256 of everything before it. Version 1 files only
+//! hold strings; they are still read and get rewritten as version 2 on the
+//! next change. Binary values show up as hex wherever configs are handled as
+//! text.
+
 use std::{
     collections::HashMap,
+    fmt::Write as _,
     fs::{self, File},
-    io::{Read, Write},
-    path::{Path, PathBuf},
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, bail, ensure};
 use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::{defs, utils::ensure_dir_exists};
+use crate::{config_events, config_schema, defs, utils::ensure_dir_exists};
 
 #[allow(clippy::unreadable_literal)]
 const MODULE_CONFIG_MAGIC: u32 = 0x4150544D; // "APTM"
-const MODULE_CONFIG_VERSION: u32 = 1;
+const MODULE_CONFIG_VERSION_V1: u32 = 1;
+const MODULE_CONFIG_VERSION: u32 = 2;
+const CHECKSUM_LEN: usize = 32;
 
 // Validation limits
 pub const MAX_CONFIG_KEY_LEN: usize = 256;
-pub const MAX_CONFIG_VALUE_LEN: usize = 1024 * 1024; // 1MB
-pub const MAX_CONFIG_COUNT: usize = 32;
+// Per-module defaults, a module can change them with `limits` in its config schema
+pub const DEFAULT_MAX_VALUE_LEN: usize = 1024 * 1024; // 1MB
+pub const DEFAULT_MAX_CONFIG_COUNT: usize = 32;
+// Upper bounds of the per-module limits, also what loading a file enforces
+pub const HARD_MAX_VALUE_LEN: usize = 16 * 1024 * 1024; // 16MB
+pub const HARD_MAX_CONFIG_COUNT: usize = 1024;
+// Largest config file that gets written or read
+pub const HARD_MAX_CONFIG_FILE_LEN: usize = 64 * 1024 * 1024; // 64MB
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConfigType {
@@ -32,6 +54,177 @@ impl ConfigType {
             Self::Temp => defs::TEMP_CONFIG_NAME,
         }
     }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Persist => "persist",
+            Self::Temp => "temp",
+        }
+    }
+}
+
+/// Size limits of one module's config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    #[serde(default = "default_max_value_len")]
+    pub max_value_len: usize,
+}
+
+const fn default_max_entries() -> usize {
+    DEFAULT_MAX_CONFIG_COUNT
+}
+
+const fn default_max_value_len() -> usize {
+    DEFAULT_MAX_VALUE_LEN
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_entries: DEFAULT_MAX_CONFIG_COUNT,
+            max_value_len: DEFAULT_MAX_VALUE_LEN,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check(&self) -> Result<()> {
+        ensure!(
+            self.max_entries <= HARD_MAX_CONFIG_COUNT,
+            "max_entries {} exceeds {HARD_MAX_CONFIG_COUNT}",
+            self.max_entries
+        );
+        ensure!(
+            self.max_value_len <= HARD_MAX_VALUE_LEN,
+            "max_value_len {} exceeds {HARD_MAX_VALUE_LEN}",
+            self.max_value_len
+        );
+        Ok(())
+    }
+}
+
+/// Limits of module `module_id`, from its config schema if it sets any
+pub fn limits(module_id: &str) -> Limits {
+    match config_schema::load(module_id) {
+        Ok(schema) => schema.and_then(|s| s.limits).unwrap_or_default(),
+        Err(e) => {
+            warn!("{e:#}");
+            Limits::default()
+        }
+    }
+}
+
+/// A stored config value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+    Json(String),
+    Blob(Vec<u8>),
+}
+
+impl ConfigValue {
+    const fn tag(&self) -> u8 {
+        match self {
+            Self::String(_) => 0,
+            Self::Int(_) => 1,
+            Self::Bool(_) => 2,
+            Self::Json(_) => 3,
+            Self::Blob(_) => 4,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::String(s) | Self::Json(s) => s.as_bytes().to_vec(),
+            Self::Int(n) => n.to_le_bytes().to_vec(),
+            Self::Bool(b) => vec![u8::from(*b)],
+            Self::Blob(bytes) => bytes.clone(),
+        }
+    }
+
+    fn decode(tag: u8, data: &[u8]) -> Result<Self> {
+        let text = || String::from_utf8(data.to_vec()).context("Invalid UTF-8 in value");
+        Ok(match tag {
+            0 => Self::String(text()?),
+            1 => Self::Int(i64::from_le_bytes(
+                data.try_into().context("Invalid int value")?,
+            )),
+            2 => match data {
+                [0] => Self::Bool(false),
+                [1] => Self::Bool(true),
+                _ => bail!("Invalid bool value"),
+            },
+            3 => Self::Json(text()?),
+            4 => Self::Blob(data.to_vec()),
+            _ => bail!("Unknown value type {tag}"),
+        })
+    }
+
+    /// Text form: blobs become lowercase hex
+    pub fn to_text(&self) -> String {
+        match self {
+            Self::String(s) | Self::Json(s) => s.clone(),
+            Self::Int(n) => n.to_string(),
+            Self::Bool(b) => b.to_string(),
+            Self::Blob(bytes) => bytes.iter().fold(String::new(), |mut hex, b| {
+                let _ = write!(hex, "{b:02x}");
+                hex
+            }),
+        }
+    }
+
+    /// Parse `text`, as [`ConfigValue::to_text`] gives it, into a value of
+    /// the same type as `self`
+    pub fn retyped(&self, text: &str) -> Result<Self> {
+        Ok(match self {
+            Self::String(_) => Self::String(text.to_string()),
+            Self::Int(_) => Self::Int(text.parse().context("invalid int")?),
+            Self::Bool(_) => match text {
+                "true" => Self::Bool(true),
+                "false" => Self::Bool(false),
+                _ => bail!("invalid bool"),
+            },
+            Self::Json(_) => {
+                serde_json::from_str::<serde_json::Value>(text).context("invalid JSON")?;
+                Self::Json(text.to_string())
+            }
+            Self::Blob(_) => Self::Blob(decode_hex(text)?),
+        })
+    }
+}
+
+/// Bytes of the hex text form of a blob
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    ensure!(hex.len().is_multiple_of(2), "odd number of hex digits");
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .context("invalid hex digit")
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+    pub value: ConfigValue,
+    /// Unix time of the last change, 0 for values migrated from version 1
+    pub modified: u64,
+}
+
+impl ConfigEntry {
+    pub fn new(value: ConfigValue) -> Self {
+        let modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self { value, modified }
+    }
 }
 
 /// Validate config key
@@ -62,26 +255,27 @@ pub fn validate_config_key(key: &str) -> Result<()> {
 
 /// Validate config value
 /// Only enforces maximum length - no character restrictions
-/// Values are stored in binary format with length prefix, so any UTF-8 data is safe
-pub fn validate_config_value(value: &str) -> Result<()> {
-    if value.len() > MAX_CONFIG_VALUE_LEN {
+/// Values are stored in binary format with length prefix, so any data is safe
+pub fn validate_config_value(value: &[u8], limits: &Limits) -> Result<()> {
+    if value.len() > limits.max_value_len {
         bail!(
             "Config value too long: {} bytes (max: {})",
             value.len(),
-            MAX_CONFIG_VALUE_LEN
+            limits.max_value_len
         );
     }
 
-    // No character restrictions - binary storage format handles all UTF-8 safely
+    // No character restrictions - binary storage format handles all data safely
     Ok(())
 }
 
 /// Validate config count
-fn validate_config_count(config: &HashMap<String, String>) -> Result<()> {
-    if config.len() > MAX_CONFIG_COUNT {
+fn validate_config_count<V>(config: &HashMap<String, V>, limits: &Limits) -> Result<()> {
+    if config.len() > limits.max_entries {
         bail!(
-            "Too many config entries: {} (max: {MAX_CONFIG_COUNT})",
+            "Too many config entries: {} (max: {})",
             config.len(),
+            limits.max_entries
         );
     }
     Ok(())
@@ -89,11 +283,11 @@ fn validate_config_count(config: &HashMap<String, String>) -> Result<()> {
 
 /// Get the config directory path for a module
 fn get_config_dir(module_id: &str) -> PathBuf {
-    Path::new(defs::MODULE_CONFIG_DIR).join(module_id)
+    defs::module_config_dir().join(module_id)
 }
 
 /// Get the config file path for a module
-fn get_config_path(module_id: &str, config_type: ConfigType) -> PathBuf {
+pub fn get_config_path(module_id: &str, config_type: ConfigType) -> PathBuf {
     get_config_dir(module_id).join(config_type.filename())
 }
 
@@ -104,90 +298,151 @@ fn ensure_config_dir(module_id: &str) -> Result<PathBuf> {
     Ok(dir)
 }
 
-/// Load config from binary file
-pub fn load_config(module_id: &str, config_type: ConfigType) -> Result<HashMap<String, String>> {
-    let config_path = get_config_path(module_id, config_type);
+/// Bounds-checked little-endian reader over a loaded config file
+struct Reader<'a> {
+    data: &'a [u8],
+}
 
-    if !config_path.exists() {
-        debug!("Config file not found: {}", config_path.display());
-        return Ok(HashMap::new());
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(len <= self.data.len(), "Unexpected end of config file");
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
     }
 
-    let mut file = File::open(&config_path)
-        .with_context(|| format!("Failed to open config file: {}", config_path.display()))?;
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
 
-    // Read magic
-    let mut magic_buf = [0u8; 4];
-    file.read_exact(&mut magic_buf)
-        .with_context(|| "Failed to read magic")?;
-    let magic = u32::from_le_bytes(magic_buf);
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+}
+
+/// Parse a version 1 or 2 config file
+fn parse_config(data: &[u8]) -> Result<HashMap<String, ConfigEntry>> {
+    let mut reader = Reader { data };
 
+    // Read magic
+    let magic = reader.u32().context("Failed to read magic")?;
     if magic != MODULE_CONFIG_MAGIC {
         bail!("Invalid config magic: expected 0x{MODULE_CONFIG_MAGIC:08x}, got 0x{magic:08x}");
     }
 
     // Read version
-    let mut version_buf = [0u8; 4];
-    file.read_exact(&mut version_buf)
-        .with_context(|| "Failed to read version")?;
-    let version = u32::from_le_bytes(version_buf);
-
-    if version != MODULE_CONFIG_VERSION {
-        bail!("Unsupported config version: expected {MODULE_CONFIG_VERSION}, got {version}");
+    let version = reader.u32().context("Failed to read version")?;
+    match version {
+        MODULE_CONFIG_VERSION_V1 => {}
+        MODULE_CONFIG_VERSION => {
+            // Verify the checksum before trusting any length in the file
+            ensure!(
+                data.len() >= 8 + CHECKSUM_LEN,
+                "Config file too short for a checksum"
+            );
+            let (body, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+            if Sha256::digest(body).as_slice() != checksum {
+                bail!("Config checksum mismatch");
+            }
+            reader.data = &body[8..];
+        }
+        _ => bail!(
+            "Unsupported config version: expected {MODULE_CONFIG_VERSION_V1} or {MODULE_CONFIG_VERSION}, got {version}"
+        ),
     }
 
     // Read count
-    let mut count_buf = [0u8; 4];
-    file.read_exact(&mut count_buf)
-        .with_context(|| "Failed to read count")?;
-    let count = u32::from_le_bytes(count_buf);
+    let count = reader.u32().context("Failed to read count")?;
 
-    // The file is untrusted input: enforce the same limits as the write side
-    // before allocating, or a corrupted file could claim huge lengths and OOM
-    // the boot-time daemon.
-    if count as usize > MAX_CONFIG_COUNT {
-        bail!("Config entry count {count} exceeds max {MAX_CONFIG_COUNT}");
+    // The file is untrusted input: lengths it claims are checked before use
+    if count as usize > HARD_MAX_CONFIG_COUNT {
+        bail!("Config entry count {count} exceeds max {HARD_MAX_CONFIG_COUNT}");
     }
 
     // Read entries
     let mut config = HashMap::new();
     for i in 0..count {
-        // Read key length
-        let mut key_len_buf = [0u8; 4];
-        file.read_exact(&mut key_len_buf)
-            .with_context(|| format!("Failed to read key length for entry {i}"))?;
-        let key_len = u32::from_le_bytes(key_len_buf) as usize;
+        // Read key
+        let key_len = reader
+            .u32()
+            .with_context(|| format!("Failed to read key length for entry {i}"))?
+            as usize;
         if key_len > MAX_CONFIG_KEY_LEN {
             bail!("Config key length {key_len} exceeds max {MAX_CONFIG_KEY_LEN} for entry {i}");
         }
-
-        // Read key data
-        let mut key_buf = vec![0u8; key_len];
-        file.read_exact(&mut key_buf)
-            .with_context(|| format!("Failed to read key data for entry {i}"))?;
-        let key = String::from_utf8(key_buf)
-            .with_context(|| format!("Invalid UTF-8 in key for entry {i}"))?;
-
-        // Read value length
-        let mut value_len_buf = [0u8; 4];
-        file.read_exact(&mut value_len_buf)
-            .with_context(|| format!("Failed to read value length for entry {i}"))?;
-        let value_len = u32::from_le_bytes(value_len_buf) as usize;
-        if value_len > MAX_CONFIG_VALUE_LEN {
-            bail!(
-                "Config value length {value_len} exceeds max {MAX_CONFIG_VALUE_LEN} for entry {i}"
-            );
+        let key = String::from_utf8(
+            reader
+                .take(key_len)
+                .with_context(|| format!("Failed to read key data for entry {i}"))?
+                .to_vec(),
+        )
+        .with_context(|| format!("Invalid UTF-8 in key for entry {i}"))?;
+
+        // Read type and modification time, version 1 only has strings
+        let (tag, modified) = if version == MODULE_CONFIG_VERSION_V1 {
+            (0, 0)
+        } else {
+            let tag = reader
+                .u8()
+                .with_context(|| format!("Failed to read value type for entry {i}"))?;
+            let modified = reader
+                .u64()
+                .with_context(|| format!("Failed to read timestamp for entry {i}"))?;
+            (tag, modified)
+        };
+
+        // Read value
+        let value_len = reader
+            .u32()
+            .with_context(|| format!("Failed to read value length for entry {i}"))?
+            as usize;
+        if value_len > HARD_MAX_VALUE_LEN {
+            bail!("Config value length {value_len} exceeds max {HARD_MAX_VALUE_LEN} for entry {i}");
         }
-
-        // Read value data
-        let mut value_buf = vec![0u8; value_len];
-        file.read_exact(&mut value_buf)
+        let value_data = reader
+            .take(value_len)
             .with_context(|| format!("Failed to read value data for entry {i}"))?;
-        let value = String::from_utf8(value_buf)
-            .with_context(|| format!("Invalid UTF-8 in value for entry {i}"))?;
+        let value = ConfigValue::decode(tag, value_data)
+            .with_context(|| format!("Invalid value for entry {i}"))?;
 
-        config.insert(key, value);
+        config.insert(key, ConfigEntry { value, modified });
     }
+    ensure!(reader.data.is_empty(), "Trailing data after config entries");
+
+    Ok(config)
+}
+
+/// Load typed config entries from binary file
+pub fn load_entries(
+    module_id: &str,
+    config_type: ConfigType,
+) -> Result<HashMap<String, ConfigEntry>> {
+    let config_path = get_config_path(module_id, config_type);
+
+    if !config_path.exists() {
+        debug!("Config file not found: {}", config_path.display());
+        return Ok(HashMap::new());
+    }
+
+    // Check the size before reading, or a corrupted or planted file could
+    // make the boot-time daemon allocate all of it
+    let size = fs::metadata(&config_path)
+        .with_context(|| format!("Failed to stat config file: {}", config_path.display()))?
+        .len();
+    if size > HARD_MAX_CONFIG_FILE_LEN as u64 {
+        bail!(
+            "Config file {} is {size} bytes, max {HARD_MAX_CONFIG_FILE_LEN}",
+            config_path.display()
+        );
+    }
+    let data = fs::read(&config_path)
+        .with_context(|| format!("Failed to read config file: {}", config_path.display()))?;
+    let config = parse_config(&data)
+        .with_context(|| format!("Invalid config file: {}", config_path.display()))?;
 
     debug!(
         "Loaded {} entries from {}",
@@ -197,22 +452,57 @@ pub fn load_config(module_id: &str, config_type: ConfigType) -> Result<HashMap<S
     Ok(config)
 }
 
-/// Save config to binary file
-pub fn save_config(
-    module_id: &str,
-    config_type: ConfigType,
-    config: &HashMap<String, String>,
-) -> Result<()> {
-    // Validate config count
-    validate_config_count(config)?;
+/// Load config as text
+pub fn load_config(module_id: &str, config_type: ConfigType) -> Result<HashMap<String, String>> {
+    Ok(load_entries(module_id, config_type)?
+        .into_iter()
+        .map(|(key, entry)| (key, entry.value.to_text()))
+        .collect())
+}
 
-    // Validate all keys and values
-    for (key, value) in config {
+/// File content of `config` in the current version, keys and values
+/// validated against the module's limits
+pub fn encode_entries(module_id: &str, config: &HashMap<String, ConfigEntry>) -> Result<Vec<u8>> {
+    let limits = limits(module_id);
+
+    // Validate config count
+    validate_config_count(config, &limits)?;
+
+    // Sort keys so the same config always gives the same file
+    let mut keys: Vec<&String> = config.keys().collect();
+    keys.sort();
+
+    // Validate all keys and values while encoding them
+    let mut body = Vec::new();
+    body.extend_from_slice(&MODULE_CONFIG_MAGIC.to_le_bytes());
+    body.extend_from_slice(&MODULE_CONFIG_VERSION.to_le_bytes());
+    body.extend_from_slice(&(config.len() as u32).to_le_bytes());
+    for key in keys {
+        let entry = &config[key];
         validate_config_key(key).with_context(|| format!("Invalid config key: '{key}'"))?;
-        validate_config_value(value)
+        let value = entry.value.encode();
+        validate_config_value(&value, &limits)
             .with_context(|| format!("Invalid config value for key '{key}'"))?;
+
+        body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        body.extend_from_slice(key.as_bytes());
+        body.push(entry.value.tag());
+        body.extend_from_slice(&entry.modified.to_le_bytes());
+        body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        body.extend_from_slice(&value);
     }
+    ensure!(
+        body.len() + CHECKSUM_LEN <= HARD_MAX_CONFIG_FILE_LEN,
+        "Config of {} bytes exceeds max {HARD_MAX_CONFIG_FILE_LEN}",
+        body.len() + CHECKSUM_LEN
+    );
+    let checksum = Sha256::digest(&body);
+    body.extend_from_slice(&checksum);
+    Ok(body)
+}
 
+/// Replace a config file with `data`, as [`encode_entries`] gives it
+pub fn write_config(module_id: &str, config_type: ConfigType, data: &[u8]) -> Result<()> {
     ensure_config_dir(module_id)?;
 
     let config_path = get_config_path(module_id, config_type);
@@ -221,42 +511,8 @@ pub fn save_config(
     // Write to temporary file first
     let mut file = File::create(&temp_path)
         .with_context(|| format!("Failed to create temp config file: {}", temp_path.display()))?;
-
-    // Write magic
-    file.write_all(&MODULE_CONFIG_MAGIC.to_le_bytes())
-        .with_context(|| "Failed to write magic")?;
-
-    // Write version
-    file.write_all(&MODULE_CONFIG_VERSION.to_le_bytes())
-        .with_context(|| "Failed to write version")?;
-
-    // Write count
-    let count = config.len() as u32;
-    file.write_all(&count.to_le_bytes())
-        .with_context(|| "Failed to write count")?;
-
-    // Write entries
-    for (key, value) in config {
-        // Write key length
-        let key_bytes = key.as_bytes();
-        let key_len = key_bytes.len() as u32;
-        file.write_all(&key_len.to_le_bytes())
-            .with_context(|| format!("Failed to write key length for '{key}'"))?;
-
-        // Write key data
-        file.write_all(key_bytes)
-            .with_context(|| format!("Failed to write key data for '{key}'"))?;
-
-        // Write value length
-        let value_bytes = value.as_bytes();
-        let value_len = value_bytes.len() as u32;
-        file.write_all(&value_len.to_le_bytes())
-            .with_context(|| format!("Failed to write value length for '{key}'"))?;
-
-        // Write value data
-        file.write_all(value_bytes)
-            .with_context(|| format!("Failed to write value data for '{key}'"))?;
-    }
+    file.write_all(data)
+        .with_context(|| "Failed to write config entries")?;
 
     file.sync_all()
         .with_context(|| "Failed to sync config file")?;
@@ -268,16 +524,72 @@ pub fn save_config(
             temp_path.display(),
             config_path.display()
         )
-    })?;
+    })
+}
+
+/// Save typed config entries to binary file, always in the current version
+pub fn save_entries(
+    module_id: &str,
+    config_type: ConfigType,
+    config: &HashMap<String, ConfigEntry>,
+) -> Result<()> {
+    let data = encode_entries(module_id, config)?;
+    write_config(module_id, config_type, &data)?;
 
     debug!(
         "Saved {} entries to {}",
         config.len(),
-        config_path.display()
+        get_config_path(module_id, config_type).display()
     );
     Ok(())
 }
 
+/// Entry to store for `key` given as text. Unchanged text keeps the old
+/// entry, everything else is typed by the module schema, or keeps the type
+/// it had for keys the schema has no entry for.
+fn text_entry(
+    schema: Option<&config_schema::Schema>,
+    key: &str,
+    value: &str,
+    old: Option<ConfigEntry>,
+) -> Result<ConfigEntry> {
+    let schema_entry = schema.and_then(|s| s.entry(key));
+    Ok(match (old, schema_entry) {
+        (Some(entry), _) if entry.value.to_text() == value => entry,
+        (Some(entry), None) => ConfigEntry::new(
+            entry
+                .value
+                .retyped(value)
+                .with_context(|| format!("{key}: value does not fit its type"))?,
+        ),
+        (_, Some(schema_entry)) if schema_entry.kind == config_schema::EntryType::Blob => {
+            ConfigEntry::new(ConfigValue::Blob(
+                decode_hex(value).with_context(|| format!("{key}: bad blob"))?,
+            ))
+        }
+        _ => ConfigEntry::new(match schema {
+            Some(schema) => schema.typed_value(key, value)?,
+            None => ConfigValue::String(value.to_string()),
+        }),
+    })
+}
+
+/// Save config given as text, typed as [`text_entry`] does
+pub fn save_config(
+    module_id: &str,
+    config_type: ConfigType,
+    config: &HashMap<String, String>,
+) -> Result<()> {
+    let schema = config_schema::load(module_id)?;
+    let mut old = load_entries(module_id, config_type)?;
+    let mut entries = HashMap::new();
+    for (key, value) in config {
+        let entry = text_entry(schema.as_ref(), key, value, old.remove(key))?;
+        entries.insert(key.clone(), entry);
+    }
+    save_entries(module_id, config_type, &entries)
+}
+
 /// Get a single config value
 #[allow(dead_code)]
 pub fn get_config_value(
@@ -289,34 +601,125 @@ pub fn get_config_value(
     Ok(config.get(key).cloned())
 }
 
-/// Set a single config value
-pub fn set_config_value(
+/// Get a single entry, temp taking priority over persist
+pub fn get_config_entry(module_id: &str, key: &str) -> Result<Option<ConfigEntry>> {
+    for config_type in [ConfigType::Temp, ConfigType::Persist] {
+        if let Some(entry) = load_entries(module_id, config_type)?.remove(key) {
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+/// Store `value` under `key` and tell the module about the change
+fn set_config_entry(
     module_id: &str,
     key: &str,
-    value: &str,
+    value: ConfigValue,
     config_type: ConfigType,
 ) -> Result<()> {
     // Validate input early for better error messages
     validate_config_key(key)?;
-    validate_config_value(value)?;
+    validate_config_value(&value.encode(), &limits(module_id))?;
 
-    let mut config = load_config(module_id, config_type)?;
-    config.insert(key.to_string(), value.to_string());
+    let mut config = load_entries(module_id, config_type)?;
+    let old = config.get(key).map(|entry| entry.value.clone());
+    if old.as_ref() != Some(&value) {
+        config.insert(key.to_string(), ConfigEntry::new(value.clone()));
+    }
 
-    // Note: save_config will also validate, but this provides earlier feedback
-    save_config(module_id, config_type, &config)?;
+    // Note: save_entries will also validate, but this provides earlier feedback
+    save_entries(module_id, config_type, &config)?;
+    config_events::notify_change(
+        module_id,
+        config_type,
+        key,
+        old.map(|v| v.to_text()).as_deref(),
+        Some(&value.to_text()),
+    );
     Ok(())
 }
 
+/// Set a single config value given as text, typed as [`text_entry`] does
+pub fn set_config_value(
+    module_id: &str,
+    key: &str,
+    value: &str,
+    config_type: ConfigType,
+) -> Result<()> {
+    validate_config_key(key)?;
+    let schema = config_schema::load(module_id)?;
+    let old = load_entries(module_id, config_type)?.remove(key);
+    let entry = text_entry(schema.as_ref(), key, value, old)?;
+    set_config_entry(module_id, key, entry.value, config_type)
+}
+
+/// Set a single binary config value
+pub fn set_config_blob(
+    module_id: &str,
+    key: &str,
+    value: Vec<u8>,
+    config_type: ConfigType,
+) -> Result<()> {
+    validate_config_key(key)?;
+    if let Some(schema) = config_schema::load(module_id)? {
+        schema.check_blob(key)?;
+    }
+    set_config_entry(module_id, key, ConfigValue::Blob(value), config_type)
+}
+
 /// Delete a single config value
 pub fn delete_config_value(module_id: &str, key: &str, config_type: ConfigType) -> Result<()> {
-    let mut config = load_config(module_id, config_type)?;
+    let mut config = load_entries(module_id, config_type)?;
 
-    if config.remove(key).is_none() {
+    let Some(old) = config.remove(key) else {
         bail!("Key '{key}' not found in config");
+    };
+
+    save_entries(module_id, config_type, &config)?;
+    config_events::notify_change(
+        module_id,
+        config_type,
+        key,
+        Some(&old.value.to_text()),
+        None,
+    );
+    Ok(())
+}
+
+/// Tell the module about every key that differs between `old` and `new`
+pub fn notify_changes(
+    module_id: &str,
+    config_type: ConfigType,
+    old: &HashMap<String, String>,
+    new: &HashMap<String, String>,
+) {
+    for change in config_events::changes(config_type, old, new) {
+        config_events::notify_change(module_id, config_type, change.key, change.old, change.new);
+    }
+}
+
+/// Set (`Some`) and delete (`None`) several values with a single write, so
+/// either all of them are stored or none
+pub fn apply_config(
+    module_id: &str,
+    config_type: ConfigType,
+    changes: &[(String, Option<String>)],
+) -> Result<()> {
+    let old = load_config(module_id, config_type)?;
+    let mut config = old.clone();
+    for (key, value) in changes {
+        validate_config_key(key)?;
+        match value {
+            Some(value) => config.insert(key.clone(), value.clone()),
+            None => config.remove(key),
+        };
     }
 
     save_config(module_id, config_type, &config)?;
+    // reload to report the values the way the schema stored them
+    let new = load_config(module_id, config_type)?;
+    notify_changes(module_id, config_type, &old, &new);
     Ok(())
 }
 
@@ -325,9 +728,13 @@ pub fn clear_config(module_id: &str, config_type: ConfigType) -> Result<()> {
     let config_path = get_config_path(module_id, config_type);
 
     if config_path.exists() {
+        let old = load_config(module_id, config_type).unwrap_or_default();
         fs::remove_file(&config_path)
             .with_context(|| format!("Failed to remove config file: {}", config_path.display()))?;
         debug!("Cleared config: {}", config_path.display());
+        for (key, value) in &old {
+            config_events::notify_change(module_id, config_type, key, Some(value), None);
+        }
     }
 
     Ok(())
@@ -359,10 +766,22 @@ pub fn merge_configs(module_id: &str) -> Result<HashMap<String, String>> {
     Ok(merged)
 }
 
+/// Merged config with schema defaults for the keys that are not set. A broken
+/// schema only costs the defaults, as in `module list`.
+pub fn effective_config(module_id: &str) -> Result<HashMap<String, String>> {
+    let mut config = merge_configs(module_id)?;
+    match config_schema::load(module_id) {
+        Ok(Some(schema)) => schema.fill_defaults(&mut config),
+        Ok(None) => {}
+        Err(e) => warn!("{e:#}"),
+    }
+    Ok(config)
+}
+
 /// Get all module configs (for iteration)
 /// Loads all configs in a single pass to minimize I/O overhead
 pub fn get_all_module_configs() -> Result<HashMap<String, HashMap<String, String>>> {
-    let config_root = Path::new(defs::MODULE_CONFIG_DIR);
+    let config_root = defs::module_config_dir();
 
     if !config_root.exists() {
         return Ok(HashMap::new());
@@ -370,7 +789,7 @@ pub fn get_all_module_configs() -> Result<HashMap<String, HashMap<String, String
 
     let mut all_configs = HashMap::new();
 
-    for entry in fs::read_dir(config_root)
+    for entry in fs::read_dir(&config_root)
         .with_context(|| format!("Failed to read config directory: {}", config_root.display()))?
     {
         let entry = entry?;
@@ -400,7 +819,7 @@ pub fn get_all_module_configs() -> Result<HashMap<String, HashMap<String, String
 
 /// Clear all temporary configs (called during post-fs-data)
 pub fn clear_all_temp_configs() -> Result<()> {
-    let config_root = Path::new(defs::MODULE_CONFIG_DIR);
+    let config_root = defs::module_config_dir();
 
     if !config_root.exists() {
         debug!("Config directory does not exist, nothing to clear");
@@ -409,7 +828,7 @@ pub fn clear_all_temp_configs() -> Result<()> {
 
     let mut cleared_count = 0;
 
-    for entry in fs::read_dir(config_root)
+    for entry in fs::read_dir(&config_root)
         .with_context(|| format!("Failed to read config directory: {}", config_root.display()))?
     {
         let entry = entry?;
@@ -456,3 +875,161 @@ pub fn clear_module_configs(module_id: &str) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::{FakePlatform, with_fake};
+
+    fn header(version: u32, count: u32) -> Vec<u8> {
+        let mut data = MODULE_CONFIG_MAGIC.to_le_bytes().to_vec();
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&count.to_le_bytes());
+        data
+    }
+
+    fn v1(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = header(MODULE_CONFIG_VERSION_V1, entries.len() as u32);
+        for (key, value) in entries {
+            data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value.as_bytes());
+        }
+        data
+    }
+
+    fn with_checksum(mut body: Vec<u8>) -> Vec<u8> {
+        let checksum = Sha256::digest(&body);
+        body.extend_from_slice(&checksum);
+        body
+    }
+
+    fn saved(entries: &HashMap<String, ConfigEntry>) -> Vec<u8> {
+        save_entries("mod_a", ConfigType::Persist, entries).unwrap();
+        fs::read(get_config_path("mod_a", ConfigType::Persist)).unwrap()
+    }
+
+    fn error(data: &[u8]) -> String {
+        format!("{:#}", parse_config(data).unwrap_err())
+    }
+
+    #[test]
+    fn v2_round_trips_every_type() {
+        with_fake(FakePlatform::default(), |_| {
+            let entries: HashMap<String, ConfigEntry> = [
+                ("str", ConfigValue::String("héllo".to_string())),
+                ("int", ConfigValue::Int(-42)),
+                ("yes", ConfigValue::Bool(true)),
+                ("no", ConfigValue::Bool(false)),
+                ("json", ConfigValue::Json(r#"{"a":[1]}"#.to_string())),
+                ("blob", ConfigValue::Blob(vec![0, 0xff, 0x80])),
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (key, value))| {
+                let entry = ConfigEntry {
+                    value,
+                    modified: i as u64 + 1,
+                };
+                (key.to_string(), entry)
+            })
+            .collect();
+            let data = saved(&entries);
+            assert_eq!(data[4..8], MODULE_CONFIG_VERSION.to_le_bytes());
+            assert_eq!(parse_config(&data).unwrap(), entries);
+            // same config, same file
+            assert_eq!(saved(&entries), data);
+        });
+    }
+
+    #[test]
+    fn v1_is_read_and_rewritten_as_v2() {
+        with_fake(FakePlatform::default(), |_| {
+            let path = get_config_path("mod_a", ConfigType::Persist);
+            ensure_config_dir("mod_a").unwrap();
+            fs::write(&path, v1(&[("k1", "one"), ("k2", "")])).unwrap();
+
+            let entries = load_entries("mod_a", ConfigType::Persist).unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(
+                entries["k1"],
+                ConfigEntry {
+                    value: ConfigValue::String("one".to_string()),
+                    modified: 0,
+                }
+            );
+
+            let data = saved(&entries);
+            assert_eq!(data[4..8], MODULE_CONFIG_VERSION.to_le_bytes());
+            assert_eq!(parse_config(&data).unwrap(), entries);
+        });
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        with_fake(FakePlatform::default(), |_| {
+            let mut entries = HashMap::new();
+            entries.insert("key".to_string(), ConfigEntry::new(ConfigValue::Int(7)));
+            let data = saved(&entries);
+
+            let mut flipped = data.clone();
+            flipped[14] ^= 1;
+            assert!(error(&flipped).contains("checksum mismatch"));
+
+            assert!(parse_config(&data[..data.len() - 1]).is_err());
+            assert!(error(&data[..8]).contains("too short"));
+            assert!(error(&v1(&[("key", "value")])[..20]).contains("Unexpected end"));
+
+            let body = &data[..data.len() - CHECKSUM_LEN];
+            let mut trailing = body.to_vec();
+            trailing.push(0);
+            assert!(error(&with_checksum(trailing)).contains("Trailing data"));
+            let mut trailing = v1(&[("key", "value")]);
+            trailing.push(0);
+            assert!(error(&trailing).contains("Trailing data"));
+        });
+    }
+
+    #[test]
+    fn rejects_oversized_counts_and_lengths() {
+        let count = header(MODULE_CONFIG_VERSION_V1, HARD_MAX_CONFIG_COUNT as u32 + 1);
+        assert!(error(&count).contains("count"));
+
+        let mut key = header(MODULE_CONFIG_VERSION_V1, 1);
+        key.extend_from_slice(&(MAX_CONFIG_KEY_LEN as u32 + 1).to_le_bytes());
+        assert!(error(&key).contains("key length"));
+
+        let mut value = header(MODULE_CONFIG_VERSION, 1);
+        value.extend_from_slice(&3u32.to_le_bytes());
+        value.extend_from_slice(b"key");
+        value.push(0);
+        value.extend_from_slice(&0u64.to_le_bytes());
+        value.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(error(&with_checksum(value)).contains("value length"));
+    }
+
+    #[test]
+    fn set_and_save_type_text_alike() {
+        with_fake(FakePlatform::default(), |_| {
+            let stored = |key: &str| {
+                load_entries("mod_a", ConfigType::Persist).unwrap()[key]
+                    .value
+                    .clone()
+            };
+            set_config_entry("mod_a", "num", ConfigValue::Int(1), ConfigType::Persist).unwrap();
+            set_config_value("mod_a", "num", "2", ConfigType::Persist).unwrap();
+            assert_eq!(stored("num"), ConfigValue::Int(2));
+            assert!(set_config_value("mod_a", "num", "two", ConfigType::Persist).is_err());
+
+            let mut config = load_config("mod_a", ConfigType::Persist).unwrap();
+            config.insert("num".to_string(), "3".to_string());
+            config.insert("new".to_string(), "4".to_string());
+            save_config("mod_a", ConfigType::Persist, &config).unwrap();
+            assert_eq!(stored("num"), ConfigValue::Int(3));
+            assert_eq!(stored("new"), ConfigValue::String("4".to_string()));
+            config.insert("num".to_string(), "three".to_string());
+            assert!(save_config("mod_a", ConfigType::Persist, &config).is_err());
+        });
+    }
+}
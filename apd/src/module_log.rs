@@ -0,0 +1,151 @@
+//! Per-module stage logs
+//!
+//! Stage scripts and Lua hooks of a module write to
+//! `APATCH_LOG_FOLDER/modules/<id>/<stage>.log` instead of apd's own stdio.
+//! A log that grew past `MAX_LOG_SIZE` is rotated to `<stage>.log.1`,
+//! `<stage>.log.2`, ... when the stage starts again, keeping
+//! `MAX_ROTATED_LOGS` old copies. The supervisor trims the logs of the services
+//! it runs while they are running, other scripts that keep running in the
+//! background only get rotated on the next boot.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::warn;
+
+use crate::{defs, module::validate_module_id, utils::ensure_dir_exists};
+
+const MAX_LOG_SIZE: u64 = 256 << 10;
+const MAX_ROTATED_LOGS: usize = 2;
+
+fn module_log_dir(id: &str) -> PathBuf {
+    defs::resolve(defs::MODULE_LOG_FOLDER).join(id)
+}
+
+pub fn log_path(id: &str, stage: &str) -> PathBuf {
+    module_log_dir(id).join(format!("{stage}.log"))
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn too_big(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.len() >= MAX_LOG_SIZE)
+}
+
+/// Make room for a new `<stage>.log.1`
+fn shift_rotated(path: &Path) -> io::Result<()> {
+    for n in (1..MAX_ROTATED_LOGS).rev() {
+        let from = rotated(path, n);
+        if from.exists() {
+            fs::rename(&from, rotated(path, n + 1))?;
+        }
+    }
+    Ok(())
+}
+
+fn rotate(path: &Path) -> io::Result<()> {
+    if !too_big(path) {
+        return Ok(());
+    }
+    shift_rotated(path)?;
+    fs::rename(path, rotated(path, 1))
+}
+
+/// Rotate the log of `stage` while a process still writes to it: copy it to
+/// `<stage>.log.1` and truncate it, the writer appends at the new end. Output
+/// written in between is lost.
+pub fn trim(id: &str, stage: &str) -> Result<()> {
+    let path = log_path(id, stage);
+    if !too_big(&path) {
+        return Ok(());
+    }
+    shift_rotated(&path).with_context(|| format!("Failed to rotate {}", path.display()))?;
+    fs::copy(&path, rotated(&path, 1))
+        .with_context(|| format!("Failed to rotate {}", path.display()))?;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_len(0))
+        .with_context(|| format!("Failed to truncate {}", path.display()))
+}
+
+/// Open the log of `stage` for appending, rotating it first if it is too big
+pub fn open(id: &str, stage: &str) -> Result<fs::File> {
+    let path = log_path(id, stage);
+    ensure_dir_exists(module_log_dir(id))?;
+    rotate(&path).with_context(|| format!("Failed to rotate {}", path.display()))?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    writeln!(file, "--- {stage} at {now}")?;
+    Ok(file)
+}
+
+/// Send stdout and stderr of `command` to the stage log of module `id`.
+/// Output stays on apd's stdio if the log cannot be opened.
+pub fn redirect(command: &mut Command, id: &str, stage: &str) {
+    let log = open(id, stage).and_then(|log| Ok((log.try_clone()?, log)));
+    match log {
+        Ok((stdout, stderr)) => {
+            command.stdout(stdout).stderr(stderr);
+        }
+        Err(e) => warn!("Failed to open {stage} log of {id}: {e:#}"),
+    }
+}
+
+/// `apd module logs <id> [--stage <stage>]`
+pub fn print_logs(id: &str, stage: Option<&str>) -> Result<()> {
+    validate_module_id(id)?;
+    let dir = module_log_dir(id);
+
+    let logs = match stage {
+        Some(stage) => {
+            ensure!(
+                !stage.is_empty() && !stage.contains(['/', '.']),
+                "invalid stage: {stage}"
+            );
+            vec![log_path(id, stage)]
+        }
+        None => {
+            let mut logs: Vec<PathBuf> = fs::read_dir(&dir)
+                .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+                .unwrap_or_default();
+            logs.retain(|path| path.extension().is_some_and(|ext| ext == "log"));
+            logs.sort();
+            logs
+        }
+    };
+    if logs.is_empty() || !logs.iter().any(|path| path.exists()) {
+        bail!("no logs of {id}");
+    }
+
+    let mut stdout = io::stdout().lock();
+    let single = logs.len() == 1;
+    for path in logs {
+        let Ok(mut file) = fs::File::open(&path) else {
+            continue;
+        };
+        if !single {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            writeln!(stdout, "==> {name} <==")?;
+        }
+        io::copy(&mut file, &mut stdout)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,252 @@
+//! Module updates through the `updateJson=` field of module.prop
+//!
+//! The update json follows the Magisk format:
+//! `{"version": "v2", "versionCode": 2, "zipUrl": "...", "changelog": "..."}`.
+//! Downloads go through a [`Fetcher`]; on device that is busybox wget, while
+//! `APD_UPDATE_MIRROR=<dir>` serves every url from a local directory laid out
+//! as `<dir>/<host>/<path>`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets, defs,
+    module::{self, ModuleType, foreach_module, read_module_prop},
+};
+
+const MIRROR_ENV: &str = "APD_UPDATE_MIRROR";
+const FETCH_TIMEOUT_SECS: &str = "30";
+
+pub trait Fetcher {
+    fn fetch(&self, url: &str) -> Result<Vec<u8>>;
+}
+
+/// Fetches over the network with busybox wget, only http and https urls
+pub struct HttpFetcher;
+
+impl Fetcher for HttpFetcher {
+    fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        // local files only come from a mirror, never from a module's updateJson
+        ensure!(
+            url.starts_with("https://") || url.starts_with("http://"),
+            "Unsupported url {url}"
+        );
+        let output = Command::new(assets::busybox_path())
+            .args(["wget", "-q", "-T", FETCH_TIMEOUT_SECS, "-O", "-", url])
+            .output()
+            .with_context(|| format!("Failed to run wget for {url}"))?;
+        ensure!(
+            output.status.success(),
+            "Failed to fetch {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(output.stdout)
+    }
+}
+
+/// Serves urls from a local mirror directory
+pub struct MirrorFetcher {
+    root: PathBuf,
+}
+
+impl MirrorFetcher {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn local_path(&self, url: &str) -> PathBuf {
+        let path = url.split_once("://").map_or(url, |(_, rest)| rest);
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        self.root.join(path.trim_start_matches('/'))
+    }
+}
+
+impl Fetcher for MirrorFetcher {
+    fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        let path = self.local_path(url);
+        fs::read(&path).with_context(|| format!("Failed to read {} for {url}", path.display()))
+    }
+}
+
+pub fn fetcher() -> Box<dyn Fetcher> {
+    match std::env::var_os(MIRROR_ENV) {
+        Some(dir) => Box::new(MirrorFetcher::new(dir)),
+        None => Box::new(HttpFetcher),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateJson {
+    version: String,
+    version_code: i64,
+    zip_url: String,
+    #[serde(default)]
+    changelog: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateStatus {
+    pub id: String,
+    pub version: String,
+    pub version_code: i64,
+    pub update_available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest_version_code: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zip_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changelog: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn fetch_update_json(fetcher: &dyn Fetcher, url: &str) -> Result<UpdateJson> {
+    let content = fetcher.fetch(url)?;
+    serde_json::from_slice(&content).with_context(|| format!("Invalid update json at {url}"))
+}
+
+/// Compare an installed module against its updateJson.
+/// Returns `None` for modules that do not publish updates.
+fn check_module(
+    fetcher: &dyn Fetcher,
+    module: &Path,
+) -> Option<(UpdateStatus, Option<UpdateJson>)> {
+    let props = read_module_prop(module).ok()?;
+    let url = props
+        .get("updateJson")
+        .map(|u| u.trim())
+        .filter(|u| !u.is_empty())?;
+    let id = props
+        .get("id")
+        .cloned()
+        .or_else(|| Some(module.file_name()?.to_str()?.to_string()))?;
+
+    let mut status = UpdateStatus {
+        id,
+        version: props.get("version").cloned().unwrap_or_default(),
+        version_code: props
+            .get("versionCode")
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0),
+        update_available: false,
+        latest_version: None,
+        latest_version_code: None,
+        zip_url: None,
+        changelog: None,
+        error: None,
+    };
+    match fetch_update_json(fetcher, url) {
+        Ok(update) => {
+            status.update_available = update.version_code > status.version_code;
+            status.latest_version = Some(update.version.clone());
+            status.latest_version_code = Some(update.version_code);
+            status.zip_url = Some(update.zip_url.clone());
+            status.changelog = update.changelog.clone();
+            Some((status, Some(update)))
+        }
+        Err(e) => {
+            warn!("check update for {} failed: {e:#}", module.display());
+            status.error = Some(format!("{e:#}"));
+            Some((status, None))
+        }
+    }
+}
+
+pub fn check_updates(fetcher: &dyn Fetcher) -> Result<Vec<UpdateStatus>> {
+    let mut statuses = Vec::new();
+    foreach_module(ModuleType::All, |module| {
+        if module.join(defs::REMOVE_FILE_NAME).exists() {
+            return Ok(());
+        }
+        if let Some((status, _)) = check_module(fetcher, module) {
+            statuses.push(status);
+        }
+        Ok(())
+    })?;
+    Ok(statuses)
+}
+
+/// `apd module check-updates [--json]`
+pub fn print_updates(json: bool) -> Result<()> {
+    let statuses = check_updates(fetcher().as_ref())?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+        return Ok(());
+    }
+
+    if statuses.is_empty() {
+        println!("No module provides an updateJson");
+    }
+    for status in &statuses {
+        match (&status.error, status.update_available) {
+            (Some(error), _) => println!("{}: check failed: {error}", status.id),
+            (None, true) => {
+                println!(
+                    "{}: {} ({}) -> {} ({})",
+                    status.id,
+                    status.version,
+                    status.version_code,
+                    status.latest_version.as_deref().unwrap_or_default(),
+                    status.latest_version_code.unwrap_or_default()
+                );
+                if let Some(changelog) = &status.changelog {
+                    println!("  changelog: {changelog}");
+                }
+            }
+            (None, false) => println!("{}: up to date ({})", status.id, status.version),
+        }
+    }
+    Ok(())
+}
+
+/// `apd module update <id>`: download the latest zip and install it
+pub fn update_module(id: &str) -> Result<()> {
+    module::validate_module_id(id)?;
+    let fetcher = fetcher();
+    let module = defs::module_dir().join(id);
+    ensure!(module.exists(), "module: {id} not found!");
+
+    let Some((status, update)) = check_module(fetcher.as_ref(), &module) else {
+        bail!("{id} has no updateJson in module.prop");
+    };
+    let Some(update) = update else {
+        bail!("{}", status.error.unwrap_or_default());
+    };
+    ensure!(
+        status.update_available,
+        "{id} is already up to date ({})",
+        status.version
+    );
+
+    info!(
+        "updating {id} to {} ({})",
+        update.version, update.version_code
+    );
+    let zip = fetcher.fetch(&update.zip_url)?;
+    let zip_path = defs::working_dir().join(format!("{id}.update.zip"));
+    fs::write(&zip_path, zip).with_context(|| format!("Failed to write {}", zip_path.display()))?;
+
+    let result = check_zip_id(&zip_path, id)
+        .and_then(|()| module::install_module(&zip_path.to_string_lossy()));
+    let _ = fs::remove_file(&zip_path);
+    result
+}
+
+/// An update must not install over, or as, another module
+fn check_zip_id(zip: &Path, id: &str) -> Result<()> {
+    let module_prop = module::read_zip_module_prop(zip)?;
+    let zip_id = module::zip_module_id(&module_prop)?;
+    ensure!(zip_id == id, "update zip of {id} is for module {zip_id}");
+    Ok(())
+}
@@ -9,6 +9,8 @@ use std::{
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
+use crate::defs;
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct PackageConfig {
     pub pkg: String,
@@ -22,7 +24,7 @@ pub struct PackageConfig {
 pub fn read_ap_package_config() -> Vec<PackageConfig> {
     let max_retry = 5;
     for _ in 0..max_retry {
-        let file = match File::open("/data/adb/ap/package_config") {
+        let file = match File::open(defs::resolve(defs::PACKAGE_CONFIG_PATH)) {
             Ok(file) => file,
             Err(e) => {
                 warn!("Error opening file: {}", e);
@@ -56,9 +58,10 @@ pub fn read_ap_package_config() -> Vec<PackageConfig> {
 
 pub fn write_ap_package_config(package_configs: &[PackageConfig]) -> io::Result<()> {
     let max_retry = 5;
+    let config_path = defs::resolve(defs::PACKAGE_CONFIG_PATH);
+    let temp_path = config_path.with_extension("tmp");
     for _ in 0..max_retry {
-        let temp_path = "/data/adb/ap/package_config.tmp";
-        let file = match File::create(temp_path) {
+        let file = match File::create(&temp_path) {
             Ok(file) => file,
             Err(e) => {
                 warn!("Error creating temp file: {}", e);
@@ -89,7 +92,7 @@ pub fn write_ap_package_config(package_configs: &[PackageConfig]) -> io::Result<
             continue;
         }
 
-        if let Err(e) = std::fs::rename(temp_path, "/data/adb/ap/package_config") {
+        if let Err(e) = std::fs::rename(&temp_path, &config_path) {
             warn!("Error renaming temp file: {}", e);
             thread::sleep(Duration::from_secs(1));
             continue;
@@ -111,7 +114,7 @@ pub fn synchronize_package_uid() -> io::Result<()> {
 
     let max_retry = 5;
     for _ in 0..max_retry {
-        match read_lines("/data/system/packages.list") {
+        match read_lines(defs::resolve(defs::SYSTEM_PACKAGES_LIST)) {
             Ok(lines) => {
                 // Skip bad lines instead of `map_while(Result::ok)`: truncating
                 // at the first error would drop the tail of the list, and the
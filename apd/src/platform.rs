@@ -0,0 +1,528 @@
+//! Platform backend
+//!
+//! Everything apd asks of Android itself (system properties, SELinux policy,
+//! KernelPatch supercalls, kernel modules and init/framework control) goes
+//! through the [`Platform`] trait. [`AndroidPlatform`] talks to the real
+//! device, [`FakePlatform`] keeps all state in memory and records every side
+//! effect, so the boot stages can run on a plain Linux host against a sandbox
+//! root (see `defs::root`).
+//!
+//! The backend is picked once per process: `APD_PLATFORM=fake` selects the
+//! fake, anything else the Android backend.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CStr,
+    fs,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Mutex, OnceLock},
+};
+
+#[cfg(test)]
+use std::{os::unix::fs::PermissionsExt, sync::PoisonError};
+
+use anyhow::{Context, Result, ensure};
+use libc::{c_long, uid_t};
+use log::info;
+
+#[cfg(test)]
+use crate::defs;
+use crate::{insmod, resetprop, sepolicy, supercall, supercall::SuProfile, utils};
+
+pub const PLATFORM_ENV: &str = "APD_PLATFORM";
+
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+const KERNEL_MODULE_SYSFS: &str = "/sys/module/";
+
+pub trait Platform: Send + Sync {
+    // System properties
+    fn getprop(&self, name: &str) -> Option<String>;
+    /// Set a property bypassing the property service (like `resetprop -n`)
+    fn setprop(&self, name: &str, value: &str) -> Result<()>;
+    /// Load a `system.prop` style file (like `resetprop -n --file`)
+    fn load_prop_file(&self, path: &Path) -> Result<()>;
+
+    // SELinux
+    /// Patch the live policy with the built-in Magisk rules
+    fn apply_magisk_policy(&self) -> Result<()>;
+    /// Patch the live policy with the statements in a `sepolicy.rule` file
+    fn apply_policy_rule_file(&self, path: &Path) -> Result<()>;
+    fn set_selinux_enforcing(&self, enforcing: bool) -> Result<()>;
+
+    // KernelPatch supercalls, returning the raw syscall result
+    fn su(&self, key: &CStr, profile: &SuProfile) -> c_long;
+    fn su_get_safemode(&self, key: &CStr) -> c_long;
+    fn su_reset_path(&self, key: &CStr, path: &CStr) -> c_long;
+    fn su_grant_uid(&self, key: &CStr, profile: &SuProfile) -> c_long;
+    fn su_revoke_uid(&self, key: &CStr, uid: uid_t) -> c_long;
+    fn su_uid_nums(&self, key: &CStr) -> c_long;
+    fn su_allow_uids(&self, key: &CStr, buf: &mut [uid_t]) -> c_long;
+    fn set_ap_mod_exclude(&self, key: &CStr, uid: i64, exclude: i32) -> c_long;
+    /// Notify KernelPatch about a boot event (`truncate <key> event <event> <state>`)
+    fn report_event(&self, superkey: Option<&str>, event: &str, state: &str) -> Result<()>;
+
+    // Kernel modules
+    fn is_kernel_module_loaded(&self, name: &str) -> bool;
+    fn load_kernel_module(&self, path: &Path, params: &[String]) -> Result<()>;
+
+    // Process, init and framework control
+    /// Start capturing logcat and dmesg of this boot into `log_dir`
+    fn start_log_capture(&self, log_dir: &Path) -> Result<()>;
+    fn daemonize(&self) -> Result<()>;
+    fn switch_mnt_ns(&self, pid: i32) -> Result<()>;
+    /// `stop`: tear down the Android framework
+    fn stop_framework(&self) -> Result<()>;
+    /// `start`: bring the Android framework back up
+    fn start_framework(&self) -> Result<()>;
+    /// Run an activity manager command (`am <args>`)
+    fn am(&self, args: &[&str]) -> Result<()>;
+}
+
+static PLATFORM: OnceLock<Box<dyn Platform>> = OnceLock::new();
+
+/// The platform backend of this process
+pub fn get() -> &'static dyn Platform {
+    #[cfg(test)]
+    if let Some(fake) = *TEST_PLATFORM.lock().unwrap_or_else(PoisonError::into_inner) {
+        return fake;
+    }
+    PLATFORM
+        .get_or_init(|| match std::env::var(PLATFORM_ENV).as_deref() {
+            Ok("fake") => {
+                info!("using fake platform backend");
+                Box::new(FakePlatform::default())
+            }
+            _ => Box::new(AndroidPlatform),
+        })
+        .as_ref()
+}
+
+fn init_command(name: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(name)
+        .args(args)
+        .status()
+        .with_context(|| format!("{name} failed"))?;
+    ensure!(status.success(), "{name} exited with status: {status}");
+    Ok(())
+}
+
+/// The real device
+pub struct AndroidPlatform;
+
+impl Platform for AndroidPlatform {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn getprop(&self, name: &str) -> Option<String> {
+        android_properties::getprop(name).value()
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn getprop(&self, _name: &str) -> Option<String> {
+        unimplemented!()
+    }
+
+    fn setprop(&self, name: &str, value: &str) -> Result<()> {
+        resetprop::set_prop(name, value)
+    }
+
+    fn load_prop_file(&self, path: &Path) -> Result<()> {
+        resetprop::load_system_prop_file(path)
+    }
+
+    fn apply_magisk_policy(&self) -> Result<()> {
+        sepolicy::apply_magisk_policy_live()
+    }
+
+    fn apply_policy_rule_file(&self, path: &Path) -> Result<()> {
+        sepolicy::get_policy_main(&[
+            "magiskpolicy".to_string(),
+            "--live".to_string(),
+            "--apply".to_string(),
+            path.display().to_string(),
+        ])?;
+        Ok(())
+    }
+
+    fn set_selinux_enforcing(&self, enforcing: bool) -> Result<()> {
+        fs::write(SELINUX_ENFORCE, if enforcing { "1" } else { "0" })
+            .with_context(|| format!("Failed to write {SELINUX_ENFORCE}"))
+    }
+
+    fn su(&self, key: &CStr, profile: &SuProfile) -> c_long {
+        supercall::sc_su(key, profile)
+    }
+
+    fn su_get_safemode(&self, key: &CStr) -> c_long {
+        supercall::sc_su_get_safemode(key)
+    }
+
+    fn su_reset_path(&self, key: &CStr, path: &CStr) -> c_long {
+        supercall::sc_su_reset_path(key, path)
+    }
+
+    fn su_grant_uid(&self, key: &CStr, profile: &SuProfile) -> c_long {
+        supercall::sc_su_grant_uid(key, profile)
+    }
+
+    fn su_revoke_uid(&self, key: &CStr, uid: uid_t) -> c_long {
+        supercall::sc_su_revoke_uid(key, uid)
+    }
+
+    fn su_uid_nums(&self, key: &CStr) -> c_long {
+        supercall::sc_su_uid_nums(key)
+    }
+
+    fn su_allow_uids(&self, key: &CStr, buf: &mut [uid_t]) -> c_long {
+        supercall::sc_su_allow_uids(key, buf)
+    }
+
+    fn set_ap_mod_exclude(&self, key: &CStr, uid: i64, exclude: i32) -> c_long {
+        supercall::sc_set_ap_mod_exclude(key, uid, exclude)
+    }
+
+    fn report_event(&self, superkey: Option<&str>, event: &str, state: &str) -> Result<()> {
+        let args = [superkey.unwrap_or("su"), "event", event, state];
+        utils::run_command("truncate", &args, None)?.wait()?;
+        Ok(())
+    }
+
+    fn is_kernel_module_loaded(&self, name: &str) -> bool {
+        Path::new(KERNEL_MODULE_SYSFS).join(name).exists()
+    }
+
+    fn load_kernel_module(&self, path: &Path, params: &[String]) -> Result<()> {
+        insmod::insmod(path, params)
+    }
+
+    fn start_log_capture(&self, log_dir: &Path) -> Result<()> {
+        let logcat_path = log_dir.join("logcat.log").display().to_string();
+        let bootlog = fs::File::create(log_dir.join("dmesg.log"))?;
+        let logcat = [
+            "-s",
+            "9",
+            "45s",
+            "logcat",
+            "-b",
+            "main,system,crash",
+            "DrmLibFs:S",
+            "-f",
+            &logcat_path,
+            "logcatcher-bootlog:S",
+        ];
+        let _ = unsafe {
+            Command::new("timeout")
+                .process_group(0)
+                .pre_exec(|| {
+                    utils::switch_cgroups();
+                    Ok(())
+                })
+                .args(logcat)
+                .spawn()
+        };
+        let _ = unsafe {
+            Command::new("timeout")
+                .process_group(0)
+                .pre_exec(|| {
+                    utils::switch_cgroups();
+                    Ok(())
+                })
+                .args(["-s", "9", "120s", "dmesg", "-w"])
+                .stdout(Stdio::from(bootlog))
+                .spawn()
+        };
+        Ok(())
+    }
+
+    fn daemonize(&self) -> Result<()> {
+        utils::daemonize()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn switch_mnt_ns(&self, pid: i32) -> Result<()> {
+        utils::switch_mnt_ns(pid)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn switch_mnt_ns(&self, _pid: i32) -> Result<()> {
+        Ok(())
+    }
+
+    fn stop_framework(&self) -> Result<()> {
+        init_command("stop", &[])
+    }
+
+    fn start_framework(&self) -> Result<()> {
+        init_command("start", &[])
+    }
+
+    fn am(&self, args: &[&str]) -> Result<()> {
+        init_command("am", args)
+    }
+}
+
+/// A side effect requested from a [`FakePlatform`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    SetProp { name: String, value: String },
+    LoadPropFile(PathBuf),
+    MagiskPolicy,
+    PolicyRuleFile(PathBuf),
+    SelinuxEnforcing(bool),
+    Su { uid: i32, to_uid: i32 },
+    ResetSuPath(String),
+    GrantUid { uid: i32, to_uid: i32, sctx: String },
+    RevokeUid(uid_t),
+    ExcludeUid { uid: i64, exclude: i32 },
+    ReportEvent { event: String, state: String },
+    LoadKernelModule(PathBuf),
+    StartLogCapture(PathBuf),
+    Daemonize,
+    SwitchMntNs(i32),
+    StopFramework,
+    StartFramework,
+    Am(Vec<String>),
+}
+
+/// In-memory platform: keeps properties, the su allow list and loaded kernel
+/// modules in memory and records every side effect in order.
+#[derive(Default)]
+pub struct FakePlatform {
+    props: Mutex<HashMap<String, String>>,
+    safemode: bool,
+    allowed_uids: Mutex<Vec<uid_t>>,
+    kernel_modules: Mutex<HashSet<String>>,
+    effects: Mutex<Vec<Effect>>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl FakePlatform {
+    pub fn with_prop(self, name: &str, value: &str) -> Self {
+        self.props
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn with_safemode(mut self, safemode: bool) -> Self {
+        self.safemode = safemode;
+        self
+    }
+
+    pub fn with_allowed_uids(self, uids: &[uid_t]) -> Self {
+        self.allowed_uids.lock().unwrap().extend_from_slice(uids);
+        self
+    }
+
+    /// All side effects so far, in the order they were requested
+    pub fn effects(&self) -> Vec<Effect> {
+        self.effects.lock().unwrap().clone()
+    }
+
+    pub fn allowed_uids(&self) -> Vec<uid_t> {
+        self.allowed_uids.lock().unwrap().clone()
+    }
+}
+
+impl FakePlatform {
+    fn record(&self, effect: Effect) {
+        info!("[fake platform] {effect:?}");
+        self.effects.lock().unwrap().push(effect);
+    }
+}
+
+fn scontext_to_string(scontext: &[u8]) -> String {
+    let len = scontext
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(scontext.len());
+    String::from_utf8_lossy(&scontext[..len]).into_owned()
+}
+
+impl Platform for FakePlatform {
+    fn getprop(&self, name: &str) -> Option<String> {
+        self.props.lock().unwrap().get(name).cloned()
+    }
+
+    fn setprop(&self, name: &str, value: &str) -> Result<()> {
+        self.props
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), value.to_string());
+        self.record(Effect::SetProp {
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+        Ok(())
+    }
+
+    fn load_prop_file(&self, path: &Path) -> Result<()> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        {
+            let mut props = self.props.lock().unwrap();
+            for line in content.lines() {
+                let line = line.trim();
+                if line.starts_with('#') {
+                    continue;
+                }
+                if let Some((name, value)) = line.split_once('=') {
+                    props.insert(name.trim().to_string(), value.trim().to_string());
+                }
+            }
+        }
+        self.record(Effect::LoadPropFile(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn apply_magisk_policy(&self) -> Result<()> {
+        self.record(Effect::MagiskPolicy);
+        Ok(())
+    }
+
+    fn apply_policy_rule_file(&self, path: &Path) -> Result<()> {
+        self.record(Effect::PolicyRuleFile(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn set_selinux_enforcing(&self, enforcing: bool) -> Result<()> {
+        self.record(Effect::SelinuxEnforcing(enforcing));
+        Ok(())
+    }
+
+    fn su(&self, _key: &CStr, profile: &SuProfile) -> c_long {
+        self.record(Effect::Su {
+            uid: profile.uid,
+            to_uid: profile.to_uid,
+        });
+        0
+    }
+
+    fn su_get_safemode(&self, _key: &CStr) -> c_long {
+        self.safemode.into()
+    }
+
+    fn su_reset_path(&self, _key: &CStr, path: &CStr) -> c_long {
+        self.record(Effect::ResetSuPath(path.to_string_lossy().into_owned()));
+        0
+    }
+
+    fn su_grant_uid(&self, _key: &CStr, profile: &SuProfile) -> c_long {
+        self.allowed_uids.lock().unwrap().push(profile.uid as uid_t);
+        self.record(Effect::GrantUid {
+            uid: profile.uid,
+            to_uid: profile.to_uid,
+            sctx: scontext_to_string(&profile.scontext),
+        });
+        0
+    }
+
+    fn su_revoke_uid(&self, _key: &CStr, uid: uid_t) -> c_long {
+        self.allowed_uids.lock().unwrap().retain(|&u| u != uid);
+        self.record(Effect::RevokeUid(uid));
+        0
+    }
+
+    fn su_uid_nums(&self, _key: &CStr) -> c_long {
+        self.allowed_uids.lock().unwrap().len() as c_long
+    }
+
+    fn su_allow_uids(&self, _key: &CStr, buf: &mut [uid_t]) -> c_long {
+        let uids = self.allowed_uids.lock().unwrap();
+        let n = usize::min(uids.len(), buf.len());
+        buf[..n].copy_from_slice(&uids[..n]);
+        n as c_long
+    }
+
+    fn set_ap_mod_exclude(&self, _key: &CStr, uid: i64, exclude: i32) -> c_long {
+        self.record(Effect::ExcludeUid { uid, exclude });
+        0
+    }
+
+    fn report_event(&self, _superkey: Option<&str>, event: &str, state: &str) -> Result<()> {
+        self.record(Effect::ReportEvent {
+            event: event.to_string(),
+            state: state.to_string(),
+        });
+        Ok(())
+    }
+
+    fn is_kernel_module_loaded(&self, name: &str) -> bool {
+        self.kernel_modules.lock().unwrap().contains(name)
+    }
+
+    fn load_kernel_module(&self, path: &Path, _params: &[String]) -> Result<()> {
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // `<kmi>_kernelpatch.ko` registers itself as `kernelpatch`
+        let name = name.rsplit('_').next().unwrap_or_default().to_string();
+        self.kernel_modules.lock().unwrap().insert(name);
+        self.record(Effect::LoadKernelModule(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn start_log_capture(&self, log_dir: &Path) -> Result<()> {
+        self.record(Effect::StartLogCapture(log_dir.to_path_buf()));
+        Ok(())
+    }
+
+    fn daemonize(&self) -> Result<()> {
+        self.record(Effect::Daemonize);
+        Ok(())
+    }
+
+    fn switch_mnt_ns(&self, pid: i32) -> Result<()> {
+        self.record(Effect::SwitchMntNs(pid));
+        Ok(())
+    }
+
+    fn stop_framework(&self) -> Result<()> {
+        self.record(Effect::StopFramework);
+        Ok(())
+    }
+
+    fn start_framework(&self) -> Result<()> {
+        self.record(Effect::StartFramework);
+        Ok(())
+    }
+
+    fn am(&self, args: &[&str]) -> Result<()> {
+        self.record(Effect::Am(args.iter().map(ToString::to_string).collect()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+static TEST_PLATFORM: Mutex<Option<&'static FakePlatform>> = Mutex::new(None);
+
+#[cfg(test)]
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Run `f` with `fake` as the platform and a fresh sandbox root holding an
+/// empty APatch install. Both are process wide, so tests going through here
+/// run one at a time.
+#[cfg(test)]
+pub fn with_fake<T>(fake: FakePlatform, f: impl FnOnce(&FakePlatform) -> T) -> T {
+    let _guard = TEST_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let root = std::env::temp_dir().join(format!("apd-test-{}", std::process::id()));
+    defs::init_root(Some(root.clone()));
+    assert_eq!(defs::root(), root, "sandbox root pinned elsewhere");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(defs::working_dir()).unwrap();
+    fs::create_dir_all(defs::module_dir()).unwrap();
+    // scripts run as `busybox sh <script>`, the host shell does for the sandbox
+    let busybox = crate::assets::busybox_path();
+    fs::create_dir_all(busybox.parent().unwrap()).unwrap();
+    fs::write(&busybox, "#!/bin/sh\nshift\nexec /bin/sh \"$@\"\n").unwrap();
+    fs::set_permissions(&busybox, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let fake: &'static FakePlatform = Box::leak(Box::new(fake));
+    *TEST_PLATFORM.lock().unwrap_or_else(PoisonError::into_inner) = Some(fake);
+    let result = f(fake);
+    *TEST_PLATFORM.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    let _ = fs::remove_dir_all(&root);
+    result
+}
@@ -0,0 +1,152 @@
+//! `apd module restart <id>`: re-run the late stages of one module in place
+//!
+//! Every stage script leads its own process group. Its id is remembered in
+//! `WORKING_DIR/run/<id>/<stage>.pgid` together with the boot id, so a restart
+//! can take down whatever an earlier `service.sh` left running before it
+//! applies `system.prop` again and re-runs `service.sh` and the Lua `service`
+//! hook, which gets the superkey given with `-s` as at boot. A recorded group
+//! is only signalled while one of its processes still carries the module's
+//! `AP_MODULE` or runs in its directory, so a reused id is left alone. Modules
+//! that need mounting are refused, their files are only put in place at boot.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail, ensure};
+use log::{info, warn};
+
+use crate::{
+    boot_report, defs, lua,
+    module::{run_stage_script, validate_module_id},
+    platform,
+    utils::ensure_dir_exists,
+};
+
+/// Stages a restart re-runs, in boot order
+const RESTART_STAGES: &[&str] = &["service"];
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn pgid_path(id: &str, stage: &str) -> PathBuf {
+    defs::resolve(defs::MODULE_RUN_DIR)
+        .join(id)
+        .join(format!("{stage}.pgid"))
+}
+
+/// Remember the process group of the `stage` script of module `id`
+pub fn track_process_group(id: &str, stage: &str, pgid: u32) {
+    let path = pgid_path(id, stage);
+    let result = path
+        .parent()
+        .map_or(Ok(()), ensure_dir_exists)
+        .and_then(|()| {
+            let content = format!("{}\n{pgid}\n", boot_report::read_boot_id());
+            Ok(fs::write(&path, content)?)
+        });
+    if let Err(e) = result {
+        warn!("Failed to record process group of {id} {stage}: {e:#}");
+    }
+}
+
+/// Process group recorded for `stage` during this boot
+fn tracked_process_group(id: &str, stage: &str) -> Option<libc::pid_t> {
+    let content = fs::read_to_string(pgid_path(id, stage)).ok()?;
+    let mut lines = content.lines();
+    let boot_id = lines.next()?;
+    let pgid = lines.next()?.trim().parse().ok()?;
+    (boot_id == boot_report::read_boot_id() && pgid > 1).then_some(pgid)
+}
+
+/// Process group of `/proc/<pid>`, from the fields after the command name
+fn process_group(pid: &Path) -> Option<libc::pid_t> {
+    let stat = fs::read_to_string(pid.join("stat")).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(2)?.parse().ok()
+}
+
+/// Whether process `/proc/<pid>` was started for module `id`
+fn belongs_to(pid: &Path, id: &str, module: &Path) -> bool {
+    let marker = format!("AP_MODULE={id}");
+    let in_env = fs::read(pid.join("environ")).is_ok_and(|environ| {
+        environ
+            .split(|&b| b == 0)
+            .any(|var| var == marker.as_bytes())
+    });
+    in_env || fs::read_link(pid.join("cwd")).is_ok_and(|cwd| cwd.starts_with(module))
+}
+
+/// Whether group `pgid` still has a process of module `id`. The id may have
+/// been reused since it was recorded.
+fn group_alive(pgid: libc::pid_t, id: &str) -> bool {
+    let module = defs::module_dir().join(id);
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let pid = entry.path();
+        entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.parse::<u32>().is_ok())
+            && process_group(&pid) == Some(pgid)
+            && belongs_to(&pid, id, &module)
+    })
+}
+
+/// SIGTERM the process group, SIGKILL it if it does not go away in time
+fn stop_process_group(pgid: libc::pid_t, id: &str) {
+    if !group_alive(pgid, id) {
+        return;
+    }
+    info!("stopping process group {pgid}");
+    unsafe { libc::kill(-pgid, libc::SIGTERM) };
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while Instant::now() < deadline && group_alive(pgid, id) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    if group_alive(pgid, id) {
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    }
+}
+
+pub fn restart_module(id: &str, superkey: &str) -> Result<()> {
+    validate_module_id(id)?;
+    let module = defs::module_dir().join(id);
+    ensure!(module.is_dir(), "module {id} is not installed");
+    ensure!(
+        !module.join(defs::DISABLE_FILE_NAME).exists()
+            && !module.join(defs::REMOVE_FILE_NAME).exists(),
+        "module {id} is not active"
+    );
+    if module.join("system").is_dir() && !module.join("skip_mount").exists() {
+        bail!("module {id} needs mounting, reboot to apply its changes");
+    }
+
+    for stage in RESTART_STAGES {
+        if let Some(pgid) = tracked_process_group(id, stage) {
+            stop_process_group(pgid, id);
+        }
+    }
+
+    let system_prop = module.join("system.prop");
+    if system_prop.exists() {
+        println!("- Loading system.prop");
+        platform::get().load_prop_file(&system_prop)?;
+    }
+
+    for stage in RESTART_STAGES {
+        let script = module.join(format!("{stage}.sh"));
+        if script.exists() {
+            println!("- Running {stage}.sh");
+            run_stage_script(id, stage, &script, false, None)?;
+        }
+        if lua::exec_module_hook(id, &stage.replace('-', "_"), superkey)? {
+            println!("- Ran Lua {stage} hook");
+        }
+    }
+    println!("- {id} restarted, output goes to `apd module logs {id}`");
+    Ok(())
+}
@@ -75,7 +75,7 @@ fn restore_syscon_if_unlabeled<P: AsRef<Path>>(dir: P) -> Result<()> {
 }
 
 pub fn restorecon() -> Result<()> {
-    lsetfilecon(defs::DAEMON_PATH, ADB_CON)?;
-    restore_syscon_if_unlabeled(defs::MODULE_DIR)?;
+    lsetfilecon(defs::daemon_path(), ADB_CON)?;
+    restore_syscon_if_unlabeled(defs::module_dir())?;
     Ok(())
 }
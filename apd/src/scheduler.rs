@@ -0,0 +1,270 @@
+//! Stage script scheduler
+//!
+//! Modules may order their stage scripts against each other with `after=` and
+//! `before=` in module.prop, listing module ids separated by commas or spaces.
+//! Blocking stages run every script whose dependencies have finished in
+//! parallel, at most `MAX_PARALLEL_SCRIPTS` at a time, and return once all of
+//! them are done. Non-blocking stages (service, boot-completed) only order the
+//! spawns: a script starts after the scripts it depends on have started, not
+//! finished, so one that needs another module's service up has to wait for it
+//! itself. Scripts that are ready at the same time start in `foreach_module`
+//! order.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+};
+
+use anyhow::Result;
+use log::warn;
+
+use crate::{
+    boot_report::{Outcome, StageReport, StepReport},
+    module::read_module_prop,
+};
+
+/// Upper bound of stage scripts running at the same time
+const MAX_PARALLEL_SCRIPTS: usize = 4;
+
+pub struct Job {
+    pub id: String,
+    pub module: PathBuf,
+    pub script: PathBuf,
+}
+
+fn parse_ids(value: Option<&String>) -> impl Iterator<Item = &str> {
+    value
+        .into_iter()
+        .flat_map(|v| v.split([',', ' ']))
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+}
+
+/// For every job, the jobs that have to wait for it
+fn build_graph(jobs: &[Job]) -> Vec<BTreeSet<usize>> {
+    let index: HashMap<&str, usize> = jobs
+        .iter()
+        .enumerate()
+        .map(|(i, job)| (job.id.as_str(), i))
+        .collect();
+
+    let mut dependents = vec![BTreeSet::new(); jobs.len()];
+    for (i, job) in jobs.iter().enumerate() {
+        let Ok(props) = read_module_prop(&job.module) else {
+            continue;
+        };
+        // modules that are not installed or have no script for this stage are ignored
+        for id in parse_ids(props.get("after")) {
+            if let Some(&dep) = index.get(id)
+                && dep != i
+            {
+                dependents[dep].insert(i);
+            }
+        }
+        for id in parse_ids(props.get("before")) {
+            if let Some(&dep) = index.get(id)
+                && dep != i
+            {
+                dependents[i].insert(dep);
+            }
+        }
+    }
+    dependents
+}
+
+/// Run `jobs` honouring their ordering, recording one step per job named `name`.
+/// Without `block` a job counts as done once its script is spawned.
+pub fn run_jobs<F>(jobs: &[Job], block: bool, name: &str, report: &mut StageReport, run: F)
+where
+    F: Fn(&Job) -> Result<Outcome> + Sync,
+{
+    let dependents = build_graph(jobs);
+    let mut pending = vec![0usize; jobs.len()];
+    for deps in &dependents {
+        for &dep in deps {
+            pending[dep] += 1;
+        }
+    }
+    let mut ready: BTreeSet<usize> = (0..jobs.len()).filter(|&i| pending[i] == 0).collect();
+    let mut done = vec![false; jobs.len()];
+    let limit = if block { MAX_PARALLEL_SCRIPTS } else { 1 };
+
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        let mut running = 0;
+        let mut finished = 0;
+
+        while finished < jobs.len() {
+            while running < limit
+                && let Some(i) = ready.pop_first()
+            {
+                let tx = tx.clone();
+                let run = &run;
+                let job = &jobs[i];
+                let index = report.start_step(name, Some(&job.id));
+                scope.spawn(move || {
+                    let (step, result) = StepReport::run(name, Some(&job.id), || run(job));
+                    let _ = tx.send((i, index, step, result));
+                });
+                running += 1;
+            }
+
+            if running == 0 {
+                // Only jobs stuck in a dependency cycle are left, break it
+                let i = (0..jobs.len())
+                    .find(|&i| !done[i] && pending[i] > 0)
+                    .unwrap_or_default();
+                warn!("dependency cycle around {}, running it anyway", jobs[i].id);
+                pending[i] = 0;
+                ready.insert(i);
+                continue;
+            }
+
+            let Ok((i, index, step, result)) = rx.recv() else {
+                break;
+            };
+            running -= 1;
+            finished += 1;
+            done[i] = true;
+            report.end_step(index, step);
+            if let Err(e) = result {
+                warn!("Failed to exec {name} of {}: {e:#}", jobs[i].id);
+            }
+            for &dep in &dependents[i] {
+                if pending[dep] > 0 {
+                    pending[dep] -= 1;
+                    if pending[dep] == 0 && !done[dep] {
+                        ready.insert(dep);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        defs,
+        platform::{FakePlatform, with_fake},
+    };
+
+    /// Jobs for modules `(id, module.prop ordering lines)` in the sandbox root
+    fn jobs(modules: &[(&str, &str)]) -> Vec<Job> {
+        modules
+            .iter()
+            .map(|(id, ordering)| {
+                let module = defs::module_dir().join(id);
+                fs::create_dir_all(&module).unwrap();
+                fs::write(module.join("module.prop"), format!("id={id}\n{ordering}\n")).unwrap();
+                Job {
+                    id: (*id).to_string(),
+                    script: module.join("service.sh"),
+                    module,
+                }
+            })
+            .collect()
+    }
+
+    /// Run `jobs`, returning the ids in the order they started and the most
+    /// jobs that ran at the same time
+    fn run(jobs: &[Job], block: bool) -> (Vec<String>, usize) {
+        let started = Mutex::new(Vec::new());
+        let running = Mutex::new((0, 0));
+        let mut report = StageReport::new("test");
+        run_jobs(jobs, block, "job", &mut report, |job| {
+            started.lock().unwrap().push(job.id.clone());
+            {
+                let mut running = running.lock().unwrap();
+                running.0 += 1;
+                running.1 = running.1.max(running.0);
+            }
+            thread::sleep(Duration::from_millis(50));
+            running.lock().unwrap().0 -= 1;
+            Ok(Outcome::Done)
+        });
+        assert_eq!(report.steps.len(), jobs.len());
+        (
+            started.into_inner().unwrap(),
+            running.into_inner().unwrap().1,
+        )
+    }
+
+    #[test]
+    fn orders_by_after_and_before() {
+        with_fake(FakePlatform::default(), |_| {
+            let jobs = jobs(&[
+                ("mod_c", "after=mod_b, mod_unknown"),
+                ("mod_b", ""),
+                ("mod_a", "before=mod_b mod_c"),
+            ]);
+            assert_eq!(
+                build_graph(&jobs),
+                [BTreeSet::new(), BTreeSet::from([0]), BTreeSet::from([0, 1])]
+            );
+            let (started, _) = run(&jobs, true);
+            assert_eq!(started, ["mod_a", "mod_b", "mod_c"]);
+        });
+    }
+
+    #[test]
+    fn ignores_unknown_and_own_ids() {
+        with_fake(FakePlatform::default(), |_| {
+            let jobs = jobs(&[("mod_a", "after=mod_a,mod_x"), ("mod_b", "before=mod_y")]);
+            assert_eq!(build_graph(&jobs), [BTreeSet::new(), BTreeSet::new()]);
+        });
+    }
+
+    #[test]
+    fn breaks_cycles() {
+        with_fake(FakePlatform::default(), |_| {
+            let jobs = jobs(&[
+                ("mod_a", "after=mod_c"),
+                ("mod_b", "after=mod_a"),
+                ("mod_c", "after=mod_b"),
+                ("mod_d", "after=mod_a"),
+            ]);
+            let (mut started, _) = run(&jobs, true);
+            // the cycle is broken at its first job, everything runs exactly once
+            assert_eq!(started[0], "mod_a");
+            assert_eq!(started[3], "mod_c");
+            started.sort();
+            assert_eq!(started, ["mod_a", "mod_b", "mod_c", "mod_d"]);
+        });
+    }
+
+    #[test]
+    fn limits_parallel_jobs() {
+        with_fake(FakePlatform::default(), |_| {
+            let jobs = jobs(&[
+                ("mod_a", ""),
+                ("mod_b", ""),
+                ("mod_c", ""),
+                ("mod_d", ""),
+                ("mod_e", ""),
+                ("mod_f", ""),
+            ]);
+            let start = Instant::now();
+            let (_, parallel) = run(&jobs, true);
+            assert!(parallel > 1 && parallel <= MAX_PARALLEL_SCRIPTS);
+            assert!(start.elapsed() < Duration::from_millis(50 * 6));
+
+            // non-blocking stages only order spawns, one at a time
+            let (started, parallel) = run(&jobs, false);
+            assert_eq!(parallel, 1);
+            assert_eq!(
+                started,
+                ["mod_a", "mod_b", "mod_c", "mod_d", "mod_e", "mod_f"]
+            );
+        });
+    }
+}
@@ -0,0 +1,254 @@
+//! Module zip signatures
+//!
+//! A signed zip carries `META-INF/apatch.sig`: an ed25519 signature, raw or
+//! hex encoded, over the SHA-256 digest of every other entry. Entries are
+//! hashed in name order as `name`, a NUL byte, the little-endian u64 size, the
+//! little-endian u32 unix mode (0 if the zip has none) and the uncompressed
+//! content, so the digest does not depend on how the zip was packed.
+//!
+//! Trusted public keys live in `WORKING_DIR/trusted_signers`, one
+//! `<name> <hex key>` per line. `WORKING_DIR/signature_policy` selects what
+//! happens to zips that are unsigned or not signed by a trusted key:
+//! `enforce` refuses them, `warn` installs them with a warning and `off`
+//! (the default) does not complain.
+
+use std::{fs, io, path::Path, str::FromStr};
+
+use anyhow::{Context, Result, bail};
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use crate::defs;
+
+pub const SIGNATURE_ENTRY: &str = "META-INF/apatch.sig";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    Enforce,
+    Warn,
+    #[default]
+    Off,
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "enforce" => Ok(Self::Enforce),
+            "warn" => Ok(Self::Warn),
+            "off" => Ok(Self::Off),
+            other => bail!("invalid signature policy: {other}"),
+        }
+    }
+}
+
+pub fn policy() -> Policy {
+    let Ok(content) = fs::read_to_string(defs::resolve(defs::SIGNATURE_POLICY_FILE)) else {
+        return Policy::default();
+    };
+    content.parse().unwrap_or_else(|e| {
+        // a broken policy file must not silently turn verification off
+        warn!("{e}, enforcing");
+        Policy::Enforce
+    })
+}
+
+pub struct TrustedSigner {
+    pub name: String,
+    key: VerifyingKey,
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn trusted_signers() -> Result<Vec<TrustedSigner>> {
+    let path = defs::resolve(defs::TRUSTED_SIGNERS_FILE);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    let mut signers = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, key)) = line.split_once(char::is_whitespace) else {
+            warn!("ignoring malformed trusted signer: {line}");
+            continue;
+        };
+        let key = decode_hex(key)
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok());
+        match key {
+            Some(key) => signers.push(TrustedSigner {
+                name: name.to_string(),
+                key,
+            }),
+            None => warn!("ignoring invalid key of trusted signer {name}"),
+        }
+    }
+    Ok(signers)
+}
+
+/// Digest of all entries but the signature, as described in the module docs
+fn zip_digest(archive: &mut zip::ZipArchive<fs::File>) -> Result<[u8; 32]> {
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| *name != SIGNATURE_ENTRY)
+        .map(ToString::to_string)
+        .collect();
+    names.sort();
+
+    let mut hasher = Sha256::new();
+    for name in names {
+        let mut entry = archive.by_name(&name)?;
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update(entry.size().to_le_bytes());
+        // covers symlink entries and executable bits, which extraction honours
+        hasher.update(entry.unix_mode().unwrap_or(0).to_le_bytes());
+        io::copy(&mut entry, &mut hasher).with_context(|| format!("Failed to read {name}"))?;
+    }
+    Ok(hasher.finalize().into())
+}
+
+fn read_signature(archive: &mut zip::ZipArchive<fs::File>) -> Result<Option<Signature>> {
+    let mut entry = match archive.by_name(SIGNATURE_ENTRY) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut raw = Vec::new();
+    io::Read::read_to_end(&mut entry, &mut raw)?;
+
+    let bytes = if raw.len() == Signature::BYTE_SIZE {
+        raw
+    } else {
+        decode_hex(&String::from_utf8_lossy(&raw)).context("signature is not valid hex")?
+    };
+    let bytes: [u8; Signature::BYTE_SIZE] = bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("signature has the wrong length"))?;
+    Ok(Some(Signature::from_bytes(&bytes)))
+}
+
+/// Name of the trusted signer of `zip`. `Ok(None)` means the zip is unsigned,
+/// a signature that matches no trusted key is an error.
+pub fn verify_zip(zip: &Path) -> Result<Option<String>> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(zip)?)?;
+    let Some(signature) = read_signature(&mut archive)? else {
+        return Ok(None);
+    };
+    let digest = zip_digest(&mut archive)?;
+
+    let signers = trusted_signers()?;
+    match signers
+        .iter()
+        .find(|signer| signer.key.verify_strict(&digest, &signature).is_ok())
+    {
+        Some(signer) => Ok(Some(signer.name.clone())),
+        None => bail!("{SIGNATURE_ENTRY} does not match any trusted signer"),
+    }
+}
+
+/// What the signature check makes of a zip
+pub struct Assessment {
+    pub policy: Policy,
+    pub signer: Option<String>,
+    /// Why the zip is not trusted, if it is not
+    pub problem: Option<String>,
+}
+
+impl Assessment {
+    /// Whether the policy refuses to install the zip
+    pub fn rejected(&self) -> bool {
+        self.policy == Policy::Enforce && self.problem.is_some()
+    }
+}
+
+pub fn assess(zip: &Path) -> Assessment {
+    let (signer, problem) = match verify_zip(zip) {
+        Ok(Some(signer)) => (Some(signer), None),
+        Ok(None) => (None, Some("module zip is not signed".to_string())),
+        Err(e) => (None, Some(format!("{e:#}"))),
+    };
+    Assessment {
+        policy: policy(),
+        signer,
+        problem,
+    }
+}
+
+/// Verify `zip` before installation and apply the signature policy.
+/// Returns the signer to record for the module.
+pub fn check_install(zip: &Path) -> Result<Option<String>> {
+    let assessment = assess(zip);
+    if let Some(signer) = &assessment.signer {
+        println!("- Signed by: {signer}");
+    }
+    let Some(problem) = assessment.problem.as_deref() else {
+        return Ok(assessment.signer);
+    };
+    enforce(
+        assessment.policy,
+        problem,
+        &format!("module zip {}", zip.display()),
+    )?;
+    Ok(None)
+}
+
+/// Apply the signature policy to a module restored from a backup. The zip it
+/// was installed from is gone, so there is nothing to verify and the module
+/// counts as unsigned.
+pub fn check_restore(id: &str) -> Result<()> {
+    enforce(
+        policy(),
+        "restored modules carry no verifiable signature",
+        &format!("module {id} from a backup"),
+    )
+}
+
+fn enforce(policy: Policy, problem: &str, what: &str) -> Result<()> {
+    match policy {
+        Policy::Off => {}
+        Policy::Enforce => bail!("Signature check failed: {problem}"),
+        Policy::Warn => {
+            println!("! Signature check failed: {problem}");
+            warn!("installing untrusted {what}: {problem}");
+        }
+    }
+    Ok(())
+}
+
+/// Remember who signed an installed module, for `module list`
+pub fn record_signer(module_dir: &Path, signer: Option<&str>) -> Result<()> {
+    let path = module_dir.join(defs::SIGNER_FILE_NAME);
+    match signer {
+        Some(signer) => {
+            fs::write(&path, signer).with_context(|| format!("Failed to write {}", path.display()))
+        }
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        },
+    }
+}
+
+pub fn read_signer(module_dir: &Path) -> Option<String> {
+    fs::read_to_string(module_dir.join(defs::SIGNER_FILE_NAME))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
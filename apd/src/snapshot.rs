@@ -0,0 +1,196 @@
+//! Snapshots of replaced module versions
+//!
+//! When `handle_updated_modules` replaces a module, the old directory is moved
+//! to `WORKING_DIR/module_snapshots/<id>/<unix time>/module` together with a
+//! copy of its persistent module config, instead of being deleted.
+//! `apd module rollback <id>` moves the newest snapshot back into
+//! modules_update, so the next boot swaps it in like any other update and
+//! restores the config that belonged to it.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{info, warn};
+
+use crate::{
+    defs,
+    module::{mark_module_state, mark_update, validate_module_id},
+    module_config::{self, ConfigType},
+    utils::{self, ensure_dir_exists},
+};
+
+/// Snapshots kept per module
+const MAX_SNAPSHOTS_PER_MODULE: usize = 2;
+/// Size of all snapshots together, the oldest go first
+const MAX_SNAPSHOTS_SIZE: u64 = 512 << 20;
+
+const MODULE_SUBDIR: &str = "module";
+/// Left in modules_update/<id> by a rollback, holds the snapshot name. Module
+/// zips cannot ship it, `apd module install` removes it after extraction.
+pub const ROLLBACK_MARKER: &str = ".rollback";
+
+fn module_snapshots(id: &str) -> PathBuf {
+    defs::resolve(defs::MODULE_SNAPSHOT_DIR).join(id)
+}
+
+/// Snapshots of a module, oldest first
+fn list(id: &str) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(module_snapshots(id)) else {
+        return Vec::new();
+    };
+    let mut snapshots: Vec<(u64, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let stamp = entry.file_name().to_str()?.parse().ok()?;
+            Some((stamp, entry.path()))
+        })
+        .collect();
+    snapshots.sort();
+    snapshots.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Move the outgoing version of a module into a new snapshot
+pub fn take(id: &str, module_dir: &Path) -> Result<()> {
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut snapshot = module_snapshots(id).join(stamp.to_string());
+    // two updates of the same module within a second
+    let mut stamp = stamp;
+    while snapshot.exists() {
+        stamp += 1;
+        snapshot = module_snapshots(id).join(stamp.to_string());
+    }
+    ensure_dir_exists(&snapshot)?;
+
+    let config = module_config::get_config_path(id, ConfigType::Persist);
+    if config.exists() {
+        fs::copy(&config, snapshot.join(defs::PERSIST_CONFIG_NAME))
+            .with_context(|| format!("Failed to snapshot config of {id}"))?;
+    }
+    if let Err(e) = fs::rename(module_dir, snapshot.join(MODULE_SUBDIR)) {
+        let _ = fs::remove_dir_all(&snapshot);
+        return Err(e).with_context(|| format!("Failed to snapshot {}", module_dir.display()));
+    }
+    // flags belong to the installed module, a rollback takes over the current ones
+    for flag in [
+        defs::UPDATE_FILE_NAME,
+        defs::REMOVE_FILE_NAME,
+        defs::DISABLE_FILE_NAME,
+    ] {
+        let _ = fs::remove_file(snapshot.join(MODULE_SUBDIR).join(flag));
+    }
+    info!("snapshot of {id} saved to {}", snapshot.display());
+
+    prune(id);
+    Ok(())
+}
+
+/// Apply the per-module count cap and the global size cap
+fn prune(id: &str) {
+    let snapshots = list(id);
+    for old in snapshots
+        .iter()
+        .take(snapshots.len().saturating_sub(MAX_SNAPSHOTS_PER_MODULE))
+    {
+        let _ = fs::remove_dir_all(old);
+    }
+
+    let Ok(modules) = fs::read_dir(defs::resolve(defs::MODULE_SNAPSHOT_DIR)) else {
+        return;
+    };
+    let mut all: Vec<(u64, PathBuf, u64)> = modules
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str().map(list))
+        .flatten()
+        .filter_map(|path| {
+            let stamp = path.file_name()?.to_str()?.parse().ok()?;
+            let size = utils::dir_size(&path);
+            Some((stamp, path, size))
+        })
+        .collect();
+    all.sort_by_key(|(stamp, _, _)| *stamp);
+
+    let mut total: u64 = all.iter().map(|(_, _, size)| size).sum();
+    for (_, path, size) in all {
+        if total <= MAX_SNAPSHOTS_SIZE {
+            break;
+        }
+        warn!(
+            "snapshots exceed {MAX_SNAPSHOTS_SIZE} bytes, dropping {}",
+            path.display()
+        );
+        let _ = fs::remove_dir_all(&path);
+        total -= size;
+    }
+}
+
+/// `apd module rollback <id>`: stage the newest snapshot as an update
+pub fn rollback(id: &str) -> Result<()> {
+    validate_module_id(id)?;
+    let Some(snapshot) = list(id).pop() else {
+        bail!("no snapshot of {id} to roll back to");
+    };
+    let update_dir = defs::module_update_dir().join(id);
+    ensure!(
+        !update_dir.exists(),
+        "{id} already has a pending update, reboot first"
+    );
+    ensure_dir_exists(defs::module_update_dir())?;
+
+    fs::rename(snapshot.join(MODULE_SUBDIR), &update_dir)
+        .with_context(|| format!("Failed to stage snapshot {}", snapshot.display()))?;
+    let name = snapshot
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    fs::write(update_dir.join(ROLLBACK_MARKER), name)?;
+
+    let _ = mark_module_state(id, defs::UPDATE_FILE_NAME, true);
+    mark_update()?;
+    println!("- {id} will be rolled back on next boot");
+    Ok(())
+}
+
+/// The snapshot a staged update rolls back to, if it is a rollback; such an
+/// update must not be snapshotted itself. A marker naming no snapshot of `id`
+/// is dropped.
+pub fn pending_rollback(id: &str, updated_module: &Path) -> Option<PathBuf> {
+    let marker = updated_module.join(ROLLBACK_MARKER);
+    let name = fs::read_to_string(&marker).ok()?;
+    let name = name.trim();
+    let snapshot = list(id)
+        .into_iter()
+        .find(|snapshot| snapshot.file_name().is_some_and(|n| n == name));
+    if snapshot.is_none() {
+        warn!("{id}: rollback marker names no snapshot: {name}");
+        let _ = fs::remove_file(&marker);
+    }
+    snapshot
+}
+
+/// Finish a rollback to `snapshot` after it was moved into place: bring back
+/// its config and drop what is left of it
+pub fn finish_rollback(id: &str, module_dir: &Path, snapshot: &Path) -> Result<()> {
+    fs::remove_file(module_dir.join(ROLLBACK_MARKER))?;
+
+    let config = snapshot.join(defs::PERSIST_CONFIG_NAME);
+    let config_path = module_config::get_config_path(id, ConfigType::Persist);
+    if config.exists() {
+        if let Some(parent) = config_path.parent() {
+            ensure_dir_exists(parent)?;
+        }
+        fs::copy(&config, &config_path)
+            .with_context(|| format!("Failed to restore config of {id}"))?;
+    } else {
+        let _ = fs::remove_file(&config_path);
+    }
+    let _ = fs::remove_dir_all(snapshot);
+    info!("{id} rolled back to snapshot {}", snapshot.display());
+    Ok(())
+}
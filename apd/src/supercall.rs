@@ -2,6 +2,7 @@ use std::{
     ffi::{CStr, CString},
     fs::File,
     io::{self, Read},
+    path::Path,
     process,
     sync::{Arc, Mutex},
 };
@@ -9,7 +10,11 @@ use std::{
 use libc::{EINVAL, c_long, c_void, syscall, uid_t};
 use log::{error, info, warn};
 
-use crate::package::{read_ap_package_config, synchronize_package_uid};
+use crate::{
+    defs,
+    package::{read_ap_package_config, synchronize_package_uid},
+    platform,
+};
 
 // Generated by build.rs from app/src/main/cpp/version (single source of the
 // KernelPatch version embedded into supercalls).
@@ -30,10 +35,20 @@ const SUPERCALL_SU_GET_SAFEMODE: c_long = 0x1112;
 const SUPERCALL_SCONTEXT_LEN: usize = 0x60;
 
 #[repr(C)]
-struct SuProfile {
-    uid: i32,
-    to_uid: i32,
-    scontext: [u8; SUPERCALL_SCONTEXT_LEN],
+pub struct SuProfile {
+    pub uid: i32,
+    pub to_uid: i32,
+    pub scontext: [u8; SUPERCALL_SCONTEXT_LEN],
+}
+
+impl SuProfile {
+    pub fn new(uid: i32, to_uid: i32, scontext: &str) -> Self {
+        Self {
+            uid,
+            to_uid,
+            scontext: convert_string_to_u8_array(scontext),
+        }
+    }
 }
 
 fn ver_and_cmd(cmd: c_long) -> c_long {
@@ -43,7 +58,7 @@ fn ver_and_cmd(cmd: c_long) -> c_long {
     ((version_code as c_long) << 32) | (0x1158 << 16) | (cmd & 0xFFFF)
 }
 
-fn sc_su_revoke_uid(key: &CStr, uid: uid_t) -> c_long {
+pub fn sc_su_revoke_uid(key: &CStr, uid: uid_t) -> c_long {
     if key.to_bytes().is_empty() {
         return (-EINVAL).into();
     }
@@ -57,7 +72,7 @@ fn sc_su_revoke_uid(key: &CStr, uid: uid_t) -> c_long {
     }
 }
 
-fn sc_su_grant_uid(key: &CStr, profile: &SuProfile) -> c_long {
+pub fn sc_su_grant_uid(key: &CStr, profile: &SuProfile) -> c_long {
     if key.to_bytes().is_empty() {
         return (-EINVAL).into();
     }
@@ -95,7 +110,7 @@ fn sc_kstorage_write(
     }
 }
 
-fn sc_set_ap_mod_exclude(key: &CStr, uid: i64, exclude: i32) -> c_long {
+pub fn sc_set_ap_mod_exclude(key: &CStr, uid: i64, exclude: i32) -> c_long {
     sc_kstorage_write(
         key,
         KSTORAGE_EXCLUDE_LIST_GROUP,
@@ -127,7 +142,7 @@ pub fn sc_su_get_safemode(key: &CStr) -> c_long {
     }
 }
 
-fn sc_su(key: &CStr, profile: &SuProfile) -> c_long {
+pub fn sc_su(key: &CStr, profile: &SuProfile) -> c_long {
     if key.to_bytes().is_empty() {
         return (-EINVAL).into();
     }
@@ -141,7 +156,7 @@ fn sc_su(key: &CStr, profile: &SuProfile) -> c_long {
     }
 }
 
-fn sc_su_reset_path(key: &CStr, path: &CStr) -> c_long {
+pub fn sc_su_reset_path(key: &CStr, path: &CStr) -> c_long {
     if key.to_bytes().is_empty() || path.to_bytes().is_empty() {
         return (-EINVAL).into();
     }
@@ -155,14 +170,14 @@ fn sc_su_reset_path(key: &CStr, path: &CStr) -> c_long {
     }
 }
 
-fn sc_su_uid_nums(key: &CStr) -> c_long {
+pub fn sc_su_uid_nums(key: &CStr) -> c_long {
     if key.to_bytes().is_empty() {
         return (-EINVAL).into();
     }
     unsafe { syscall(__NR_SUPERCALL, key.as_ptr(), ver_and_cmd(SUPERCALL_SU_NUMS)) as c_long }
 }
 
-fn sc_su_allow_uids(key: &CStr, buf: &mut [uid_t]) -> c_long {
+pub fn sc_su_allow_uids(key: &CStr, buf: &mut [uid_t]) -> c_long {
     if key.to_bytes().is_empty() {
         return (-EINVAL).into();
     }
@@ -180,7 +195,7 @@ fn sc_su_allow_uids(key: &CStr, buf: &mut [uid_t]) -> c_long {
     }
 }
 
-fn read_file_to_string(path: &str) -> io::Result<String> {
+fn read_file_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
     let mut file = File::open(path)?;
     let mut content = String::new();
     file.read_to_string(&mut content)?;
@@ -201,15 +216,16 @@ fn convert_superkey(s: &Option<String>) -> Option<CString> {
 
 pub fn refresh_ap_package_list(skey: &CStr, mutex: &Arc<Mutex<()>>) {
     let _lock = mutex.lock().unwrap();
+    let platform = platform::get();
 
-    let num = sc_su_uid_nums(skey);
+    let num = platform.su_uid_nums(skey);
     if num < 0 {
         error!("[refresh_su_list] Error getting number of UIDs: {}", num);
         return;
     }
     let num = num as usize;
     let mut uids = vec![0 as uid_t; num];
-    let n = sc_su_allow_uids(skey, &mut uids);
+    let n = platform.su_allow_uids(skey, &mut uids);
     if n < 0 {
         error!("[refresh_su_list] Error getting su list");
         return;
@@ -226,7 +242,7 @@ pub fn refresh_ap_package_list(skey: &CStr, mutex: &Arc<Mutex<()>>) {
             "[refresh_ap_package_list] Revoking {} root permission...",
             uid
         );
-        let rc = sc_su_revoke_uid(skey, *uid);
+        let rc = platform.su_revoke_uid(skey, *uid);
         if rc != 0 {
             error!("[refresh_ap_package_list] Error revoking UID: {}", rc);
         }
@@ -239,19 +255,15 @@ pub fn refresh_ap_package_list(skey: &CStr, mutex: &Arc<Mutex<()>>) {
     let package_configs = read_ap_package_config();
     for config in package_configs {
         if config.allow == 1 && config.exclude == 0 {
-            let profile = SuProfile {
-                uid: config.uid,
-                to_uid: config.to_uid,
-                scontext: convert_string_to_u8_array(&config.sctx),
-            };
-            let result = sc_su_grant_uid(skey, &profile);
+            let profile = SuProfile::new(config.uid, config.to_uid, &config.sctx);
+            let result = platform.su_grant_uid(skey, &profile);
             info!(
                 "[refresh_ap_package_list] Loading {}: result = {}",
                 config.pkg, result
             );
         }
         if config.allow == 0 && config.exclude == 1 {
-            let result = sc_set_ap_mod_exclude(skey, config.uid as i64, 1);
+            let result = platform.set_ap_mod_exclude(skey, config.uid as i64, 1);
             info!(
                 "[refresh_ap_package_list] Loading exclude {}: result = {}",
                 config.pkg, result
@@ -264,19 +276,19 @@ pub fn privilege_apd_profile(superkey: &Option<String>) {
     let key = convert_superkey(superkey);
 
     let all_allow_ctx = "u:r:magisk:s0";
-    let profile = SuProfile {
-        uid: process::id().try_into().expect("PID conversion failed"),
-        to_uid: 0,
-        scontext: convert_string_to_u8_array(all_allow_ctx),
-    };
+    let profile = SuProfile::new(
+        process::id().try_into().expect("PID conversion failed"),
+        0,
+        all_allow_ctx,
+    );
     if let Some(ref key) = key {
-        let result = sc_su(key, &profile);
+        let result = platform::get().su(key, &profile);
         info!("[privilege_apd_profile] result = {}", result);
     }
 }
 
 pub fn init_load_su_path(superkey: &Option<String>) {
-    let su_path_file = "/data/adb/ap/su_path";
+    let su_path_file = defs::resolve(defs::SU_PATH_FILE);
 
     match read_file_to_string(su_path_file) {
         Ok(su_path) => {
@@ -285,7 +297,7 @@ pub fn init_load_su_path(superkey: &Option<String>) {
             match superkey_cstr {
                 Some(superkey_cstr) => match CString::new(su_path.trim()) {
                     Ok(su_path_cstr) => {
-                        let result = sc_su_reset_path(&superkey_cstr, &su_path_cstr);
+                        let result = platform::get().su_reset_path(&superkey_cstr, &su_path_cstr);
                         if result == 0 {
                             info!("suPath load successfully");
                         } else {
@@ -306,3 +318,54 @@ pub fn init_load_su_path(superkey: &Option<String>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::platform::{Effect, FakePlatform, with_fake};
+
+    #[test]
+    fn refresh_reloads_grants_from_package_config() {
+        let fake = FakePlatform::default().with_allowed_uids(&[0, 10100, 10300]);
+        with_fake(fake, |fake| {
+            fs::write(
+                defs::resolve(defs::PACKAGE_CONFIG_PATH),
+                "pkg,exclude,allow,uid,to_uid,sctx\n\
+                 com.a,0,1,10100,0,u:r:magisk:s0\n\
+                 com.b,1,0,10200,0,u:r:untrusted_app:s0\n\
+                 com.gone,0,1,10300,0,u:r:magisk:s0\n",
+            )
+            .unwrap();
+            let packages = defs::resolve(defs::SYSTEM_PACKAGES_LIST);
+            fs::create_dir_all(packages.parent().unwrap()).unwrap();
+            fs::write(
+                packages,
+                "com.a 10150 0 /data/user/0/com.a\ncom.b 10200 0 /data/user/0/com.b\n",
+            )
+            .unwrap();
+
+            refresh_ap_package_list(c"su", &Arc::new(Mutex::new(())));
+
+            // uid 0 is never revoked, com.a moved to a new uid, com.gone is uninstalled
+            assert_eq!(
+                fake.effects(),
+                [
+                    Effect::RevokeUid(10100),
+                    Effect::RevokeUid(10300),
+                    Effect::GrantUid {
+                        uid: 10150,
+                        to_uid: 0,
+                        sctx: "u:r:magisk:s0".to_string(),
+                    },
+                    Effect::ExcludeUid {
+                        uid: 10200,
+                        exclude: 1,
+                    },
+                ]
+            );
+            assert_eq!(fake.allowed_uids(), [0, 10150]);
+        });
+    }
+}
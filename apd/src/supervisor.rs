@@ -0,0 +1,599 @@
+//! Supervised module services
+//!
+//! A module declares its long-running daemons in `<module>/services.json`:
+//!
+//! ```json
+//! {
+//!   "services": [{
+//!     "name": "proxy",
+//!     "command": "bin/proxy -c proxy.conf",
+//!     "restart": "on-failure",
+//!     "backoff": 1,
+//!     "max_backoff": 60,
+//!     "env": { "PROXY_LEVEL": "2" },
+//!     "stop_signal": "TERM",
+//!     "stop_timeout": 10
+//!   }]
+//! }
+//! ```
+//!
+//! At the `service` stage apd starts `apd supervisor`. It runs every service
+//! of the active modules, looking for new ones every `RESCAN_INTERVAL`, with
+//! busybox sh in the module directory, in its own
+//! process group like a stage script, and with its output in the module log
+//! `service-<name>`. Services are respawned as `restart` says (`always`,
+//! `on-failure` or `never`), waiting `backoff` seconds, doubled after every
+//! quick crash up to `max_backoff`. Once a module is disabled or removed its
+//! services get `stop_signal`, then SIGKILL after `stop_timeout` seconds; they
+//! come back when the module is enabled again. The supervisor writes what it
+//! sees to `WORKING_DIR/service_state.json` for `apd module services`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boot_report, defs,
+    module::{ModuleType, busybox_command, foreach_module, get_common_script_envs, has_exited},
+    module_log,
+    utils::switch_cgroups,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often services.json of the active modules are read again
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+/// A run at least this long resets the backoff
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    Always,
+    #[default]
+    OnFailure,
+    Never,
+}
+
+fn default_backoff() -> u64 {
+    1
+}
+
+fn default_max_backoff() -> u64 {
+    60
+}
+
+fn default_stop_signal() -> String {
+    "TERM".to_string()
+}
+
+fn default_stop_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceSpec {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub restart: Restart,
+    #[serde(default = "default_backoff")]
+    pub backoff: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default = "default_stop_signal")]
+    pub stop_signal: String,
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: u64,
+}
+
+#[derive(Deserialize)]
+struct ServicesFile {
+    services: Vec<ServiceSpec>,
+}
+
+fn parse_signal(name: &str) -> Result<i32> {
+    let name = name.trim();
+    Ok(match name.strip_prefix("SIG").unwrap_or(name) {
+        "TERM" => libc::SIGTERM,
+        "INT" => libc::SIGINT,
+        "HUP" => libc::SIGHUP,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        other => bail!("unsupported stop signal: {other}"),
+    })
+}
+
+/// The services a module declares, empty if it has no services.json
+pub fn load_specs(module_dir: &Path) -> Result<Vec<ServiceSpec>> {
+    let path = module_dir.join(defs::SERVICES_FILE_NAME);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read(&path)?;
+    let file: ServicesFile =
+        serde_json::from_slice(&content).with_context(|| format!("invalid {}", path.display()))?;
+
+    let name_re = regex_lite::Regex::new(r"^[a-zA-Z0-9_-]+$")?;
+    let mut names = Vec::new();
+    for spec in &file.services {
+        ensure!(
+            name_re.is_match(&spec.name),
+            "invalid service name: {}",
+            spec.name
+        );
+        ensure!(
+            !names.contains(&&spec.name),
+            "service {} is declared twice",
+            spec.name
+        );
+        ensure!(
+            !spec.command.trim().is_empty(),
+            "service {} has no command",
+            spec.name
+        );
+        parse_signal(&spec.stop_signal)?;
+        names.push(&spec.name);
+    }
+    Ok(file.services)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    /// Sent its stop signal, waiting for it to exit
+    Stopping,
+    /// Waiting to be restarted
+    Backoff,
+    /// Stopped because the module is not active
+    Stopped,
+    /// Exited and not restarted
+    Exited,
+    /// Failed and not restarted
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub module: String,
+    pub name: String,
+    pub state: State,
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    pub last_exit: Option<String>,
+    /// Unix time in seconds of the last state change
+    pub since: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    boot_id: String,
+    supervisor_pid: u32,
+    services: Vec<ServiceStatus>,
+}
+
+struct Service {
+    module_dir: PathBuf,
+    spec: ServiceSpec,
+    stop_signal: i32,
+    child: Option<Child>,
+    started: Instant,
+    next_start: Option<Instant>,
+    /// When a stopping service gets SIGKILL
+    kill_at: Option<Instant>,
+    backoff: Duration,
+    status: ServiceStatus,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn is_active(module_dir: &Path) -> bool {
+    module_dir.is_dir()
+        && !module_dir.join(defs::DISABLE_FILE_NAME).exists()
+        && !module_dir.join(defs::REMOVE_FILE_NAME).exists()
+}
+
+fn describe_exit(status: ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exit code {code}"),
+        (None, Some(signal)) => format!("killed by signal {signal}"),
+        _ => "unknown".to_string(),
+    }
+}
+
+impl Service {
+    fn new(module_dir: &Path, id: &str, spec: ServiceSpec) -> Self {
+        Self {
+            module_dir: module_dir.to_path_buf(),
+            stop_signal: parse_signal(&spec.stop_signal).unwrap_or(libc::SIGTERM),
+            backoff: Duration::from_secs(spec.backoff),
+            child: None,
+            started: Instant::now(),
+            next_start: Some(Instant::now()),
+            kill_at: None,
+            status: ServiceStatus {
+                module: id.to_string(),
+                name: spec.name.clone(),
+                state: State::Stopped,
+                pid: None,
+                restarts: 0,
+                last_exit: None,
+                since: unix_now(),
+            },
+            spec,
+        }
+    }
+
+    /// Module log the service writes to
+    fn log_stage(&self) -> String {
+        format!("service-{}", self.spec.name)
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.status.state = state;
+        self.status.since = unix_now();
+    }
+
+    fn start(&mut self) {
+        let id = &self.status.module;
+        let mut command = busybox_command();
+        command
+            .current_dir(&self.module_dir)
+            .args(["sh", "-c", &self.spec.command])
+            .envs(get_common_script_envs(Some(id)))
+            .envs(&self.spec.env);
+        module_log::redirect(&mut command, id, &self.log_stage());
+
+        self.next_start = None;
+        self.started = Instant::now();
+        match command.spawn() {
+            Ok(child) => {
+                info!("started service {id}/{} ({})", self.spec.name, child.id());
+                self.status.pid = Some(child.id());
+                self.child = Some(child);
+                self.set_state(State::Running);
+            }
+            Err(e) => {
+                warn!("Failed to start service {id}/{}: {e}", self.spec.name);
+                self.status.last_exit = Some(format!("failed to start: {e}"));
+                self.schedule_restart(false);
+            }
+        }
+    }
+
+    /// Decide what happens after the service ended
+    fn schedule_restart(&mut self, success: bool) {
+        if self.started.elapsed() >= STABLE_RUN {
+            self.backoff = Duration::from_secs(self.spec.backoff);
+        }
+        let restart = match self.spec.restart {
+            Restart::Always => true,
+            Restart::OnFailure => !success,
+            Restart::Never => false,
+        };
+        if !restart {
+            self.set_state(if success {
+                State::Exited
+            } else {
+                State::Failed
+            });
+            return;
+        }
+        self.next_start = Some(Instant::now() + self.backoff);
+        self.status.restarts += 1;
+        self.backoff = (self.backoff * 2).min(Duration::from_secs(self.spec.max_backoff));
+        self.set_state(State::Backoff);
+    }
+
+    fn reap(&mut self) {
+        let Some(child) = &mut self.child else {
+            return;
+        };
+        let pid = child.id() as libc::pid_t;
+        if self.status.state == State::Stopping && has_exited(pid) {
+            // take down what is left of the group while its id is still ours
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+        }
+        let status = match child.try_wait() {
+            Ok(Some(status)) => status,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to wait for {}: {e}", self.spec.name);
+                return;
+            }
+        };
+        self.child = None;
+        self.status.pid = None;
+        self.status.last_exit = Some(describe_exit(status));
+        if self.status.state == State::Stopping {
+            info!("service {}/{} stopped", self.status.module, self.spec.name);
+            self.kill_at = None;
+            self.set_state(State::Stopped);
+            return;
+        }
+        warn!(
+            "service {}/{} ended: {}",
+            self.status.module,
+            self.spec.name,
+            describe_exit(status)
+        );
+        self.schedule_restart(status.success());
+    }
+
+    /// Send the stop signal to the process group; [`Service::kill_overdue`]
+    /// follows up with SIGKILL after the timeout
+    fn stop(&mut self) {
+        self.next_start = None;
+        let Some(child) = &self.child else {
+            self.set_state(State::Stopped);
+            return;
+        };
+        if self.status.state == State::Stopping {
+            return;
+        }
+        info!("stopping service {}/{}", self.status.module, self.spec.name);
+        unsafe { libc::kill(-(child.id() as libc::pid_t), self.stop_signal) };
+        self.kill_at = Some(Instant::now() + Duration::from_secs(self.spec.stop_timeout));
+        self.set_state(State::Stopping);
+    }
+
+    /// SIGKILL a stopping service whose timeout has passed
+    fn kill_overdue(&mut self, now: Instant) {
+        let (Some(child), Some(kill_at)) = (&self.child, self.kill_at) else {
+            return;
+        };
+        if now >= kill_at {
+            warn!(
+                "service {}/{} did not stop in time, killing it",
+                self.status.module, self.spec.name
+            );
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            self.kill_at = None;
+        }
+    }
+
+    fn tick(&mut self, now: Instant) {
+        self.reap();
+        if self.child.is_some()
+            && let Err(e) = module_log::trim(&self.status.module, &self.log_stage())
+        {
+            warn!("Failed to trim log of {}: {e:#}", self.spec.name);
+        }
+        if self.status.state == State::Stopping {
+            self.kill_overdue(now);
+            return;
+        }
+        if !is_active(&self.module_dir) {
+            if self.status.state != State::Stopped {
+                self.stop();
+            }
+            return;
+        }
+        if self.status.state == State::Stopped && self.next_start.is_none() {
+            // the module was enabled again
+            self.next_start = Some(now);
+        }
+        if self.next_start.is_some_and(|at| at <= now) {
+            self.start();
+        }
+    }
+}
+
+fn state_path() -> PathBuf {
+    defs::resolve(defs::SERVICE_STATE_FILE)
+}
+
+fn write_state(services: &[Service]) -> Result<()> {
+    let state = StateFile {
+        boot_id: boot_report::read_boot_id(),
+        supervisor_pid: std::process::id(),
+        services: services.iter().map(|s| s.status.clone()).collect(),
+    };
+    let path = state_path();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(&state)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+fn read_state() -> Option<StateFile> {
+    let content = fs::read(state_path()).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+/// Pid of the supervisor of this boot, if it is still alive
+fn running_supervisor() -> Option<u32> {
+    let state = read_state()?;
+    let alive = state.boot_id == boot_report::read_boot_id()
+        && unsafe { libc::kill(state.supervisor_pid as libc::pid_t, 0) } == 0;
+    alive.then_some(state.supervisor_pid)
+}
+
+/// Services of all modules; those of inactive modules stay stopped until the
+/// module is enabled
+fn collect_services() -> Result<Vec<Service>> {
+    let mut services = Vec::new();
+    foreach_module(ModuleType::All, |module_dir| {
+        let Some(id) = module_dir.file_name().and_then(|n| n.to_str()) else {
+            return Ok(());
+        };
+        if module_dir.join(defs::REMOVE_FILE_NAME).exists() {
+            return Ok(());
+        }
+        match load_specs(module_dir) {
+            Ok(specs) => services.extend(
+                specs
+                    .into_iter()
+                    .map(|spec| Service::new(module_dir, id, spec)),
+            ),
+            Err(e) => warn!("Failed to load services of {id}: {e:#}"),
+        }
+        Ok(())
+    })?;
+    Ok(services)
+}
+
+/// Add services that showed up since the last scan, e.g. of a module whose
+/// removal was undone
+fn rescan(services: &mut Vec<Service>) {
+    let found = match collect_services() {
+        Ok(found) => found,
+        Err(e) => {
+            warn!("Failed to scan for services: {e:#}");
+            return;
+        }
+    };
+    for service in found {
+        let known = services
+            .iter()
+            .any(|s| s.status.module == service.status.module && s.spec.name == service.spec.name);
+        if !known {
+            info!(
+                "supervising new service {}/{}",
+                service.status.module, service.spec.name
+            );
+            services.push(service);
+        }
+    }
+}
+
+/// Start `apd supervisor` in the background if any active module has services
+pub fn spawn() -> Result<()> {
+    let mut wanted = false;
+    foreach_module(ModuleType::Active, |module_dir| {
+        wanted |= module_dir.join(defs::SERVICES_FILE_NAME).exists();
+        Ok(())
+    })?;
+    if !wanted {
+        return Ok(());
+    }
+    if let Some(pid) = running_supervisor() {
+        info!("service supervisor already running ({pid})");
+        return Ok(());
+    }
+
+    let mut command = Command::new(defs::daemon_path());
+    command.process_group(0);
+    unsafe {
+        command.pre_exec(|| {
+            switch_cgroups();
+            Ok(())
+        });
+    }
+    if !defs::is_default_root() {
+        command.env(defs::ROOT_ENV, defs::root());
+    }
+    command
+        .arg("supervisor")
+        .spawn()
+        .context("Failed to start service supervisor")?;
+    Ok(())
+}
+
+/// `apd supervisor`: run the services of active modules until SIGTERM
+pub fn run() -> Result<()> {
+    let term = Arc::new(AtomicBool::new(false));
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&term))?;
+    }
+
+    let mut services = collect_services()?;
+    info!("supervising {} services", services.len());
+    let mut last_state = None;
+    let mut last_scan = Instant::now();
+    while !term.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now.duration_since(last_scan) >= RESCAN_INTERVAL {
+            rescan(&mut services);
+            last_scan = now;
+        }
+        for service in &mut services {
+            service.tick(now);
+        }
+
+        let state: Vec<(State, Option<u32>, u32)> = services
+            .iter()
+            .map(|s| (s.status.state, s.status.pid, s.status.restarts))
+            .collect();
+        if last_state.as_ref() != Some(&state) {
+            if let Err(e) = write_state(&services) {
+                warn!("Failed to write service state: {e:#}");
+            }
+            last_state = Some(state);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    info!("supervisor stopping");
+    for service in &mut services {
+        service.stop();
+    }
+    while services.iter().any(|s| s.status.state == State::Stopping) {
+        let now = Instant::now();
+        for service in &mut services {
+            service.reap();
+            service.kill_overdue(now);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    write_state(&services)
+}
+
+/// `apd module services`
+pub fn print_services(json: bool) -> Result<()> {
+    let running = running_supervisor().is_some();
+    let services = read_state().map(|s| s.services).unwrap_or_default();
+    if json {
+        let value = serde_json::json!({ "supervisor": running, "services": services });
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        return Ok(());
+    }
+
+    if !running {
+        println!("supervisor: not running, states below are from its last run");
+    }
+    for service in services {
+        let state = serde_json::to_value(service.state)?;
+        print!(
+            "{}/{}: {}",
+            service.module,
+            service.name,
+            state.as_str().unwrap_or_default()
+        );
+        if let Some(pid) = service.pid {
+            print!(" pid {pid}");
+        }
+        if service.restarts > 0 {
+            print!(", {} restarts", service.restarts);
+        }
+        if let Some(last_exit) = &service.last_exit {
+            print!(", last {last_exit}");
+        }
+        println!();
+    }
+    Ok(())
+}
@@ -0,0 +1,267 @@
+//! Trash for uninstalled modules
+//!
+//! `prune_modules` moves a removed module to
+//! `WORKING_DIR/module_trash/<id>.<unix time>/` with its persistent config and
+//! a `meta.json` saying when and why it went away, instead of deleting it.
+//! Entries older than the retention period in `WORKING_DIR/trash_retention_days`
+//! (default 7, 0 keeps nothing) are purged on boot.
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    defs, deps, metamodule,
+    module::{
+        InstallBlock, mark_update, metamodule_install_block, read_module_prop, validate_module_id,
+    },
+    module_config::{self, ConfigType},
+    utils::{self, ensure_dir_exists},
+};
+
+const DEFAULT_RETENTION_DAYS: u64 = 7;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+const MODULE_SUBDIR: &str = "module";
+const META_FILE: &str = "meta.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    /// Unix time in seconds
+    pub removed_at: u64,
+    pub reason: String,
+    #[serde(skip_deserializing)]
+    pub size: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn trash_dir() -> PathBuf {
+    defs::resolve(defs::MODULE_TRASH_DIR)
+}
+
+fn retention_secs() -> u64 {
+    let days = fs::read_to_string(defs::resolve(defs::TRASH_RETENTION_FILE))
+        .ok()
+        .and_then(|days| days.trim().parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    days.saturating_mul(SECS_PER_DAY)
+}
+
+/// All trash entries with their directories, oldest first
+fn entries() -> Vec<(PathBuf, TrashEntry)> {
+    let Ok(dir) = fs::read_dir(trash_dir()) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<(PathBuf, TrashEntry)> = dir
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let meta = fs::read(path.join(META_FILE)).ok()?;
+            let mut meta: TrashEntry = serde_json::from_slice(&meta).ok()?;
+            meta.size = utils::dir_size(&path);
+            Some((path, meta))
+        })
+        .collect();
+    entries.sort_by_key(|(_, meta)| meta.removed_at);
+    entries
+}
+
+/// Move a module that is being uninstalled into the trash
+pub fn move_to_trash(id: &str, module_dir: &Path) -> Result<()> {
+    if retention_secs() == 0 {
+        module_config::clear_module_configs(id)?;
+        return fs::remove_dir_all(module_dir)
+            .with_context(|| format!("Failed to remove {}", module_dir.display()));
+    }
+
+    let props = read_module_prop(module_dir).unwrap_or_default();
+    let reason = fs::read_to_string(module_dir.join(defs::REMOVE_FILE_NAME))
+        .ok()
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty())
+        .unwrap_or_else(|| "uninstall requested".to_string());
+    let meta = TrashEntry {
+        id: id.to_string(),
+        name: props.get("name").cloned().unwrap_or_default(),
+        version: props.get("version").cloned().unwrap_or_default(),
+        removed_at: now(),
+        reason,
+        size: 0,
+    };
+
+    let entry = trash_dir().join(format!("{id}.{}", meta.removed_at));
+    ensure_dir_exists(&entry)?;
+    fs::write(entry.join(META_FILE), serde_json::to_vec_pretty(&meta)?)?;
+
+    let config = module_config::get_config_path(id, ConfigType::Persist);
+    if config.exists() {
+        fs::copy(&config, entry.join(defs::PERSIST_CONFIG_NAME))
+            .with_context(|| format!("Failed to keep config of {id}"))?;
+    }
+    fs::rename(module_dir, entry.join(MODULE_SUBDIR))
+        .with_context(|| format!("Failed to move {} to trash", module_dir.display()))?;
+    let _ = fs::remove_file(entry.join(MODULE_SUBDIR).join(defs::REMOVE_FILE_NAME));
+
+    module_config::clear_module_configs(id)?;
+    info!("{id} moved to trash");
+    Ok(())
+}
+
+/// Drop entries past the retention period
+pub fn purge_expired() {
+    let retention = retention_secs();
+    let now = now();
+    for (path, meta) in entries() {
+        if now.saturating_sub(meta.removed_at) >= retention {
+            info!("purging {} from trash", meta.id);
+            if let Err(e) = fs::remove_dir_all(&path) {
+                warn!("Failed to purge {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// `apd module trash list`
+pub fn list() -> Result<()> {
+    let entries: Vec<TrashEntry> = entries().into_iter().map(|(_, meta)| meta).collect();
+    println!("{}", serde_json::to_string_pretty(&entries)?);
+    Ok(())
+}
+
+/// `apd module trash restore <id>`: put the most recently removed copy back.
+/// Its uninstall.sh already ran, so the module is staged in modules_update
+/// like a fresh install, under the same checks, and comes back after a reboot.
+pub fn restore(id: &str) -> Result<()> {
+    validate_module_id(id)?;
+    let Some((entry, _)) = entries().into_iter().rev().find(|(_, meta)| meta.id == id) else {
+        bail!("{id} is not in the trash");
+    };
+    let module_dir = defs::module_dir().join(id);
+    ensure!(
+        !module_dir.exists(),
+        "{id} is installed, uninstall it and reboot before restoring"
+    );
+    let update_dir = defs::module_update_dir().join(id);
+    ensure!(
+        !update_dir.exists(),
+        "{id} already has a pending update, reboot first"
+    );
+
+    let module = entry.join(MODULE_SUBDIR);
+    let props = read_module_prop(&module)?;
+    deps::check_install(id, &props)?;
+    let is_metamodule = metamodule::is_metamodule(&props);
+    let needs_mount = module.join("system").is_dir() && !module.join("skip_mount").exists();
+    match metamodule_install_block(id, is_metamodule, needs_mount) {
+        Some(InstallBlock::MetamoduleBusy { disabled: true }) => {
+            bail!(
+                "a metamodule with a custom installer is disabled; re-enable or uninstall it, then reboot"
+            )
+        }
+        Some(InstallBlock::MetamoduleBusy { disabled: false }) => {
+            bail!("a metamodule with a custom installer has pending changes; reboot first")
+        }
+        Some(InstallBlock::OtherMetamodule(existing)) => {
+            bail!("metamodule {existing} is already installed, only one can be active")
+        }
+        None => {}
+    }
+
+    let config = entry.join(defs::PERSIST_CONFIG_NAME);
+    if config.exists() {
+        let config_path = module_config::get_config_path(id, ConfigType::Persist);
+        if let Some(parent) = config_path.parent() {
+            ensure_dir_exists(parent)?;
+        }
+        fs::copy(&config, &config_path)
+            .with_context(|| format!("Failed to restore config of {id}"))?;
+    }
+    ensure_dir_exists(defs::module_update_dir())?;
+    fs::rename(&module, &update_dir).with_context(|| format!("Failed to restore {id}"))?;
+    let _ = fs::remove_dir_all(&entry);
+
+    // the same placeholder an install leaves until the update is moved in
+    ensure_dir_exists(&module_dir)?;
+    fs::set_permissions(&module_dir, fs::Permissions::from_mode(0o700))?;
+    if is_metamodule {
+        metamodule::ensure_symlink(&module_dir)?;
+    }
+
+    mark_update()?;
+    println!("- {id} restored, reboot to apply");
+    Ok(())
+}
+
+/// `apd module trash purge [<id>]`
+pub fn purge(id: Option<&str>) -> Result<()> {
+    for (path, meta) in entries() {
+        if id.is_none_or(|id| id == meta.id) {
+            fs::remove_dir_all(&path)
+                .with_context(|| format!("Failed to purge {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::{FakePlatform, with_fake};
+
+    fn trash_module(id: &str, props: &str) {
+        let dir = defs::module_dir().join(id);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("module.prop"), format!("id={id}\n{props}")).unwrap();
+        move_to_trash(id, &dir).unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn restore_stages_like_an_install() {
+        with_fake(FakePlatform::default(), |_| {
+            trash_module("mod_a", "");
+            restore("mod_a").unwrap();
+
+            let update_dir = defs::module_update_dir().join("mod_a");
+            assert!(update_dir.join("module.prop").exists());
+            let placeholder = defs::module_dir().join("mod_a");
+            assert!(placeholder.is_dir() && !placeholder.join("module.prop").exists());
+            assert!(restore("mod_a").is_err());
+        });
+    }
+
+    #[test]
+    fn restore_checks_requirements_and_metamodules() {
+        with_fake(FakePlatform::default(), |_| {
+            trash_module("mod_a", "requires=mod_dep\n");
+            let err = restore("mod_a").unwrap_err();
+            assert!(format!("{err:#}").contains("mod_dep"));
+            assert!(!defs::module_update_dir().join("mod_a").exists());
+
+            let meta = defs::module_dir().join("meta_a");
+            fs::create_dir_all(&meta).unwrap();
+            fs::write(meta.join("module.prop"), "id=meta_a\nmetamodule=1\n").unwrap();
+            metamodule::ensure_symlink(&meta).unwrap();
+            trash_module("meta_b", "metamodule=1\n");
+            let err = restore("meta_b").unwrap_err();
+            assert!(format!("{err}").contains("meta_a"));
+            assert_eq!(metamodule::get_metamodule_path(), Some(meta));
+        });
+    }
+}
@@ -13,7 +13,7 @@ use std::{
 use anyhow::{Context, Error, Ok, Result, bail};
 use log::{info, warn};
 
-use crate::{defs, supercall::sc_su_get_safemode};
+use crate::{defs, platform};
 
 pub fn ensure_file_exists<T: AsRef<Path>>(file: T) -> Result<()> {
     match File::options().write(true).create_new(true).open(&file) {
@@ -40,21 +40,48 @@ pub fn ensure_dir_exists<T: AsRef<Path>>(dir: T) -> Result<()> {
     }
 }
 
+/// Hold an exclusive lock on `<path>.lock` until the returned file is dropped.
+/// Guards read-modify-write cycles on state files that separate apd processes
+/// (boot stages, CLI calls) update.
+pub fn lock_file<T: AsRef<Path>>(path: T) -> Result<File> {
+    let mut lock_path = path.as_ref().as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open {}", Path::new(&lock_path).display()))?;
+    lock.lock()
+        .with_context(|| format!("Failed to lock {}", Path::new(&lock_path).display()))?;
+    Ok(lock)
+}
+
+/// Total size of the regular files below `path`, symlinks are not followed
+pub fn dir_size<T: AsRef<Path>>(path: T) -> u64 {
+    let Result::Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Result::Ok(t) if t.is_dir() => dir_size(entry.path()),
+            Result::Ok(t) if t.is_file() => entry.metadata().map_or(0, |m| m.len()),
+            _ => 0,
+        })
+        .sum()
+}
+
 // todo: ensure
 pub fn ensure_binary<T: AsRef<Path>>(path: T) -> Result<()> {
     set_permissions(&path, Permissions::from_mode(0o755))?;
     Ok(())
 }
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn getprop(prop: &str) -> Option<String> {
-    android_properties::getprop(prop).value()
+    platform::get().getprop(prop)
 }
 
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
-pub fn getprop(_prop: &str) -> Option<String> {
-    unimplemented!()
-}
 pub fn run_command(
     command: &str,
     args: &[&str],
@@ -87,7 +114,7 @@ pub fn is_safe_mode(superkey: Option<String>) -> bool {
                 warn!("[is_safe_mode] No valid superkey provided, assuming safemode as false.");
                 false
             },
-            |cstr| sc_su_get_safemode(&cstr) == 1,
+            |cstr| platform::get().su_get_safemode(&cstr) == 1,
         );
     info!("kernel_safemode: {}", safemode);
     safemode